@@ -0,0 +1,70 @@
+use criterion::{criterion_group,criterion_main,Criterion,black_box};
+use chaos_ntp::ntp::types::*;
+use chaos_ntp::ntp::parser::serialize_packet;
+use chaos_ntp::ntp::builder::PacketBuilder;
+use chaos_ntp::ntpd::server::{Server,Stats};
+use chaos_ntp::ntpd::response_strategy::{StrategyContext,find_strategy};
+use chaos_ntp::ntpd::metrics::Metrics;
+use chaos_ntp::ntpd::random::Randomness;
+
+//a plain client request and a server response with a mac, what the server sees most of the time
+fn packets() -> (Vec<u8>, Packet) {
+    let request = PacketBuilder::client().transit_timestamp(Timestamp::from(0).set_seconds(0xe38c4fd4).set_fraction(0xd7472dcd)).build_unchecked();
+    let response = Packet {
+        mode: Mode::Server,
+        stratum: Stratum::SecondaryServer(2),
+        origin_timestamp: request.transit_timestamp,
+        receive_timestamp: request.transit_timestamp,
+        auth: Some(Auth { key_indentifier: 1, digest: vec![0; 20] }),
+        ..request.clone()
+    };
+    (request.to_bytes().unwrap(), response)
+}
+
+fn parse(c: &mut Criterion) {
+    let (request, _) = packets();
+    c.bench_function("Packet::parse", |b| b.iter(|| Packet::parse(black_box(&request)).unwrap()));
+    c.bench_function("PacketRef::parse", |b| b.iter(|| PacketRef::parse(black_box(&request)).unwrap()));
+}
+
+fn serialize(c: &mut Criterion) {
+    let (_, response) = packets();
+    c.bench_function("serialize_packet", |b| b.iter(|| serialize_packet(black_box(&response)).unwrap()));
+    let mut buf = [0; 1024];
+    c.bench_function("serialize_into", |b| b.iter(|| black_box(&response).serialize_into(&mut buf).unwrap()));
+}
+
+//everything a plain request goes through in the server, from parsing to sending the response
+//responses go to a socket that is never read from, the kernel drops them once its buffer is full
+fn handle_request(c: &mut Criterion) {
+    let (request, _) = packets();
+    let ctx = StrategyContext::default();
+    let ctor = find_strategy("current_time").unwrap();
+    let mut server = Server {
+        port: 0,
+        addr: [127, 0, 0, 1].into(),
+        log_all_requests: false,
+        parse_mode: Default::default(),
+        response_version: Default::default(),
+        response_strategy: ctor.new_boxed(&ctx),
+        response_strategy_name: ctor.name(),
+        metrics: Metrics::new().unwrap(),
+        capture: None,
+        clock: ctx.clock.clone(),
+        randomness: Randomness::new(0),
+        rate_limiter: None,
+        acl: Default::default(),
+        keys: ctx.keys.clone(),
+        require_auth: false,
+        nts: None,
+        shutdown: Default::default(),
+        stats: Stats::default(),
+    };
+    let socket = server.bind().unwrap();
+    let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = client.local_addr().unwrap();
+    c.bench_function("Server::handle_request", |b| b.iter(|| server.handle_request(&socket, addr, black_box(&request))));
+}
+
+criterion_group!(benches, parse, serialize, handle_request);
+criterion_main!(benches);
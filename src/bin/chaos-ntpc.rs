@@ -1,7 +1,7 @@
 use std::net::{UdpSocket, ToSocketAddrs};
 use clap::{Arg, App};
 use chrono::Utc;
-use chaos_ntp::ntp::{parser::{serialize_packet, parse_packet}, types::*};
+use chaos_ntp::ntp::{types::*, builder::PacketBuilder, parser::ParseMode, auth::{self, Keys}};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = App::new("chaos-ntp client")
@@ -25,36 +25,60 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
              .help("print timestamps as numbers")
              .long("raw-timestamps")
              .required(false))
+        .arg(Arg::with_name("keys")
+             .help("ntp.keys compatible key file")
+             .short("k")
+             .long("keys")
+             .value_name("PATH")
+             .takes_value(true)
+             .required(false))
+        .arg(Arg::with_name("key-id")
+             .help("sign the request with this key and verify the response")
+             .long("key-id")
+             .value_name("ID")
+             .takes_value(true)
+             .requires("keys")
+             .required(false))
+        .arg(Arg::with_name("parse-mode")
+             .help("how forgiving to be with the response, raw shows whatever the server sent")
+             .long("parse-mode")
+             .possible_values(&["strict", "lenient", "raw"])
+             .default_value("strict"))
+        .arg(Arg::with_name("ntp-version")
+             .help("ntp version of the request, version 1 requests are sent with mode 0")
+             .long("ntp-version")
+             .possible_values(&["1", "2", "3", "4"])
+             .default_value("4"))
         .get_matches();
 
     let addr = args.value_of("ADDR").unwrap();
     let port = args.value_of("port").unwrap_or("123");
     let verbose = args.is_present("verbose");
     let raw_timestamps = args.is_present("raw-timestamps");
+    let keys = match args.value_of("keys") {
+        Some(path) => Keys::from_file(path)?,
+        None => Keys::default(),
+    };
+    let key = match args.value_of("key-id") {
+        Some(id) => Some(keys.get(id.parse()?).ok_or("no such key in the key file")?),
+        None => None,
+    };
+
+    let version = args.value_of("ntp-version").unwrap().parse()?;
 
     let resolved_addr = (String::from(addr) + ":" + port)
                    .to_socket_addrs()?
                    .collect::<Vec<_>>();
     
-    let packet = Packet {
-        leap_indicator: LeapIndicator::Unknown,
-        version: 4,
-        mode: Mode::Client,
-        stratum: Stratum::Unsynchronized,
-        poll: 4,
-        precision: -6,
-        root_delay: 65535.into(),
-        root_dispersion: 65535.into(),
-        reference_id: *b"INIT",
-        reference_timestamp: Timestamp(0),
-        origin_timestamp: Timestamp(0),
-        receive_timestamp: Timestamp(0),
-        transit_timestamp: Timestamp::from_utc_datetime(Utc::now()).unwrap(),
-        extensions: None,
-        auth: None
-    };
+    let mut packet = PacketBuilder::client()
+        .version(version)
+        .mode(if version == 1 { Mode::Reserved } else { Mode::Client })
+        .build()?;
+    if let Some(key) = key {
+        auth::sign(&mut packet, key)?;
+    }
 
-    let packet_data = serialize_packet(&packet).unwrap();
+    let packet_data = packet.to_bytes()?;
     let mut response_buf = [0; Packet::MAX_SIZE];
 
     let socket = UdpSocket::bind("0.0.0.0:0")?;
@@ -62,9 +86,33 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     socket.set_read_timeout(Some(std::time::Duration::from_secs(10)))?;
 
     let (size, from) = socket.recv_from(&mut response_buf)?;
-    let response_packet = parse_packet(&response_buf[..size]).unwrap().1.unwrap();
+    let destination = Timestamp::from_utc_datetime(Utc::now())?;
+    let parse_mode = match args.value_of("parse-mode") {
+        Some("lenient") => ParseMode::Lenient,
+        Some("raw") => ParseMode::Raw,
+        _ => ParseMode::Strict,
+    };
+    let (response_packet, anomalies) = Packet::parse_with(&response_buf[..size], parse_mode)?;
 
     println!("response from: {:?}", from);
+    if response_packet.version != version {
+        println!("version mismatch: sent {}, got {}", version, response_packet.version);
+    }
+    for anomaly in anomalies {
+        println!("parse anomaly: {}", anomaly);
+    }
+    if let Err(violations) = response_packet.validate() {
+        for violation in violations.0 {
+            println!("rfc violation: {}", violation);
+        }
+    }
+
+    if key.is_some() || response_packet.auth.is_some() {
+        match auth::verify(&response_packet, &keys) {
+            Ok(id) => println!("authentication: ok, key {}", id),
+            Err(err) => println!("authentication: failed, {}", err),
+        }
+    }
 
     if verbose {
         println!("response size: {:?}", size);
@@ -83,11 +131,27 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             println!("root dispersion: {:?}", response_packet.root_dispersion.into_duration());
         }
 
-        println!("reference id: {:?}", response_packet.reference_id);
-        println!("extensions: {:?}", response_packet.extensions);
+        match response_packet.reference() {
+            reference @ ReferenceId::Ipv4(_) => println!("reference id: {} (or the hash of an ipv6 address)", reference),
+            reference => println!("reference id: {}", reference),
+        }
+        println!("extensions: {:?}", response_packet.typed_extensions());
         println!("auth: {:?}", response_packet.auth);
     }
 
+    //a server that doesn't echo our transmit timestamp could be answering someone else
+    if response_packet.origin_timestamp != packet.transit_timestamp {
+        println!("origin timestamp does not match the request");
+    }
+    let (offset, delay) = response_packet.offset_and_delay(destination);
+    if raw_timestamps {
+        println!("offset: {:?}", offset);
+        println!("delay: {:?}", delay);
+    } else {
+        println!("offset: {}", offset);
+        println!("delay: {}", delay);
+    }
+
     if raw_timestamps {
         println!("reference timestamp: {:?}", response_packet.reference_timestamp);
         println!("origin timestamp: {:?}", response_packet.origin_timestamp);
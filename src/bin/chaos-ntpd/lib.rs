@@ -1,5 +0,0 @@
-pub mod server;
-pub mod response_strategy;
-pub mod logger;
-pub mod config;
-
@@ -1,13 +1,22 @@
 use config::{Config,File};
 use clap::{App,Arg,ArgMatches,SubCommand};
 use std::io::Write;
-mod server;
-mod response_strategy;
-use response_strategy::{ResponseStrategyCtor};
-mod logger;
-use logger::setup_logger;
-mod server_config;
-use server_config::ServerConfig;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,Ordering};
+use slog_scope::info;
+use chaos_ntp::ntpd::{server,capture};
+use chaos_ntp::ntpd::response_strategy::{find_strategy,StrategyContext};
+use chaos_ntp::ntpd::clock;
+use chaos_ntp::ntpd::random::Randomness;
+use chaos_ntp::ntpd::rate_limit::RateLimiter;
+use chaos_ntp::ntpd::acl::Acl;
+use chaos_ntp::ntp::auth::Keys;
+use chaos_ntp::ntp::nts::CookieKey;
+use chaos_ntp::ntpd::nts::NtsKe;
+use chaos_ntp::ntpd::logger::setup_logger;
+use chaos_ntp::ntpd::server_config::ServerConfig;
+use chaos_ntp::ntpd::metrics::Metrics;
+use chaos_ntp::ntpd::capture::PcapWriter;
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -32,6 +41,33 @@ fn get_app<'a, 'b>() -> App<'a, 'b> {
                          .required(true)
                          .help("path of the new config file")
                          .takes_value(true)))
+        .subcommand(SubCommand::with_name("replay")
+                    .about("feed requests from a pcap capture into a response strategy and print the responses")
+                    .arg(Arg::with_name("capture")
+                         .value_name("PATH")
+                         .required(true)
+                         .help("pcap file to read requests from")
+                         .takes_value(true))
+                    .arg(Arg::with_name("strategy")
+                         .value_name("NAME")
+                         .default_value("current_time")
+                         .short("s")
+                         .long("strategy")
+                         .help("response strategy to replay the requests against")
+                         .takes_value(true))
+                    .arg(Arg::with_name("seed")
+                         .value_name("SEED")
+                         .default_value("0")
+                         .long("seed")
+                         .help("seed for random decisions made by the strategy")
+                         .takes_value(true))
+                    .arg(Arg::with_name("port")
+                         .value_name("PORT")
+                         .default_value("123")
+                         .short("p")
+                         .long("port")
+                         .help("only datagrams sent to this port are treated as requests")
+                         .takes_value(true)))
 }
 
 fn start(args: &ArgMatches) -> std::io::Result<()> { 
@@ -68,15 +104,73 @@ fn start(args: &ArgMatches) -> std::io::Result<()> {
 
     let config = config_rep.try_into::<ServerConfig>().unwrap();
 
-    let _guard = setup_logger(config.log.level);
+    let _guard = setup_logger(&config.log)?;
+
+    let keys = match &config.auth.keys {
+        Some(path) => Keys::from_file(path)?,
+        None => Keys::default(),
+    };
+    //the cookie key only lives as long as the process, clients have to redo nts-ke after a restart
+    let cookie_key = if config.nts.enabled {
+        Some(Arc::new(CookieKey::random(&mut rand::rngs::OsRng)))
+    } else {
+        None
+    };
+    let ctx = StrategyContext {
+        clock: clock::from_config(&config.clock)?,
+        keys: Arc::new(keys),
+        nts: cookie_key,
+    };
+    let ctor = find_strategy(&config.server.resp_strategy)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("unknown strategy {}", config.server.resp_strategy)))?;
+    let rs = ctor.new_boxed(&ctx);
+    let acl = Acl::from_config(&config.acl, &ctx)?;
+
+    let metrics = Metrics::new().map_err(std::io::Error::other)?;
+    if config.metrics.enabled {
+        metrics.serve(config.metrics.address)?;
+    }
 
-    let rs = inventory::iter::<&dyn ResponseStrategyCtor>.into_iter().find(|s| s.name() == config.server.resp_strategy).unwrap().new_boxed();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let handler_shutdown = shutdown.clone();
+    ctrlc::set_handler(move || {
+        info!("received termination signal, shutting down");
+        handler_shutdown.store(true, Ordering::SeqCst);
+    }).map_err(std::io::Error::other)?;
+
+    if let Some(cookie_key) = &ctx.nts {
+        let ke = Arc::new(NtsKe::from_config(&config.nts, cookie_key.clone())?);
+        let listener = NtsKe::bind(config.nts.address)?;
+        let ke_shutdown = shutdown.clone();
+        std::thread::Builder::new()
+            .name("nts-ke".to_string())
+            .spawn(move || ke.serve(listener, ke_shutdown))?;
+    }
+
+    let capture = match &config.capture.file {
+        Some(path) => Some(PcapWriter::create(path)?),
+        None => None,
+    };
 
     let mut server = server::Server {
         port: config.server.port,
         addr: config.server.address,
         log_all_requests: config.log.log_all_requests,
+        parse_mode: config.server.parse_mode,
+        response_version: config.server.response_version,
         response_strategy: rs,
+        response_strategy_name: ctor.name(),
+        metrics,
+        capture,
+        clock: ctx.clock.clone(),
+        randomness: config.server.seed.map(Randomness::new).unwrap_or_else(Randomness::from_entropy),
+        rate_limiter: RateLimiter::from_config(&config.rate_limit),
+        acl,
+        keys: ctx.keys.clone(),
+        require_auth: config.auth.require,
+        nts: ctx.nts.clone(),
+        shutdown,
+        stats: Default::default(),
     };
     server.start_server().map_err(|err| match err.kind() {
         std::io::ErrorKind::PermissionDenied => {
@@ -97,6 +191,20 @@ fn generate_config(args: &ArgMatches) -> std::io::Result<()> {
     Ok(())
 }
 
+fn replay(args: &ArgMatches) -> std::io::Result<()> {
+    let path = args.value_of("capture").unwrap();
+    let name = args.value_of("strategy").unwrap();
+    let port = args.value_of("port").unwrap().parse::<u16>()
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    let seed = args.value_of("seed").unwrap().parse::<u64>()
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+
+    let ctor = find_strategy(name)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("unknown strategy {}", name)))?;
+
+    capture::replay(std::path::Path::new(path), port, ctor, seed, &mut std::io::stdout().lock())
+}
+
 fn main() -> std::io::Result<()> {
     let mut app = get_app();
     let args = app.clone().get_matches();
@@ -104,8 +212,9 @@ fn main() -> std::io::Result<()> {
     match args.subcommand() {
         ("start", Some(sub_args)) => start(sub_args),
         ("generate-config", Some(sub_args)) => generate_config(sub_args),
+        ("replay", Some(sub_args)) => replay(sub_args),
         _ => {
-            app.print_help().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            app.print_help().map_err(std::io::Error::other)?;
             println!();
             Ok(())
         }
@@ -1,2 +1,2 @@
 pub mod ntp;
-
+pub mod ntpd;
@@ -0,0 +1,101 @@
+use aes::Aes128;
+use aes::cipher::{BlockEncrypt,KeyInit,generic_array::GenericArray};
+use cmac::{Cmac,Mac};
+
+//AEAD_AES_SIV_CMAC_256 from rfc 5297, the only aead nts servers have to support
+//there is no aes-siv crate we can use, so here it is on top of aes and cmac
+
+pub const KEY_SIZE: usize = 32;
+pub const TAG_SIZE: usize = 16;
+
+#[derive(Clone)]
+pub struct AesSivCmac256 {
+    mac_key: [u8;16],   //k1, s2v
+    ctr_key: Aes128,    //k2, ctr
+}
+
+impl AesSivCmac256 {
+    pub fn new(key: &[u8;KEY_SIZE]) -> Self {
+        let mut mac_key = [0; 16];
+        mac_key.copy_from_slice(&key[..16]);
+        Self { mac_key, ctr_key: Aes128::new(GenericArray::from_slice(&key[16..])) }
+    }
+
+    //returns the synthetic iv followed by the ciphertext, for nts associated_data is [ad, nonce]
+    pub fn encrypt(&self, associated_data: &[&[u8]], plaintext: &[u8]) -> Vec<u8> {
+        let iv = self.s2v(associated_data, plaintext);
+        let mut output = iv.to_vec();
+        output.extend_from_slice(plaintext);
+        self.ctr(&iv, &mut output[TAG_SIZE..]);
+        output
+    }
+
+    //None if the ciphertext or the associated data was tampered with
+    pub fn decrypt(&self, associated_data: &[&[u8]], ciphertext: &[u8]) -> Option<Vec<u8>> {
+        if ciphertext.len() < TAG_SIZE {
+            return None;
+        }
+        let (iv, ciphertext) = ciphertext.split_at(TAG_SIZE);
+        let mut plaintext = ciphertext.to_vec();
+        self.ctr(iv, &mut plaintext);
+
+        let expected = self.s2v(associated_data, &plaintext);
+        if expected.iter().zip(iv).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0 {
+            Some(plaintext)
+        } else {
+            None
+        }
+    }
+
+    fn cmac(&self, data: &[u8]) -> [u8;16] {
+        let mut mac = <Cmac<Aes128> as Mac>::new_from_slice(&self.mac_key).expect("aes-128 key is 16 bytes");
+        mac.update(data);
+        mac.finalize().into_bytes().into()
+    }
+
+    fn s2v(&self, associated_data: &[&[u8]], plaintext: &[u8]) -> [u8;16] {
+        let mut d = self.cmac(&[0; 16]);
+        for component in associated_data {
+            d = xor(dbl(d), self.cmac(component));
+        }
+
+        if plaintext.len() >= 16 {
+            //xorend
+            let mut t = plaintext.to_vec();
+            let start = t.len() - 16;
+            t[start..].iter_mut().zip(&d).for_each(|(a, b)| *a ^= b);
+            self.cmac(&t)
+        } else {
+            let mut padded = [0; 16];
+            padded[..plaintext.len()].copy_from_slice(plaintext);
+            padded[plaintext.len()] = 0x80;
+            self.cmac(&xor(dbl(d), padded))
+        }
+    }
+
+    fn ctr(&self, iv: &[u8], data: &mut [u8]) {
+        //the top bits of the last two 32 bit words are cleared so that implementations can use 64 bit counters
+        let mut counter = [0; 16];
+        counter.copy_from_slice(iv);
+        counter[8] &= 0x7f;
+        counter[12] &= 0x7f;
+        let mut counter = u128::from_be_bytes(counter);
+
+        for chunk in data.chunks_mut(16) {
+            let mut block = GenericArray::from(counter.to_be_bytes());
+            self.ctr_key.encrypt_block(&mut block);
+            chunk.iter_mut().zip(block.iter()).for_each(|(a, b)| *a ^= b);
+            counter = counter.wrapping_add(1);
+        }
+    }
+}
+
+fn dbl(block: [u8;16]) -> [u8;16] {
+    let value = u128::from_be_bytes(block);
+    let carry = if value >> 127 == 1 { 0x87 } else { 0 };
+    ((value << 1) ^ carry).to_be_bytes()
+}
+
+fn xor(a: [u8;16], b: [u8;16]) -> [u8;16] {
+    (u128::from_be_bytes(a) ^ u128::from_be_bytes(b)).to_be_bytes()
+}
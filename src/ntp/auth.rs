@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::path::Path;
+use md5::{Md5,Digest};
+use sha1::Sha1;
+use aes::Aes128;
+use cmac::{Cmac,Mac};
+use simple_error::SimpleError;
+use super::types::{Packet,Auth};
+use super::parser::serialize_packet;
+
+//symmetric key authentication, rfc 5905 (md5), rfc 8573 (aes-cmac) and whatever ntpd does (sha1)
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum MacAlgorithm {
+    Md5,        //md5(key || packet), legacy
+    Sha1,       //sha1(key || packet), legacy
+    AesCmac,    //aes-128-cmac, rfc 8573
+}
+
+impl MacAlgorithm {
+    pub fn digest_size(&self) -> usize {
+        match self {
+            MacAlgorithm::Md5 | MacAlgorithm::AesCmac => 16,
+            MacAlgorithm::Sha1 => 20,
+        }
+    }
+
+    //names used in ntp.keys, ntpd uses M for md5, chrony and ntpsec spell out the cmac one differently
+    fn from_keys_name(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "M" | "MD5" => Some(MacAlgorithm::Md5),
+            "SHA1" | "SHA-1" => Some(MacAlgorithm::Sha1),
+            "AES128CMAC" | "AES-128-CMAC" | "AES128" | "AES-128" | "AES" => Some(MacAlgorithm::AesCmac),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct Key {
+    pub id: u32,
+    pub algorithm: MacAlgorithm,
+    pub secret: Vec<u8>,
+}
+
+impl Key {
+    pub fn mac(&self, data: &[u8]) -> Vec<u8> {
+        match self.algorithm {
+            MacAlgorithm::Md5 => Md5::new().chain_update(&self.secret).chain_update(data).finalize().to_vec(),
+            MacAlgorithm::Sha1 => Sha1::new().chain_update(&self.secret).chain_update(data).finalize().to_vec(),
+            MacAlgorithm::AesCmac => {
+                //key length is checked when the key is created
+                let mut mac = <Cmac<Aes128> as Mac>::new_from_slice(&self.secret).expect("aes-128 key is 16 bytes");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            },
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum AuthError {
+    Missing,            //no mac at all
+    CryptoNak,          //mac with just a key id
+    UnknownKey(u32),
+    WrongLength(u32),   //digest length does not match the key's algorithm
+    BadDigest(u32),
+    Malformed,          //the packet could not be serialized to compute the mac
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "packet is not authenticated"),
+            AuthError::CryptoNak => write!(f, "crypto-nak"),
+            AuthError::UnknownKey(id) => write!(f, "unknown key {}", id),
+            AuthError::WrongLength(id) => write!(f, "wrong digest length for key {}", id),
+            AuthError::BadDigest(id) => write!(f, "digest mismatch for key {}", id),
+            AuthError::Malformed => write!(f, "malformed packet"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+//ntp.keys compatible key file
+//  # comment
+//  1 M     plainpassword
+//  2 SHA1  0123456789abcdef0123456789abcdef01234567
+//  3 AES128CMAC 0123456789abcdef0123456789abcdef
+//keys up to 20 characters are used as is, longer ones are hex
+#[derive(Debug,Clone,Default)]
+pub struct Keys {
+    keys: HashMap<u32, Key>,
+}
+
+impl Keys {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+    }
+
+    pub fn parse(data: &str) -> Result<Self, SimpleError> {
+        let mut keys = HashMap::new();
+        for (n, line) in data.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let key = parse_key(line).map_err(|err| SimpleError::new(format!("line {}: {}", n + 1, err)))?;
+            keys.insert(key.id, key);
+        }
+        Ok(Self { keys })
+    }
+
+    pub fn insert(&mut self, key: Key) {
+        self.keys.insert(key.id, key);
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Key> {
+        self.keys.get(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Key> {
+        self.keys.values()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+fn parse_key(line: &str) -> Result<Key, SimpleError> {
+    let mut fields = line.split_whitespace();
+    let (id, algorithm, secret) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(id), Some(algorithm), Some(secret)) => (id, algorithm, secret),
+        _ => return Err(SimpleError::new("expected key id, type and key")),
+    };
+
+    //key id 0 is reserved for crypto-nak
+    let id = id.parse::<u32>().ok().filter(|id| *id != 0)
+        .ok_or_else(|| SimpleError::new(format!("invalid key id {}", id)))?;
+    let algorithm = MacAlgorithm::from_keys_name(algorithm)
+        .ok_or_else(|| SimpleError::new(format!("unsupported key type {}", algorithm)))?;
+    let secret = if secret.len() <= 20 {
+        secret.as_bytes().to_vec()
+    } else {
+        hex::decode(secret).map_err(|err| SimpleError::new(format!("invalid hex key: {}", err)))?
+    };
+    if algorithm == MacAlgorithm::AesCmac && secret.len() != 16 {
+        return Err(SimpleError::new("aes-128-cmac keys have to be 16 bytes"));
+    }
+
+    Ok(Key { id, algorithm, secret })
+}
+
+//the mac covers everything before it, header and extension fields
+fn mac_input(packet: &Packet) -> Result<Vec<u8>, AuthError> {
+    let unauthenticated = Packet { auth: None, ..packet.clone() };
+    serialize_packet(&unauthenticated).map_err(|_| AuthError::Malformed)
+}
+
+//replaces the mac of the packet with one made with the key
+pub fn sign(packet: &mut Packet, key: &Key) -> Result<(), AuthError> {
+    let digest = key.mac(&mac_input(packet)?);
+    packet.auth = Some(Auth { key_indentifier: key.id, digest });
+    Ok(())
+}
+
+//returns the id of the key the packet was signed with
+pub fn verify(packet: &Packet, keys: &Keys) -> Result<u32, AuthError> {
+    let auth = packet.auth.as_ref().ok_or(AuthError::Missing)?;
+    if auth.digest.is_empty() {
+        return Err(AuthError::CryptoNak);
+    }
+    let key = keys.get(auth.key_indentifier).ok_or(AuthError::UnknownKey(auth.key_indentifier))?;
+    if auth.digest.len() != key.algorithm.digest_size() {
+        return Err(AuthError::WrongLength(key.id));
+    }
+
+    let expected = key.mac(&mac_input(packet)?);
+    //constant time, not that anyone is going to time a chaos server
+    if expected.iter().zip(&auth.digest).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0 {
+        Ok(key.id)
+    } else {
+        Err(AuthError::BadDigest(key.id))
+    }
+}
+
+//rfc 5905 crypto-nak, a mac with key id 0 and no digest
+pub fn crypto_nak() -> Auth {
+    Auth { key_indentifier: 0, digest: Vec::new() }
+}
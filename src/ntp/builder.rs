@@ -0,0 +1,300 @@
+use std::convert::TryFrom;
+use std::time::SystemTime;
+use super::types::*;
+use super::Error;
+
+//packets with sane defaults, checked against rfc 5905 when they are built
+//strategies that want to break the rules have to say so with build_unchecked
+//
+//  let request = PacketBuilder::client().build()?;
+//  let response = PacketBuilder::response_to(&request).timestamps(now).build()?;
+
+//something a well behaved ntp implementation would not send
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Violation {
+    UnsupportedVersion(u8),
+    ReservedMode,                   //mode 0 only makes sense for ntpv1, where every request is mode 0
+    ReservedStratum(u8),
+    KissCodeNotAscii([u8;4]),       //stratum 0 server packets carry a kiss code, four uppercase letters
+    UnsynchronizedLeap { leap_indicator: LeapIndicator, stratum: Stratum },    //leap 3 goes with stratum 0 or 16, in servers
+    ZeroTransmitTimestamp,
+    ReceiveAfterTransmit,
+    ReferenceAfterTransmit,
+    RootDispersionTooLarge(Short),  //MAXDISP, 16 seconds
+    ExtensionsBeforeV4,             //extension fields came with ntpv4
+    DigestLength(usize),            //16 or 20 bytes, or none for a crypto-nak
+    Encoding(Error),                //doesn't serialize or doesn't parse back, extension layouts mostly
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Violation::UnsupportedVersion(version) => write!(f, "unsupported version {}", version),
+            Violation::ReservedMode => write!(f, "mode 0 is reserved from ntpv2 on"),
+            Violation::ReservedStratum(stratum) => write!(f, "stratum {} is reserved", stratum),
+            Violation::KissCodeNotAscii(code) => write!(f, "kiss code {:x?} is not four uppercase letters", code),
+            Violation::UnsynchronizedLeap { leap_indicator, stratum } =>
+                write!(f, "leap indicator {:?} does not match stratum {:?}", leap_indicator, stratum),
+            Violation::ZeroTransmitTimestamp => write!(f, "transmit timestamp is zero"),
+            Violation::ReceiveAfterTransmit => write!(f, "receive timestamp is after the transmit timestamp"),
+            Violation::ReferenceAfterTransmit => write!(f, "reference timestamp is after the transmit timestamp"),
+            Violation::RootDispersionTooLarge(dispersion) =>
+                write!(f, "root dispersion {:?} is over 16 seconds", dispersion.into_duration()),
+            Violation::ExtensionsBeforeV4 => write!(f, "extension fields need ntpv4"),
+            Violation::DigestLength(length) => write!(f, "{} byte digest, expected 16 or 20", length),
+            Violation::Encoding(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Violation {}
+
+//everything wrong with a packet at once
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Violations(pub Vec<Violation>);
+
+impl std::fmt::Display for Violations {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let violations = self.0.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        write!(f, "{}", violations.join(", "))
+    }
+}
+
+impl std::error::Error for Violations {}
+
+//rfc 5905 section 7.3, anything past it is as good as unsynchronized
+const MAX_DISPERSION: Short = Short(16 << 16);
+
+fn is_server(mode: Mode) -> bool {
+    matches!(mode, Mode::Server | Mode::Broadcast | Mode::SymmetricActive | Mode::SymmetricPassive)
+}
+
+impl Packet {
+    //every rule the packet breaks, timestamps are only checked against each other in server packets
+    pub fn validate(&self) -> Result<(), Violations> {
+        let mut violations = Vec::new();
+        if !(Packet::MIN_VERSION..=Packet::MAX_VERSION).contains(&self.version) {
+            violations.push(Violation::UnsupportedVersion(self.version));
+        }
+        if self.mode == Mode::Reserved && self.version != 1 {
+            violations.push(Violation::ReservedMode);
+        }
+        if let Stratum::Reserved(stratum) = self.stratum {
+            violations.push(Violation::ReservedStratum(stratum));
+        }
+        if self.transit_timestamp == Timestamp(0) {
+            violations.push(Violation::ZeroTransmitTimestamp);
+        }
+
+        if is_server(self.mode) {
+            if self.stratum == Stratum::Unspecified && !self.reference_id.iter().all(|b| b.is_ascii_uppercase()) {
+                violations.push(Violation::KissCodeNotAscii(self.reference_id));
+            }
+            let unsynchronized = matches!(self.stratum, Stratum::Unspecified | Stratum::Unsynchronized);
+            if unsynchronized != (self.leap_indicator == LeapIndicator::Unknown) {
+                violations.push(Violation::UnsynchronizedLeap { leap_indicator: self.leap_indicator, stratum: self.stratum });
+            }
+            //kiss-o'-death echo the client's timestamp everywhere, there's nothing to compare
+            if self.stratum != Stratum::Unspecified {
+                if (self.transit_timestamp - self.receive_timestamp).0 < 0 {
+                    violations.push(Violation::ReceiveAfterTransmit);
+                }
+                if (self.transit_timestamp - self.reference_timestamp).0 < 0 {
+                    violations.push(Violation::ReferenceAfterTransmit);
+                }
+            }
+            if self.root_dispersion > MAX_DISPERSION {
+                violations.push(Violation::RootDispersionTooLarge(self.root_dispersion));
+            }
+        }
+
+        if self.extensions.as_ref().is_some_and(|e| !e.is_empty()) && self.version < 4 {
+            violations.push(Violation::ExtensionsBeforeV4);
+        }
+        if let Some(auth) = &self.auth {
+            if ![0, 16, 20].contains(&auth.digest.len()) {
+                violations.push(Violation::DigestLength(auth.digest.len()));
+            }
+        }
+        //the layout rules of rfc 7822 are easier to check on the wire
+        if let Err(err) = self.to_bytes().and_then(|data| PacketRef::parse(&data).map(|_| ())) {
+            violations.push(Violation::Encoding(err));
+        }
+
+        if violations.is_empty() { Ok(()) } else { Err(Violations(violations)) }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct PacketBuilder {
+    packet: Packet,
+    extensions: Vec<Extension>,     //turned into fields when the packet is built
+}
+
+impl PacketBuilder {
+    //ntpv4 client request, unsynchronized, sent now
+    pub fn client() -> Self {
+        Self { extensions: Vec::new(), packet: Packet {
+            leap_indicator: LeapIndicator::Unknown,
+            version: 4,
+            mode: Mode::Client,
+            stratum: Stratum::Unsynchronized,
+            poll: 4,
+            precision: -20,
+            root_delay: Short(0),
+            root_dispersion: Short(0),
+            reference_id: [0; 4],
+            reference_timestamp: Timestamp(0),
+            origin_timestamp: Timestamp(0),
+            receive_timestamp: Timestamp(0),
+            transit_timestamp: Timestamp::from_system_time(SystemTime::now()),
+            extensions: None,
+            auth: None,
+        }}
+    }
+
+    //synchronized stratum 2 server, the timestamps still have to be set
+    pub fn server() -> Self {
+        Self { extensions: Vec::new(), packet: Packet {
+            leap_indicator: LeapIndicator::NoWarning,
+            version: 4,
+            mode: Mode::Server,
+            stratum: Stratum::SecondaryServer(2),
+            poll: 6,
+            precision: -20,
+            root_delay: Short(0),
+            root_dispersion: Short(0),
+            reference_id: [0; 4],
+            reference_timestamp: Timestamp(0),
+            origin_timestamp: Timestamp(0),
+            receive_timestamp: Timestamp(0),
+            transit_timestamp: Timestamp(0),
+            extensions: None,
+            auth: None,
+        }}
+    }
+
+    //server, answering in the request's version with its transmit timestamp as the origin
+    pub fn response_to(request: &Packet) -> Self {
+        Self::server()
+            .version(request.version)
+            .poll(request.poll)
+            .origin_timestamp(request.transit_timestamp)
+    }
+
+    //like ntpd, only the client's transmit timestamp is echoed so no time is given away
+    pub fn kiss_of_death(request: &Packet, code: [u8;4]) -> Self {
+        Self::response_to(request)
+            .leap_indicator(LeapIndicator::Unknown)
+            .stratum(Stratum::Unspecified)
+            .reference_id(code)
+            .receive_timestamp(request.transit_timestamp)
+            .transit_timestamp(request.transit_timestamp)
+    }
+
+    pub fn leap_indicator(mut self, leap_indicator: LeapIndicator) -> Self {
+        self.packet.leap_indicator = leap_indicator;
+        self
+    }
+
+    pub fn version(mut self, version: u8) -> Self {
+        self.packet.version = version;
+        self
+    }
+
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.packet.mode = mode;
+        self
+    }
+
+    pub fn stratum(mut self, stratum: Stratum) -> Self {
+        self.packet.stratum = stratum;
+        self
+    }
+
+    pub fn poll(mut self, poll: i8) -> Self {
+        self.packet.poll = poll;
+        self
+    }
+
+    pub fn precision(mut self, precision: i8) -> Self {
+        self.packet.precision = precision;
+        self
+    }
+
+    pub fn root_delay(mut self, root_delay: Short) -> Self {
+        self.packet.root_delay = root_delay;
+        self
+    }
+
+    pub fn root_dispersion(mut self, root_dispersion: Short) -> Self {
+        self.packet.root_dispersion = root_dispersion;
+        self
+    }
+
+    pub fn reference_id(mut self, reference_id: [u8;4]) -> Self {
+        self.packet.reference_id = reference_id;
+        self
+    }
+
+    pub fn reference_timestamp(mut self, timestamp: Timestamp) -> Self {
+        self.packet.reference_timestamp = timestamp;
+        self
+    }
+
+    pub fn origin_timestamp(mut self, timestamp: Timestamp) -> Self {
+        self.packet.origin_timestamp = timestamp;
+        self
+    }
+
+    pub fn receive_timestamp(mut self, timestamp: Timestamp) -> Self {
+        self.packet.receive_timestamp = timestamp;
+        self
+    }
+
+    pub fn transit_timestamp(mut self, timestamp: Timestamp) -> Self {
+        self.packet.transit_timestamp = timestamp;
+        self
+    }
+
+    //reference, receive and transmit timestamps all at once, for servers that answer instantly
+    pub fn timestamps(self, now: Timestamp) -> Self {
+        self.reference_timestamp(now).receive_timestamp(now).transit_timestamp(now)
+    }
+
+    //fields are added in order, Extension::Unknown for anything the types don't cover
+    pub fn extension(mut self, extension: Extension) -> Self {
+        self.extensions.push(extension);
+        self
+    }
+
+    pub fn auth(mut self, auth: Auth) -> Self {
+        self.packet.auth = Some(auth);
+        self
+    }
+
+    //extensions that don't fit in a field are reported along with everything validate finds
+    pub fn build(self) -> Result<Packet, Violations> {
+        let mut violations = Vec::new();
+        let mut packet = self.packet;
+        for extension in &self.extensions {
+            match ExtensionField::try_from(extension) {
+                Ok(field) => packet.extensions.get_or_insert_with(Vec::new).push(field),
+                Err(err) => violations.push(Violation::Encoding(err)),
+            }
+        }
+        if let Err(Violations(found)) = packet.validate() {
+            violations.extend(found);
+        }
+        if violations.is_empty() { Ok(packet) } else { Err(Violations(violations)) }
+    }
+
+    //for packets that are wrong on purpose, lengths that don't fit are truncated
+    pub fn build_unchecked(self) -> Packet {
+        let mut packet = self.packet;
+        for extension in &self.extensions {
+            packet.extensions.get_or_insert_with(Vec::new).push(extension.to_field_truncated());
+        }
+        packet
+    }
+}
@@ -38,6 +38,7 @@ impl KoD {
     pub const INIT: [u8;4] = *b"INIT";   //the association has not yet synchronized for the first time
     pub const MCST: [u8;4] = *b"MCST";   //the association belongs to a dynamically discovered server
     pub const NKEY: [u8;4] = *b"NKEY";   //no key found. either the key was never installed or is not trusted
+    pub const NTSN: [u8;4] = *b"NTSN";   //nts negative-acknowledgment, the server could not use the cookie (rfc 8915)
     pub const RATE: [u8;4] = *b"RATE";   //rate exceeded, access denied temporarily
     pub const RMOT: [u8;4] = *b"RMOT";   //alteration of associations from a remote host running ntpdc
     pub const STEP: [u8;4] = *b"STEP";   //a step change in system time has occured, but the association has not yet resynchronized
@@ -0,0 +1,56 @@
+use super::parser::ExtensionError;
+use super::auth::AuthError;
+
+//everything that can go wrong parsing, serializing or verifying a packet
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Error {
+    Truncated(usize),               //shorter than the 48 byte header
+    InvalidLeapIndicator(u8),
+    InvalidMode(u8),
+    UnsupportedVersion(u8),         //strict parsing only
+    InvalidStratum(u8),             //a Stratum variant holding a value outside of its range
+    Extension(ExtensionError),      //extension fields and mac don't add up
+    ExtensionTooLong { field_type: u16, length: usize },
+    BufferTooSmall { needed: usize, available: usize },
+    Auth(AuthError),                //missing or mismatched mac
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Truncated(length) => write!(f, "packet is {} bytes, shorter than the header", length),
+            Error::InvalidLeapIndicator(leap) => write!(f, "invalid leap indicator {}", leap),
+            Error::InvalidMode(mode) => write!(f, "invalid mode {}", mode),
+            Error::UnsupportedVersion(version) => write!(f, "unsupported version {}", version),
+            Error::InvalidStratum(stratum) => write!(f, "invalid stratum {}", stratum),
+            Error::Extension(err) => write!(f, "{}", err),
+            Error::ExtensionTooLong { field_type, length } =>
+                write!(f, "extension field {:#06x} is {} bytes, too long for its length field", field_type, length),
+            Error::BufferTooSmall { needed, available } =>
+                write!(f, "packet needs {} bytes, buffer has {}", needed, available),
+            Error::Auth(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Extension(err) => Some(err),
+            Error::Auth(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<ExtensionError> for Error {
+    fn from(err: ExtensionError) -> Self {
+        Error::Extension(err)
+    }
+}
+
+impl From<AuthError> for Error {
+    fn from(err: AuthError) -> Self {
+        Error::Auth(err)
+    }
+}
@@ -1,6 +1,14 @@
 pub mod types;
 pub mod parser;
 pub mod constants;
+pub mod auth;
+pub mod aes_siv;
+pub mod nts;
+pub mod error;
+pub mod serde_impls;
+pub mod builder;
+
+pub use error::Error;
 
 #[cfg(test)]
 pub mod tests;
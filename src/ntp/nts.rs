@@ -0,0 +1,302 @@
+use std::convert::TryFrom;
+use byteorder::{BigEndian,ByteOrder};
+use rand::RngCore;
+use simple_error::SimpleError;
+use super::types::{Packet,ExtensionField,Extension};
+use super::constants::ExtensionFieldType;
+use super::parser::{serialize_packet,parse_extension_fields,serialize_extension_fields};
+use super::aes_siv::{self,AesSivCmac256};
+
+//network time security, rfc 8915
+//the key establishment runs over tls, this is just the protocol, the servers are in ntpd
+
+pub const KE_PORT: u16 = 4460;
+pub const ALPN: &[u8] = b"ntske/1";
+pub const EXPORTER_LABEL: &[u8] = b"EXPORTER-network-time-security";
+pub const PROTOCOL_NTPV4: u16 = 0;
+pub const AEAD_AES_SIV_CMAC_256: u16 = 15;
+pub const NONCE_SIZE: usize = 16;
+pub const COOKIES_PER_KE: usize = 8;
+const MAX_COOKIES: usize = 8;   //per response, no matter how many placeholders there are
+
+#[non_exhaustive]
+pub struct RecordType;
+#[allow(dead_code)]
+impl RecordType {
+    pub const END_OF_MESSAGE: u16 = 0;
+    pub const NEXT_PROTOCOL: u16 = 1;
+    pub const ERROR: u16 = 2;
+    pub const WARNING: u16 = 3;
+    pub const AEAD_ALGORITHM: u16 = 4;
+    pub const NEW_COOKIE: u16 = 5;
+    pub const SERVER: u16 = 6;
+    pub const PORT: u16 = 7;
+}
+
+#[non_exhaustive]
+pub struct KeError;
+#[allow(dead_code)]
+impl KeError {
+    pub const UNRECOGNIZED_CRITICAL_RECORD: u16 = 0;
+    pub const BAD_REQUEST: u16 = 1;
+    pub const INTERNAL_SERVER_ERROR: u16 = 2;
+}
+
+//nts-ke record, a nts-ke message is a list of them ending with END_OF_MESSAGE
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Record {
+    pub critical: bool,
+    pub record_type: u16,   //15 bits
+    pub body: Vec<u8>,
+}
+
+impl Record {
+    pub fn new(critical: bool, record_type: u16, body: Vec<u8>) -> Self {
+        Self { critical, record_type, body }
+    }
+
+    //most records are lists of u16
+    pub fn from_u16s(critical: bool, record_type: u16, values: &[u16]) -> Self {
+        let mut body = vec![0; values.len() * 2];
+        BigEndian::write_u16_into(values, &mut body);
+        Self::new(critical, record_type, body)
+    }
+
+    pub fn end_of_message() -> Self {
+        Self::new(true, RecordType::END_OF_MESSAGE, Vec::new())
+    }
+
+    //a trailing odd byte is ignored
+    pub fn u16s(&self) -> Vec<u16> {
+        self.body.chunks_exact(2).map(BigEndian::read_u16).collect()
+    }
+
+    pub fn serialize(&self, data: &mut Vec<u8>) -> Result<(), SimpleError> {
+        let length = u16::try_from(self.body.len()).map_err(|_| SimpleError::new("nts-ke record too long"))?;
+        let mut header = [0; 4];
+        BigEndian::write_u16(&mut header[..2], (u16::from(self.critical) << 15) | (self.record_type & 0x7fff));
+        BigEndian::write_u16(&mut header[2..], length);
+        data.extend_from_slice(&header);
+        data.extend_from_slice(&self.body);
+        Ok(())
+    }
+}
+
+pub fn serialize_message(records: &[Record]) -> Result<Vec<u8>, SimpleError> {
+    let mut data = Vec::new();
+    for record in records {
+        record.serialize(&mut data)?;
+    }
+    Ok(data)
+}
+
+//None until the whole message, up to and including END_OF_MESSAGE, is there
+//the END_OF_MESSAGE record is not returned
+pub fn parse_message(mut data: &[u8]) -> Option<Vec<Record>> {
+    let mut records = Vec::new();
+    while data.len() >= 4 {
+        let header = BigEndian::read_u16(&data[..2]);
+        let length = usize::from(BigEndian::read_u16(&data[2..4]));
+        if data.len() < 4 + length {
+            return None;
+        }
+        let record = Record::new(header >> 15 == 1, header & 0x7fff, data[4..4 + length].to_vec());
+        data = &data[4 + length..];
+
+        if record.record_type == RecordType::END_OF_MESSAGE {
+            return Some(records);
+        }
+        records.push(record);
+    }
+    None
+}
+
+//context for the tls exporter, the keys differ by aead and direction
+pub fn exporter_context(aead: u16, server_to_client: bool) -> [u8;5] {
+    let mut context = [0; 5];
+    BigEndian::write_u16(&mut context[..2], PROTOCOL_NTPV4);
+    BigEndian::write_u16(&mut context[2..4], aead);
+    context[4] = u8::from(server_to_client);
+    context
+}
+
+//exported from the tls session during key establishment
+#[derive(Clone,PartialEq,Eq)]
+pub struct SessionKeys {
+    pub c2s: [u8;aes_siv::KEY_SIZE],
+    pub s2c: [u8;aes_siv::KEY_SIZE],
+}
+
+impl std::fmt::Debug for SessionKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "SessionKeys {{ .. }}")
+    }
+}
+
+//cookies are the session keys encrypted with a key only the server knows, so the server doesn't
+//have to remember anything about its clients
+//nonce || aes-siv(nonce, c2s || s2c)
+pub struct CookieKey {
+    aead: AesSivCmac256,
+}
+
+impl CookieKey {
+    pub fn new(key: &[u8;aes_siv::KEY_SIZE]) -> Self {
+        Self { aead: AesSivCmac256::new(key) }
+    }
+
+    pub fn random(rng: &mut dyn RngCore) -> Self {
+        let mut key = [0; aes_siv::KEY_SIZE];
+        rng.fill_bytes(&mut key);
+        Self::new(&key)
+    }
+
+    pub fn seal(&self, keys: &SessionKeys, rng: &mut dyn RngCore) -> Vec<u8> {
+        let mut nonce = [0; NONCE_SIZE];
+        rng.fill_bytes(&mut nonce);
+        let mut plaintext = keys.c2s.to_vec();
+        plaintext.extend_from_slice(&keys.s2c);
+
+        let mut cookie = nonce.to_vec();
+        cookie.extend(self.aead.encrypt(&[&nonce], &plaintext));
+        cookie
+    }
+
+    pub fn open(&self, cookie: &[u8]) -> Option<SessionKeys> {
+        if cookie.len() < NONCE_SIZE {
+            return None;
+        }
+        let (nonce, ciphertext) = cookie.split_at(NONCE_SIZE);
+        let plaintext = self.aead.decrypt(&[nonce], ciphertext)?;
+        if plaintext.len() != 2 * aes_siv::KEY_SIZE {
+            return None;
+        }
+
+        let mut keys = SessionKeys { c2s: [0; aes_siv::KEY_SIZE], s2c: [0; aes_siv::KEY_SIZE] };
+        keys.c2s.copy_from_slice(&plaintext[..aes_siv::KEY_SIZE]);
+        keys.s2c.copy_from_slice(&plaintext[aes_siv::KEY_SIZE..]);
+        Some(keys)
+    }
+}
+
+//the authenticator covers everything in the packet before it
+pub fn associated_data(packet: &Packet, authenticator: usize) -> Result<Vec<u8>, SimpleError> {
+    let extensions = packet.extensions.as_ref().map(|e| e[..authenticator.min(e.len())].to_vec());
+    serialize_packet(&Packet { extensions, auth: None, ..packet.clone() })
+        .map_err(|err| SimpleError::new(err.to_string()))
+}
+
+//NTP_AUTHENTICATOR field over the encrypted extension fields
+pub fn authenticator(key: &[u8;aes_siv::KEY_SIZE], associated_data: &[u8], nonce: &[u8], encrypted: &[ExtensionField])
+        -> Result<ExtensionField, SimpleError> {
+    let mut plaintext = Vec::new();
+    serialize_extension_fields(encrypted, &mut plaintext).map_err(|err| SimpleError::new(err.to_string()))?;
+    let ciphertext = AesSivCmac256::new(key).encrypt(&[associated_data, nonce], &plaintext);
+    ExtensionField::try_from(Extension::NtsAuthenticator { nonce: nonce.to_vec(), ciphertext })
+        .map_err(|err| SimpleError::new(err.to_string()))
+}
+
+//returns the encrypted extension fields, None if the authenticator is malformed or doesn't match
+pub fn open_authenticator(key: &[u8;aes_siv::KEY_SIZE], associated_data: &[u8], field: &ExtensionField)
+        -> Option<Vec<ExtensionField>> {
+    let (nonce, ciphertext) = match Extension::from(field) {
+        Extension::NtsAuthenticator { nonce, ciphertext } => (nonce, ciphertext),
+        _ => return None,
+    };
+
+    let plaintext = AesSivCmac256::new(key).decrypt(&[associated_data, &nonce], &ciphertext)?;
+    match parse_extension_fields(&plaintext) {
+        Ok((&[], fields)) => Some(fields),
+        _ => None,
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum NtsError {
+    NoCookie,
+    BadCookie,          //not one of ours or tampered with
+    NoAuthenticator,
+    BadAuthenticator,
+}
+
+impl std::fmt::Display for NtsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            NtsError::NoCookie => write!(f, "no nts cookie"),
+            NtsError::BadCookie => write!(f, "invalid nts cookie"),
+            NtsError::NoAuthenticator => write!(f, "no nts authenticator"),
+            NtsError::BadAuthenticator => write!(f, "invalid nts authenticator"),
+        }
+    }
+}
+
+impl std::error::Error for NtsError {}
+
+//what the server learns from an authenticated nts request
+#[derive(Debug,Clone)]
+pub struct NtsRequest {
+    pub unique_id: Option<Vec<u8>>,
+    pub keys: SessionKeys,
+    pub cookies_wanted: usize,  //one for the cookie that was used up, one for each placeholder
+}
+
+pub fn is_nts_request(packet: &Packet) -> bool {
+    packet.extensions.iter().flatten().any(|e| e.field_type == ExtensionFieldType::NTS_COOKIE)
+}
+
+//fields after the authenticator are not authenticated and ignored
+pub fn open_request(packet: &Packet, cookie_key: &CookieKey) -> Result<NtsRequest, NtsError> {
+    let extensions = packet.extensions.as_deref().unwrap_or(&[]);
+    let position = extensions.iter().position(|e| e.field_type == ExtensionFieldType::NTP_AUTHENTICATOR);
+    let authenticated = &extensions[..position.unwrap_or(extensions.len())];
+
+    let cookie = authenticated.iter().find(|e| e.field_type == ExtensionFieldType::NTS_COOKIE)
+        .ok_or(NtsError::NoCookie)?;
+    let keys = cookie_key.open(&cookie.value).ok_or(NtsError::BadCookie)?;
+    let position = position.ok_or(NtsError::NoAuthenticator)?;
+
+    let associated_data = associated_data(packet, position).map_err(|_| NtsError::BadAuthenticator)?;
+    let encrypted = open_authenticator(&keys.c2s, &associated_data, &extensions[position])
+        .ok_or(NtsError::BadAuthenticator)?;
+
+    let placeholders = authenticated.iter().chain(&encrypted)
+        .filter(|e| e.field_type == ExtensionFieldType::NTS_COOKIE_PLACEHOLDER)
+        .count();
+    Ok(NtsRequest {
+        unique_id: authenticated.iter().find(|e| e.field_type == ExtensionFieldType::UNIQUE).map(|e| e.value.clone()),
+        keys,
+        cookies_wanted: (1 + placeholders).min(MAX_COOKIES),
+    })
+}
+
+pub fn unique_id(value: Vec<u8>) -> ExtensionField {
+    ExtensionField { field_type: ExtensionFieldType::UNIQUE, value }
+}
+
+pub fn cookie(value: Vec<u8>) -> ExtensionField {
+    ExtensionField { field_type: ExtensionFieldType::NTS_COOKIE, value }
+}
+
+//replaces the extensions of the response with the unique id echo and an authenticator carrying
+//fresh cookies, there is no mac with nts
+pub fn protect_response(response: &mut Packet, request: &NtsRequest, cookie_key: &CookieKey, rng: &mut dyn RngCore)
+        -> Result<(), SimpleError> {
+    let cookies = (0..request.cookies_wanted)
+        .map(|_| cookie(cookie_key.seal(&request.keys, rng)))
+        .collect::<Vec<_>>();
+    seal_response(response, request, &cookies, rng)
+}
+
+//protect_response with whatever cookies the caller wants to send
+pub fn seal_response(response: &mut Packet, request: &NtsRequest, encrypted: &[ExtensionField], rng: &mut dyn RngCore)
+        -> Result<(), SimpleError> {
+    response.auth = None;
+    response.extensions = Some(request.unique_id.iter().cloned().map(unique_id).collect());
+
+    let mut nonce = [0; NONCE_SIZE];
+    rng.fill_bytes(&mut nonce);
+    let associated_data = associated_data(response, usize::MAX)?;
+    let authenticator = authenticator(&request.keys.s2c, &associated_data, &nonce, encrypted)?;
+    response.extensions.get_or_insert_with(Vec::new).push(authenticator);
+    Ok(())
+}
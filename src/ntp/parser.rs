@@ -1,182 +1,317 @@
 use std::convert::{TryFrom,TryInto};
-use std::io::Write;
 use nom::{IResult};
-use byteorder::{BigEndian, WriteBytesExt};
-use simple_error::SimpleError;
+use byteorder::{BigEndian, ByteOrder};
 use super::types::*;
+use serde::{Deserialize,Serialize};
+use super::Error;
 
 //now that i think about it, maybe using nom for parsing something as simple as an ntp packet was
 //not the smartest idea 
 //had fun though
 
-fn parse_header(input: (&[u8], usize)) -> IResult<(&[u8], usize), (u8, u8, u8)> {
-    nom::error::context(
-        "ntp_header",
-        nom::sequence::tuple((
-            nom::bits::complete::take(2usize),  //leap_indicator
-            nom::bits::complete::take(3usize),  //version
-            nom::bits::complete::take(3usize),  //mode
-        ))
-    )(input)
-}
-
-//stratum poll precision root_delay root_dispersion reference_id
-type HeaderTuple<'a> = (u8, i8, i8, u32, u32, &'a [u8]);
-fn parse_metadata(input: &[u8]) -> IResult<&[u8], HeaderTuple> {
-    nom::error::context(
-        "ntp_metadata",
-        nom::sequence::tuple((
-            nom::number::complete::u8,                                  //stratum
-            nom::number::complete::i8,                                  //poll
-            nom::number::complete::i8,                                  //precision
-            nom::number::complete::u32(nom::number::Endianness::Big),   //root_delay
-            nom::number::complete::u32(nom::number::Endianness::Big),   //root_dispersion
-            nom::bytes::complete::take(4usize),                         //reference_id
-        ))
-    )(input)
-}
-
-fn parse_timedata(input: &[u8]) -> IResult<&[u8], (u64, u64, u64, u64)> {
-    nom::error::context(
-        "ntp_timedata",
-        nom::sequence::tuple((
-            nom::number::complete::u64(nom::number::Endianness::Big),   //reference_timestamp
-            nom::number::complete::u64(nom::number::Endianness::Big),   //origin_timestamp
-            nom::number::complete::u64(nom::number::Endianness::Big),   //reference_timestamp
-            nom::number::complete::u64(nom::number::Endianness::Big),   //transit_timestamp
-        ))
-    )(input)
-}
-
-fn parse_auth(input: &[u8]) -> IResult<&[u8], Option<Auth>> {
-    nom::error::context(
-        "ntp_auth",
-        nom::combinator::map(
-            nom::combinator::opt(
-                nom::sequence::tuple((
-                    nom::number::complete::u32(nom::number::Endianness::Big),   //key_indentifier
-                    nom::number::complete::u128(nom::number::Endianness::Big),  //digest
-                ))
-            ),
-            |auth| auth.map(|a| Auth { key_indentifier: a.0, digest: a.1 })
-        )
-    )(input)
-}
-
+//the length on the wire covers the type and length fields too, the value is padded to 4 bytes
 fn parse_extension(input: &[u8]) -> IResult<&[u8], ExtensionField> {
     let (input, field_type) = nom::number::complete::u16(nom::number::Endianness::Big)(input)?;
     let (input, length) = nom::combinator::verify(
         nom::number::complete::u16(nom::number::Endianness::Big),
-        |s| *s > 0 && input.len() > Packet::AUTH_SIZE && usize::from(*s) <= input.len()-Packet::AUTH_SIZE)(input)?;
-    let (input, bytes) = nom::bytes::complete::take::<usize,_,_>(length.into())(input)?;
+        |s| usize::from(*s) >= Packet::EXT_HEAD_SIZE && s % 4 == 0
+            && usize::from(*s) - Packet::EXT_HEAD_SIZE <= input.len())(input)?;
+    let (input, bytes) = nom::bytes::complete::take::<usize,_,_>(usize::from(length) - Packet::EXT_HEAD_SIZE)(input)?;
     Ok((input, ExtensionField { field_type, value: bytes.to_vec() }))
 }
 
-//at this point i just stopped caring about that nom syntax sugar
-//i have no idea how to implement this the correct:tm: way
-//i think its still missing some error handling, what if the extension field has incorrect length
-//and we accidentally consume some bytes from the auth fields
-//i think this case is not handled at all yet, i need to start writing tests
-//update: maybe its handled now but im not sure if its the correct way 
-fn parse_extensions(input: &[u8]) -> IResult<&[u8], Vec<ExtensionField>> {
-    let mut res: Vec<ExtensionField> = Vec::new();
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ExtensionError {
+    TooShort { offset: usize, length: usize },      //field shorter than 16 bytes
+    Unaligned { offset: usize, length: usize },     //field length not a multiple of 4
+    Truncated { offset: usize, length: usize },     //field goes past the end of the packet
+    LastTooShort { offset: usize, length: usize },  //last field shorter than 28 bytes with no mac after it
+    Trailing { offset: usize, length: usize },      //bytes left over that are neither a field nor a mac
+}
+
+impl std::fmt::Display for ExtensionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ExtensionError::TooShort { offset, length } =>
+                write!(f, "extension field at byte {} is {} bytes, less than {}", offset, length, Packet::MIN_EXT_SIZE),
+            ExtensionError::Unaligned { offset, length } =>
+                write!(f, "extension field at byte {} is {} bytes, not a multiple of 4", offset, length),
+            ExtensionError::Truncated { offset, length } =>
+                write!(f, "extension field at byte {} is {} bytes, longer than the rest of the packet", offset, length),
+            ExtensionError::LastTooShort { offset, length } =>
+                write!(f, "last extension field at byte {} is {} bytes, less than {} without a mac", offset, length, Packet::MIN_LAST_EXT_SIZE),
+            ExtensionError::Trailing { offset, length } =>
+                write!(f, "{} bytes at byte {} are neither an extension field nor a mac", length, offset),
+        }
+    }
+}
+
+impl std::error::Error for ExtensionError {}
+
+//rfc 7822 section 7.5, everything after the 48 byte header
+//what's left is a mac if it's exactly 4 (crypto-nak), 20 (md5, cmac) or 24 (sha1) bytes, anything
+//else has to start with an extension field. the 28 byte minimum for the last field without a mac
+//is what keeps fields and macs from being mistaken for each other
+//returns the extension fields still on the wire and the mac, nothing is copied
+pub fn split_extensions_and_mac(input: &[u8]) -> Result<(ExtensionFields<'_>, Option<AuthRef<'_>>), ExtensionError> {
     let mut i = input;
-    
-    while i.len() > Packet::AUTH_SIZE {
-        let (ni, extension) = parse_extension(input)?;
-        i = ni;
-        res.push(extension);
+    let mut last: Option<(usize, usize)> = None;
+    let offset = |i: &[u8]| Packet::BASE_SIZE + input.len() - i.len();
+
+    let auth = loop {
+        match i.len() {
+            0 => break None,
+            Packet::CRYPTO_NAK_SIZE | Packet::AUTH_SIZE | Packet::MAX_AUTH_SIZE => break Some(AuthRef {
+                key_indentifier: BigEndian::read_u32(i),
+                digest: &i[Packet::CRYPTO_NAK_SIZE..],
+            }),
+            n if n < Packet::MIN_EXT_SIZE => return Err(ExtensionError::Trailing { offset: offset(i), length: n }),
+            _ => (),
+        }
+
+        let length = usize::from(BigEndian::read_u16(&i[2..4]));
+        let error = if length < Packet::MIN_EXT_SIZE {
+            Some(ExtensionError::TooShort { offset: offset(i), length })
+        } else if length % 4 != 0 {
+            Some(ExtensionError::Unaligned { offset: offset(i), length })
+        } else if length > i.len() {
+            Some(ExtensionError::Truncated { offset: offset(i), length })
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            return Err(error);
+        }
+
+        last = Some((offset(i), length));
+        i = &i[length..];
+    };
+
+    if let Some((offset, length)) = last {
+        if auth.is_none() && length < Packet::MIN_LAST_EXT_SIZE {
+            return Err(ExtensionError::LastTooShort { offset, length });
+        }
     }
+    let extensions = &input[..input.len() - i.len()];
+    Ok((ExtensionFields::new(extensions), auth))
+}
 
-    if i.len() < Packet::AUTH_SIZE {
-        Err(nom::Err::Incomplete(nom::Needed::new(Packet::AUTH_SIZE-i.len())))
-    } else {
-        Ok((i, res))
+pub fn parse_extensions_and_mac(input: &[u8]) -> Result<(Option<Vec<ExtensionField>>, Option<Auth>), ExtensionError> {
+    let (extensions, auth) = split_extensions_and_mac(input)?;
+    Ok((
+        if extensions.is_empty() { None } else { Some(extensions.map(ExtensionField::from).collect()) },
+        auth.map(|auth| Auth { key_indentifier: auth.key_indentifier, digest: auth.digest.to_vec() }),
+    ))
+}
+
+//extension fields and nothing else, like the plaintext of an nts authenticator
+pub fn parse_extension_fields(input: &[u8]) -> IResult<&[u8], Vec<ExtensionField>> {
+    nom::multi::many0(nom::combinator::complete(parse_extension))(input)
+}
+
+pub fn serialize_extension_fields(extensions: &[ExtensionField], data: &mut Vec<u8>) -> Result<(), Error> {
+    for n in extensions {
+        let padding = n.padded_len() - n.value.len();
+        let length = u16::try_from(Packet::EXT_HEAD_SIZE + n.padded_len())
+            .map_err(|_| Error::ExtensionTooLong { field_type: n.field_type, length: Packet::EXT_HEAD_SIZE + n.padded_len() })?;
+        data.extend_from_slice(&n.field_type.to_be_bytes());
+        data.extend_from_slice(&length.to_be_bytes());
+        data.extend_from_slice(&n.value);
+        data.extend_from_slice(&[0; 3][..padding]);
+    }
+    Ok(())
+}
+
+fn stratum_byte(stratum: Stratum) -> Result<u8, Error> {
+    stratum.try_into().map_err(|_| Error::InvalidStratum(match stratum {
+        Stratum::SecondaryServer(v) | Stratum::Reserved(v) => v,
+        _ => 0,
+    }))
+}
+
+//nom flavoured Packet::parse, the nom result is only an error if the header is incomplete
+pub fn parse_packet(input: &[u8]) -> IResult<(&[u8], usize), Result<Packet, Error>> {
+    if input.len() < Packet::BASE_SIZE {
+        return Err(nom::Err::Error(nom::error::Error::new((input, 0), nom::error::ErrorKind::Eof)));
     }
+    Ok(((&input[input.len()..], 0), Packet::parse(input)))
 }
 
-pub fn parse_packet(input: &[u8]) -> IResult<(&[u8], usize), Result<Packet, SimpleError>> {
-    let has_extensions = input.len() > Packet::BASE_SIZE + Packet::AUTH_SIZE;
-
-    nom::error::context(
-        "ntp_packet",
-        nom::combinator::map(
-            nom::sequence::tuple((
-                parse_header,
-                nom::bits::bytes::<_,_,nom::error::Error<_>,_,_>(
-                    nom::sequence::tuple((
-                        parse_metadata,
-                        parse_timedata,
-                        nom::error::context(
-                            "ntp_extensions",
-                            nom::combinator::cond(has_extensions, parse_extensions)
-                        ),
-                        parse_auth,
-                    ))
-                ),
-            )),
-            |(
-              (leap_indicator, version, mode),
-              ((stratum, poll, precision, root_delay, root_dispersion, reference_id),
-               (reference_timestamp, origin_timestamp, receive_timestamp, transit_timestamp),
-               extensions,
-               auth),
-            )| {
-                Ok(Packet {
-                    version,
-                    leap_indicator: LeapIndicator::try_from(leap_indicator).map_err(|_| "invalid leap_indicator")?,
-                    mode: Mode::try_from(mode).map_err(|_| "invalid mode")?,
-
-                    stratum: stratum.try_into()?,
-                    poll, precision, 
-                    root_delay: root_delay.into(), 
-                    root_dispersion: root_dispersion.into(),
-                    reference_id: reference_id[0..4].try_into().map_err(|_| "invalid reference_id")?,
-
-                    reference_timestamp: reference_timestamp.into(), 
-                    origin_timestamp: origin_timestamp.into(), 
-                    receive_timestamp: receive_timestamp.into(), 
-                    transit_timestamp: transit_timestamp.into(),
-
-                    extensions,
-                    auth,
-                })
-            },
-        )
-    )((input,0))
-}
-
-pub fn serialize_packet(packet: &Packet) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let mut data: Vec<u8> = Vec::with_capacity(packet.size());
+pub fn serialize_into(packet: &Packet, buf: &mut [u8]) -> Result<usize, Error> {
+    let size = packet.size();
+    if buf.len() < size {
+        return Err(Error::BufferTooSmall { needed: size, available: buf.len() });
+    }
 
     let packet_indicator: u8 = packet.leap_indicator.into();
     let mode: u8 = packet.mode.into();
-    let header = (packet_indicator << 6) | (packet.version << 3) | (mode);
-    data.write_u8(header)?;
-    data.write_u8(packet.stratum.try_into()?)?;
-    data.write_i8(packet.poll)?;
-    data.write_i8(packet.precision)?;
-    data.write_u32::<BigEndian>(packet.root_delay.into())?;
-    data.write_u32::<BigEndian>(packet.root_dispersion.into())?;
-    for n in &packet.reference_id { data.write_u8(*n)?; }
-    data.write_u64::<BigEndian>(packet.reference_timestamp.into())?;
-    data.write_u64::<BigEndian>(packet.origin_timestamp.into())?;
-    data.write_u64::<BigEndian>(packet.receive_timestamp.into())?;
-    data.write_u64::<BigEndian>(packet.transit_timestamp.into())?;
-
-    if let Some(extensions) = &packet.extensions {
-        for n in extensions {
-            data.write_u16::<BigEndian>(n.field_type)?;
-            data.write_u16::<BigEndian>(n.value.len() as u16)?;
-            data.write_all(&n.value)?;
-        }
+    buf[0] = (packet_indicator << 6) | (packet.version << 3) | (mode);
+    buf[1] = stratum_byte(packet.stratum)?;
+    buf[2] = packet.poll as u8;
+    buf[3] = packet.precision as u8;
+    BigEndian::write_u32(&mut buf[4..8], packet.root_delay.into());
+    BigEndian::write_u32(&mut buf[8..12], packet.root_dispersion.into());
+    buf[12..16].copy_from_slice(&packet.reference_id);
+    BigEndian::write_u64(&mut buf[16..24], packet.reference_timestamp.into());
+    BigEndian::write_u64(&mut buf[24..32], packet.origin_timestamp.into());
+    BigEndian::write_u64(&mut buf[32..40], packet.receive_timestamp.into());
+    BigEndian::write_u64(&mut buf[40..48], packet.transit_timestamp.into());
+
+    let mut i = Packet::BASE_SIZE;
+    for n in packet.extensions.iter().flatten() {
+        let length = Packet::EXT_HEAD_SIZE + n.padded_len();
+        let length_field = u16::try_from(length)
+            .map_err(|_| Error::ExtensionTooLong { field_type: n.field_type, length })?;
+        BigEndian::write_u16(&mut buf[i..], n.field_type);
+        BigEndian::write_u16(&mut buf[i + 2..], length_field);
+        buf[i + 4..i + 4 + n.value.len()].copy_from_slice(&n.value);
+        buf[i + 4 + n.value.len()..i + length].iter_mut().for_each(|b| *b = 0);
+        i += length;
     }
-    
-    if let Some(auth) = &packet.auth { 
-        data.write_u32::<BigEndian>(auth.key_indentifier)?;
-        data.write_u128::<BigEndian>(auth.digest)?;
+
+    if let Some(auth) = &packet.auth {
+        BigEndian::write_u32(&mut buf[i..], auth.key_indentifier);
+        buf[i + 4..i + 4 + auth.digest.len()].copy_from_slice(&auth.digest);
+        i += 4 + auth.digest.len();
     }
 
+    Ok(i)
+}
+
+pub fn serialize_packet(packet: &Packet) -> Result<Vec<u8>, Error> {
+    let mut data = vec![0; packet.size()];
+    let size = serialize_into(packet, &mut data)?;
+    data.truncate(size);
     Ok(data)
 }
 
+impl Packet {
+    //the whole datagram, anything that doesn't fit the header, extension fields and mac is an error
+    pub fn parse(data: &[u8]) -> Result<Packet, Error> {
+        PacketRef::parse(data).map(|packet| packet.to_packet())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        serialize_packet(self)
+    }
+
+    pub fn serialize_into(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        serialize_into(self, buf)
+    }
+}
+
+//how forgiving parsing is, see Packet::parse_with
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default,Serialize,Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParseMode {
+    Strict,     //rfc 5905, at least 48 bytes, versions 1 to 4
+    #[default]
+    Lenient,    //short packets are zero padded (sntp clients that send less), any version
+    Raw,        //never fails, extension fields that don't add up are kept as far as they go
+}
+
+//something off about a packet that was parsed anyway
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Anomaly {
+    Short(usize),               //zero padded to 48 bytes
+    UnknownVersion(u8),
+    Extension(ExtensionError),  //raw only, fields up to the error are kept
+}
+
+impl Anomaly {
+    //for metric labels
+    pub fn name(&self) -> &'static str {
+        match self {
+            Anomaly::Short(_) => "short",
+            Anomaly::UnknownVersion(_) => "unknown_version",
+            Anomaly::Extension(_) => "extension",
+        }
+    }
+}
+
+impl std::fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Anomaly::Short(length) => write!(f, "packet is {} bytes, zero padded to {}", length, Packet::BASE_SIZE),
+            Anomaly::UnknownVersion(version) => write!(f, "unknown version {}", version),
+            Anomaly::Extension(err) => write!(f, "{}, dropped the rest", err),
+        }
+    }
+}
+
+impl Packet {
+    pub const MIN_VERSION: u8 = 1;
+    pub const MAX_VERSION: u8 = 4;
+
+    //see PacketRef::parse_with
+    pub fn parse_with(data: &[u8], mode: ParseMode) -> Result<(Packet, Vec<Anomaly>), Error> {
+        PacketRef::parse_with(data, mode).map(|(packet, anomalies)| (packet.to_packet(), anomalies))
+    }
+}
+
+impl<'a> PacketRef<'a> {
+    //anomalies are only ever reported in lenient and raw mode, strict turns them into errors
+    //short packets have no room for extension fields, only their header is padded
+    pub fn parse_with(data: &'a [u8], mode: ParseMode) -> Result<(PacketRef<'a>, Vec<Anomaly>), Error> {
+        let mut anomalies = Vec::new();
+        let packet = if data.len() < Packet::BASE_SIZE && mode != ParseMode::Strict {
+            anomalies.push(Anomaly::Short(data.len()));
+            let mut padded = [0; Packet::BASE_SIZE];
+            padded[..data.len()].copy_from_slice(data);
+            parse_fixed_header(&padded)?
+        } else {
+            match PacketRef::parse(data) {
+                Ok(packet) => packet,
+                Err(Error::Extension(err)) if mode == ParseMode::Raw => {
+                    anomalies.push(Anomaly::Extension(err));
+                    PacketRef { extensions: ExtensionFields::new(&data[Packet::BASE_SIZE..]), ..parse_fixed_header(data)? }
+                },
+                Err(err) => return Err(err),
+            }
+        };
+
+        if !(Packet::MIN_VERSION..=Packet::MAX_VERSION).contains(&packet.version) {
+            if mode == ParseMode::Strict {
+                return Err(Error::UnsupportedVersion(packet.version));
+            }
+            anomalies.push(Anomaly::UnknownVersion(packet.version));
+        }
+        Ok((packet, anomalies))
+    }
+
+    //same rules as Packet::parse, without nom and without allocating
+    pub fn parse(data: &'a [u8]) -> Result<PacketRef<'a>, Error> {
+        if data.len() < Packet::BASE_SIZE {
+            return Err(Error::Truncated(data.len()));
+        }
+        let (extensions, auth) = split_extensions_and_mac(&data[Packet::BASE_SIZE..])?;
+        Ok(PacketRef { extensions, auth, ..parse_fixed_header(data)? })
+    }
+}
+
+//the first 48 bytes, data has to be at least that long
+fn parse_fixed_header(data: &[u8]) -> Result<PacketRef<'static>, Error> {
+    let leap_indicator = data[0] >> 6;
+    let mode = data[0] & 0x7;
+    let mut reference_id = [0; 4];
+    reference_id.copy_from_slice(&data[12..16]);
+
+    Ok(PacketRef {
+        leap_indicator: LeapIndicator::try_from(leap_indicator).map_err(|_| Error::InvalidLeapIndicator(leap_indicator))?,
+        version: (data[0] >> 3) & 0x7,
+        mode: Mode::try_from(mode).map_err(|_| Error::InvalidMode(mode))?,
+        stratum: data[1].try_into().map_err(|_| Error::InvalidStratum(data[1]))?,
+        poll: data[2] as i8,
+        precision: data[3] as i8,
+        root_delay: BigEndian::read_u32(&data[4..8]).into(),
+        root_dispersion: BigEndian::read_u32(&data[8..12]).into(),
+        reference_id,
+        reference_timestamp: BigEndian::read_u64(&data[16..24]).into(),
+        origin_timestamp: BigEndian::read_u64(&data[24..32]).into(),
+        receive_timestamp: BigEndian::read_u64(&data[32..40]).into(),
+        transit_timestamp: BigEndian::read_u64(&data[40..48]).into(),
+        extensions: ExtensionFields::new(&[]),
+        auth: None,
+    })
+}
+
@@ -0,0 +1,221 @@
+use std::convert::{TryFrom,TryInto};
+use std::net::Ipv4Addr;
+use serde::{Serializer,Deserializer,Serialize,Deserialize};
+use serde::de::{self,Visitor};
+use super::types::{Timestamp,Short,Stratum,Fixed};
+
+//serde for the packet types
+//human readable formats (json, toml) get something a person can write by hand: timestamps as
+//iso 8601 dates, shorts as seconds, reference ids as kiss codes or addresses and bytes as hex
+//everything else gets the numbers and bytes as they are on the wire
+
+//ten decimal places are enough to tell every 2^-32 fraction apart, so timestamps survive the trip
+const TIMESTAMP_DECIMALS: u32 = 10;
+
+//era 0, dates after 2036 wrap around when read back
+fn format_timestamp(timestamp: Timestamp) -> String {
+    let scale = 10u128.pow(TIMESTAMP_DECIMALS);
+    let units = (u128::from(timestamp.0) * scale + (1 << 31)) >> 32;
+    let seconds = Fixed::from_seconds((units / scale) as i64);
+    let datetime = chrono::DateTime::<chrono::Utc>::try_from(seconds).expect("era 0 is a valid date");
+    format!("{}.{:0width$}Z", datetime.format("%Y-%m-%dT%H:%M:%S"), units % scale, width = TIMESTAMP_DECIMALS as usize)
+}
+
+//rfc 3339, any offset and any number of decimal places, the ones past 19 are ignored
+fn parse_timestamp(value: &str) -> Option<Timestamp> {
+    let datetime = chrono::DateTime::parse_from_rfc3339(value).ok()?;
+    let digits = value.get(19..)?.strip_prefix('.').unwrap_or("");
+    let digits = &digits[..digits.find(|c: char| !c.is_ascii_digit()).unwrap_or(digits.len())];
+    let digits = &digits[..digits.len().min(19)];
+    let fraction = match digits {
+        "" => 0,
+        digits => {
+            let scale = 10u128.pow(digits.len() as u32);
+            ((digits.parse::<u128>().ok()? << 64) + scale / 2) / scale
+        },
+    };
+    let seconds = Fixed::UNIX_EPOCH.checked_add(Fixed::from_seconds(datetime.timestamp()))?;
+    Some(seconds.checked_add(Fixed(fraction as i128))?.to_timestamp())
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&format_timestamp(*self))
+        } else {
+            serializer.serialize_u64(self.0)
+        }
+    }
+}
+
+//the raw 32.32 number is accepted in human readable formats too
+struct TimestampVisitor;
+
+impl<'de> Visitor<'de> for TimestampVisitor {
+    type Value = Timestamp;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "an rfc 3339 date or a 64 bit ntp timestamp")
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Timestamp, E> {
+        Ok(Timestamp(value))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Timestamp, E> {
+        u64::try_from(value).map(Timestamp).map_err(|_| E::invalid_value(de::Unexpected::Signed(value), &self))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Timestamp, E> {
+        parse_timestamp(value).ok_or_else(|| E::invalid_value(de::Unexpected::Str(value), &self))
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Timestamp, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(TimestampVisitor)
+        } else {
+            deserializer.deserialize_u64(TimestampVisitor)
+        }
+    }
+}
+
+//seconds, an f64 holds every 16.16 value exactly
+impl Serialize for Short {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_f64(f64::from(self.0) / 65536.0)
+        } else {
+            serializer.serialize_u32(self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Short {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Short, D::Error> {
+        if !deserializer.is_human_readable() {
+            return u32::deserialize(deserializer).map(Short);
+        }
+        let seconds = f64::deserialize(deserializer)?;
+        let fixed = (seconds * 65536.0).round();
+        if !(0.0..=f64::from(u32::MAX)).contains(&fixed) {
+            return Err(de::Error::invalid_value(de::Unexpected::Float(seconds), &"seconds from 0 to 65536"));
+        }
+        Ok(Short(fixed as u32))
+    }
+}
+
+impl Serialize for Stratum {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let stratum: u8 = (*self).try_into().map_err(serde::ser::Error::custom)?;
+        serializer.serialize_u8(stratum)
+    }
+}
+
+impl<'de> Deserialize<'de> for Stratum {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Stratum, D::Error> {
+        Stratum::try_from(u8::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+//extension field values, digests and the like
+pub mod hex_bytes {
+    use serde::{Serializer,Deserializer,Deserialize};
+    use serde::de;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&hex::encode(bytes))
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            hex::decode(String::deserialize(deserializer)?).map_err(de::Error::custom)
+        } else {
+            bytes(deserializer)
+        }
+    }
+
+    fn bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        struct BytesVisitor;
+
+        impl<'de> de::Visitor<'de> for BytesVisitor {
+            type Value = Vec<u8>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "bytes")
+            }
+
+            fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<Vec<u8>, E> {
+                Ok(value.to_vec())
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, value: Vec<u8>) -> Result<Vec<u8>, E> {
+                Ok(value)
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+                let mut bytes = Vec::new();
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                Ok(bytes)
+            }
+        }
+
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+}
+
+//kiss codes and stratum 1 sources as text ("RATE", "GPS"), anything else as an ipv4 address
+//which is what they are for most servers from stratum 2 on, ipv6 hashes just look odd
+pub mod reference_id {
+    use super::*;
+
+    fn is_text(id: &[u8; 4]) -> bool {
+        let length = id.iter().position(|b| *b == 0).unwrap_or(4);
+        length > 0 && id[..length].iter().all(|b| b.is_ascii_alphanumeric() || *b == b' ' || *b == b'-')
+            && id[length..].iter().all(|b| *b == 0)
+    }
+
+    pub fn serialize<S: Serializer>(id: &[u8; 4], serializer: S) -> Result<S::Ok, S::Error> {
+        if !serializer.is_human_readable() {
+            id.serialize(serializer)
+        } else if is_text(id) {
+            serializer.serialize_str(std::str::from_utf8(id).expect("ascii").trim_end_matches('\0'))
+        } else {
+            serializer.serialize_str(&Ipv4Addr::from(*id).to_string())
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 4], D::Error> {
+        if !deserializer.is_human_readable() {
+            return <[u8; 4]>::deserialize(deserializer);
+        }
+        let value = String::deserialize(deserializer)?;
+        if let Ok(address) = value.parse::<Ipv4Addr>() {
+            return Ok(address.octets());
+        }
+        if value.len() > 4 || !value.is_ascii() {
+            return Err(de::Error::invalid_value(de::Unexpected::Str(&value), &"up to 4 ascii characters or an ipv4 address"));
+        }
+        let mut id = [0; 4];
+        id[..value.len()].copy_from_slice(value.as_bytes());
+        Ok(id)
+    }
+}
+
+//the text form timestamps are serialized as, exact down to the 2^-32 unlike into_utc_datetime
+impl Timestamp {
+    pub fn to_rfc3339(self) -> String {
+        format_timestamp(self)
+    }
+
+    pub fn parse_rfc3339(value: &str) -> Option<Timestamp> {
+        parse_timestamp(value)
+    }
+}
@@ -0,0 +1,83 @@
+use crate::ntp::types::*;
+use super::client_request;
+use crate::ntp::parser::*;
+use crate::ntp::auth::*;
+
+static KEYS: &str = "
+# md5, ascii
+1 M     plainpassword
+2 SHA1  0123456789abcdef0123456789abcdef01234567    # hex, longer than 20 characters
+3 AES128CMAC 2b7e151628aed2a6abf7158809cf4f3c
+";
+
+#[test]
+fn key_file() {
+    let keys = Keys::parse(KEYS).unwrap();
+
+    assert_eq!(keys.get(1).unwrap().algorithm, MacAlgorithm::Md5);
+    assert_eq!(keys.get(1).unwrap().secret, b"plainpassword");
+    assert_eq!(keys.get(2).unwrap().algorithm, MacAlgorithm::Sha1);
+    assert_eq!(keys.get(2).unwrap().secret.len(), 20);
+    assert_eq!(keys.get(3).unwrap().algorithm, MacAlgorithm::AesCmac);
+    assert!(keys.get(4).is_none());
+
+    assert!(Keys::parse("0 M password").is_err());
+    assert!(Keys::parse("1 SHA256 password").is_err());
+    assert!(Keys::parse("1 M").is_err());
+    assert!(Keys::parse("1 AES128CMAC shortkey").is_err());
+    assert!(Keys::parse("1 SHA1 0123456789abcdef0123456789abcdef0123456z").is_err());
+}
+
+//rfc 4493 test vectors
+#[test]
+fn aes_cmac() {
+    let key = Keys::parse(KEYS).unwrap().get(3).unwrap().clone();
+
+    assert_eq!(hex::encode(key.mac(b"")), "bb1d6929e95937287fa37d129b756746");
+    assert_eq!(hex::encode(key.mac(&hex::decode("6bc1bee22e409f96e93d7e117393172a").unwrap())),
+               "070a16b46b4d4144f79bdd9dd04a287c");
+}
+
+#[test]
+fn sign_and_verify() {
+    let keys = Keys::parse(KEYS).unwrap();
+
+    for (id, size) in [(1, 68), (2, 72), (3, 68)] {
+        let mut signed = client_request();
+        sign(&mut signed, keys.get(id).unwrap()).unwrap();
+
+        let data = serialize_packet(&signed).unwrap();
+        assert_eq!(data.len(), size);
+        assert_eq!(signed.size(), size);
+
+        let parsed = parse_packet(&data).unwrap().1.unwrap();
+        assert_eq!(parsed.auth, signed.auth);
+        assert_eq!(verify(&parsed, &keys), Ok(id));
+
+        let mut tampered = parsed.clone();
+        tampered.transit_timestamp = Timestamp(tampered.transit_timestamp.0 + 1);
+        assert_eq!(verify(&tampered, &keys), Err(AuthError::BadDigest(id)));
+    }
+}
+
+#[test]
+fn verify_errors() {
+    let keys = Keys::parse(KEYS).unwrap();
+
+    assert_eq!(verify(&client_request(), &keys), Err(AuthError::Missing));
+    assert_eq!(verify(&Packet { auth: Some(crypto_nak()), ..client_request() }, &keys), Err(AuthError::CryptoNak));
+    assert_eq!(verify(&Packet { auth: Some(Auth { key_indentifier: 7, digest: vec![0; 16] }), ..client_request() }, &keys),
+               Err(AuthError::UnknownKey(7)));
+    assert_eq!(verify(&Packet { auth: Some(Auth { key_indentifier: 2, digest: vec![0; 16] }), ..client_request() }, &keys),
+               Err(AuthError::WrongLength(2)));
+}
+
+#[test]
+fn crypto_nak_roundtrip() {
+    let nak = Packet { auth: Some(crypto_nak()), ..client_request() };
+    let data = serialize_packet(&nak).unwrap();
+    assert_eq!(data.len(), Packet::BASE_SIZE + Packet::CRYPTO_NAK_SIZE);
+
+    let parsed = parse_packet(&data).unwrap().1.unwrap();
+    assert_eq!(parsed.auth, Some(crypto_nak()));
+}
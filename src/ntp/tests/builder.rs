@@ -0,0 +1,74 @@
+use crate::ntp::types::*;
+use crate::ntp::builder::*;
+use crate::ntp::constants::{KoD,ExtensionFieldType};
+use crate::ntp::parser::ExtensionError;
+use crate::ntp::Error;
+
+fn violations(packet: Packet) -> Vec<Violation> {
+    packet.validate().unwrap_err().0
+}
+
+#[test]
+fn defaults() {
+    let request = PacketBuilder::client().build().unwrap();
+    assert_eq!((request.version, request.mode, request.stratum), (4, Mode::Client, Stratum::Unsynchronized));
+    assert_ne!(request.transit_timestamp, Timestamp(0));
+    assert!(PacketBuilder::client().version(1).mode(Mode::Reserved).build().is_ok());
+
+    let now = request.transit_timestamp + TimeDelta(1 << 32);
+    let response = PacketBuilder::response_to(&request).timestamps(now).build().unwrap();
+    assert_eq!((response.mode, response.origin_timestamp, response.transit_timestamp), (Mode::Server, request.transit_timestamp, now));
+
+    let v3 = PacketBuilder::client().version(3).build().unwrap();
+    let kod = PacketBuilder::kiss_of_death(&v3, KoD::RATE).build().unwrap();
+    assert_eq!((kod.version, kod.stratum, kod.reference_id), (3, Stratum::Unspecified, KoD::RATE));
+    assert_eq!(kod.receive_timestamp, v3.transit_timestamp);
+
+    let nts = PacketBuilder::client().extension(Extension::UniqueIdentifier(vec![1; 32])).build().unwrap();
+    assert_eq!(nts.extensions.unwrap()[0].value.len(), 32);
+
+    //a nonce length that doesn't fit in 16 bits, unchecked packets get it cut off
+    let authenticator = Extension::NtsAuthenticator { nonce: vec![0; 0x10000], ciphertext: vec![] };
+    assert_eq!(PacketBuilder::client().extension(authenticator.clone()).build().unwrap_err().0,
+               vec![Violation::Encoding(Error::ExtensionTooLong { field_type: ExtensionFieldType::NTP_AUTHENTICATOR, length: 0x10000 })]);
+    let truncated = PacketBuilder::client().extension(authenticator).build_unchecked();
+    assert_eq!(truncated.extensions.unwrap()[0].value[..4], [0, 0, 0, 0]);
+}
+
+#[test]
+fn rfc_violations() {
+    let now = Timestamp(0xe38c4fd4_00000000);
+    let response = || PacketBuilder::server().timestamps(now);
+
+    assert_eq!(PacketBuilder::server().build().unwrap_err().0, vec![Violation::ZeroTransmitTimestamp]);
+    assert_eq!(PacketBuilder::client().version(2).mode(Mode::Reserved).build().unwrap_err().0, vec![Violation::ReservedMode]);
+    assert_eq!(violations(response().version(0).stratum(Stratum::Reserved(20)).build_unchecked()),
+               vec![Violation::UnsupportedVersion(0), Violation::ReservedStratum(20)]);
+
+    //a kiss-o'-death that isn't one and a synchronized server that says it isn't
+    assert_eq!(violations(response().stratum(Stratum::Unspecified).reference_id([10, 0, 0, 1]).build_unchecked()), vec![
+        Violation::KissCodeNotAscii([10, 0, 0, 1]),
+        Violation::UnsynchronizedLeap { leap_indicator: LeapIndicator::NoWarning, stratum: Stratum::Unspecified },
+    ]);
+    assert!(response().stratum(Stratum::Unsynchronized).leap_indicator(LeapIndicator::Unknown).build().is_ok());
+
+    //the end of the era is still before the start of the next one
+    assert_eq!(violations(response().receive_timestamp(now + TimeDelta(1)).build_unchecked()), vec![Violation::ReceiveAfterTransmit]);
+    assert!(response().reference_timestamp(Timestamp(u64::MAX)).transit_timestamp(Timestamp(1)).receive_timestamp(Timestamp(0)).build().is_ok());
+    assert_eq!(violations(response().reference_timestamp(Timestamp(u64::MAX)).build_unchecked()), vec![Violation::ReferenceAfterTransmit]);
+    assert_eq!(violations(response().root_dispersion(Short(17 << 16)).build_unchecked()), vec![Violation::RootDispersionTooLarge(Short(17 << 16))]);
+
+    let field = ExtensionField { field_type: 0x4242, value: vec![0; 12] };
+    assert_eq!(violations(response().version(3).extension(Extension::Unknown(field.clone())).build_unchecked()), vec![
+        Violation::ExtensionsBeforeV4,
+        Violation::Encoding(Error::Extension(ExtensionError::LastTooShort { offset: 48, length: 16 })),
+    ]);
+    assert!(response().extension(Extension::Unknown(field)).auth(Auth { key_indentifier: 1, digest: vec![0; 16] }).build().is_ok());
+    assert_eq!(violations(response().auth(Auth { key_indentifier: 1, digest: vec![0; 8] }).build_unchecked()), vec![
+        Violation::DigestLength(8),
+        Violation::Encoding(Error::Extension(ExtensionError::Trailing { offset: 48, length: 12 })),
+    ]);
+
+    let err = response().version(7).build().unwrap_err();
+    assert_eq!(err.to_string(), "unsupported version 7");
+}
@@ -1,6 +1,26 @@
+use crate::ntp::types::{Packet,Timestamp,TimestampTrait};
+use crate::ntp::builder::PacketBuilder;
+
 #[cfg(test)]
 pub mod types;
 
 #[cfg(test)]
 pub mod parser;
 
+
+#[cfg(test)]
+pub mod auth;
+
+#[cfg(test)]
+pub mod nts;
+
+#[cfg(test)]
+pub mod serde_impls;
+
+#[cfg(test)]
+pub mod builder;
+
+//the request most tests send, with a fixed transmit timestamp to check responses against
+pub fn client_request() -> Packet {
+    PacketBuilder::client().transit_timestamp(Timestamp::from(0).set_seconds(0xe38c4fd4).set_fraction(0xd7472dcd)).build_unchecked()
+}
@@ -0,0 +1,111 @@
+use crate::ntp::types::*;
+use super::client_request;
+use crate::ntp::parser::*;
+use crate::ntp::constants::ExtensionFieldType;
+use crate::ntp::aes_siv::AesSivCmac256;
+use crate::ntp::nts::*;
+
+fn key(hex_key: &str) -> [u8;32] {
+    let mut key = [0; 32];
+    key.copy_from_slice(&hex::decode(hex_key).unwrap());
+    key
+}
+
+//rfc 5297 appendix a
+#[test]
+fn aes_siv_test_vectors() {
+    let siv = AesSivCmac256::new(&key("fffefdfcfbfaf9f8f7f6f5f4f3f2f1f0f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff"));
+    let ad = hex::decode("101112131415161718191a1b1c1d1e1f2021222324252627").unwrap();
+    let plaintext = hex::decode("112233445566778899aabbccddee").unwrap();
+    let output = siv.encrypt(&[&ad], &plaintext);
+    assert_eq!(hex::encode(&output), "85632d07c6e8f37f950acd320a2ecc9340c02b9690c4dc04daef7f6afe5c");
+    assert_eq!(siv.decrypt(&[&ad], &output).unwrap(), plaintext);
+
+    let siv = AesSivCmac256::new(&key("7f7e7d7c7b7a79787776757473727170404142434445464748494a4b4c4d4e4f"));
+    let ad1 = hex::decode("00112233445566778899aabbccddeeffdeaddadadeaddadaffeeddccbbaa99887766554433221100").unwrap();
+    let ad2 = hex::decode("102030405060708090a0").unwrap();
+    let nonce = hex::decode("09f911029d74e35bd84156c5635688c0").unwrap();
+    let plaintext = hex::decode("7468697320697320736f6d6520706c61696e7465787420746f20656e6372797074207573696e67205349562d414553").unwrap();
+    let output = siv.encrypt(&[&ad1, &ad2, &nonce], &plaintext);
+    assert_eq!(hex::encode(&output), "7bdb6e3b432667eb06f4d14bff2fbd0fcb900f2fddbe404326601965c889bf17dba77ceb094fa663b7a3f748ba8af829ea64ad544a272e9c485b62a3fd5c0d");
+    assert_eq!(siv.decrypt(&[&ad1, &ad2, &nonce], &output).unwrap(), plaintext);
+
+    let mut tampered = output.clone();
+    tampered[20] ^= 1;
+    assert!(siv.decrypt(&[&ad1, &ad2, &nonce], &tampered).is_none());
+    assert!(siv.decrypt(&[&ad2, &ad1, &nonce], &output).is_none());
+}
+
+#[test]
+fn ke_records() {
+    let records = vec![
+        Record::from_u16s(true, RecordType::NEXT_PROTOCOL, &[PROTOCOL_NTPV4]),
+        Record::from_u16s(false, RecordType::AEAD_ALGORITHM, &[AEAD_AES_SIV_CMAC_256, 16]),
+        Record::new(false, RecordType::NEW_COOKIE, vec![1, 2, 3]),
+    ];
+    let mut data = serialize_message(&records).unwrap();
+    assert_eq!(&data[..6], &[0x80, 0x01, 0x00, 0x02, 0x00, 0x00]);
+    assert!(parse_message(&data).is_none());
+
+    Record::end_of_message().serialize(&mut data).unwrap();
+    let parsed = parse_message(&data).unwrap();
+    assert_eq!(parsed, records);
+    assert_eq!(parsed[1].u16s(), vec![AEAD_AES_SIV_CMAC_256, 16]);
+
+    assert!(parse_message(&data[..data.len()-1]).is_none());
+}
+
+#[test]
+fn cookies() {
+    let cookie_key = CookieKey::random(&mut rand::thread_rng());
+    let keys = SessionKeys { c2s: [1; 32], s2c: [2; 32] };
+
+    let cookie = cookie_key.seal(&keys, &mut rand::thread_rng());
+    assert_eq!(cookie_key.open(&cookie), Some(keys.clone()));
+    assert_ne!(cookie, cookie_key.seal(&keys, &mut rand::thread_rng()));
+
+    let mut tampered = cookie.clone();
+    tampered[40] ^= 1;
+    assert!(cookie_key.open(&tampered).is_none());
+    assert!(CookieKey::random(&mut rand::thread_rng()).open(&cookie).is_none());
+}
+
+#[test]
+fn request_and_response() {
+    let mut rng = rand::thread_rng();
+    let cookie_key = CookieKey::random(&mut rng);
+    let keys = SessionKeys { c2s: [3; 32], s2c: [4; 32] };
+
+    let mut packet = Packet {
+        extensions: Some(vec![
+            unique_id(vec![7; 32]),
+            cookie(cookie_key.seal(&keys, &mut rng)),
+            ExtensionField { field_type: ExtensionFieldType::NTS_COOKIE_PLACEHOLDER, value: vec![0; 96] },
+        ]),
+        ..client_request()
+    };
+    let ad = associated_data(&packet, usize::MAX).unwrap();
+    packet.extensions.as_mut().unwrap().push(authenticator(&keys.c2s, &ad, &[9; 16], &[]).unwrap());
+
+    //survives the trip over the wire
+    let packet = parse_packet(&serialize_packet(&packet).unwrap()).unwrap().1.unwrap();
+    let opened = open_request(&packet, &cookie_key).unwrap();
+    assert_eq!(opened.unique_id, Some(vec![7; 32]));
+    assert_eq!(opened.keys, keys);
+    assert_eq!(opened.cookies_wanted, 2);
+
+    let mut response = client_request();
+    protect_response(&mut response, &opened, &cookie_key, &mut rng).unwrap();
+    let response = parse_packet(&serialize_packet(&response).unwrap()).unwrap().1.unwrap();
+    let extensions = response.extensions.as_ref().unwrap();
+    assert_eq!(extensions[0], unique_id(vec![7; 32]));
+    let cookies = open_authenticator(&keys.s2c, &associated_data(&response, 1).unwrap(), &extensions[1]).unwrap();
+    assert_eq!(cookies.len(), 2);
+    assert_eq!(cookie_key.open(&cookies[0].value), Some(keys.clone()));
+
+    let mut tampered = packet.clone();
+    tampered.transit_timestamp = Timestamp(1);
+    assert_eq!(open_request(&tampered, &cookie_key).err(), Some(NtsError::BadAuthenticator));
+    assert_eq!(open_request(&packet, &CookieKey::random(&mut rng)).err(), Some(NtsError::BadCookie));
+    assert_eq!(open_request(&client_request(), &cookie_key).err(), Some(NtsError::NoCookie));
+}
@@ -1,6 +1,7 @@
 use crate::ntp::types::*;
 use crate::ntp::parser::*;
 use crate::ntp::constants::*;
+use crate::ntp::Error;
 use std::convert::{TryInto,TryFrom};
 
 //TODO: timestamp parsing/formatting
@@ -10,7 +11,7 @@ use std::convert::{TryInto,TryFrom};
 
 #[test]
 fn valid_server_packet() {
-    static PACKET: &'static [u8] = &[
+    static PACKET: &[u8] = &[
         0x24,                                           //no leap warning, ntpv4, server
         0x02,                                           //stratum 2
         0x03,                                           //poll interval 3 (invalid?)
@@ -41,9 +42,9 @@ fn valid_server_packet() {
     assert_eq!(parsed.transit_timestamp, Timestamp::from(0).set_seconds(0xe38c4fd4).set_fraction(0xe9b1d845)); 
 
     assert_eq!(parsed.reference_timestamp.into_utc_datetime().to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
-        "2020-12-22T10:54:41.247108268Z");
+        "2020-12-22T10:54:41.247108269Z");
     assert_eq!(parsed.origin_timestamp.into_utc_datetime().to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
-        "2020-12-22T10:58:28.840929853Z");
+        "2020-12-22T10:58:28.840929854Z");
     assert_eq!(parsed.receive_timestamp.into_utc_datetime().to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
         "2020-12-22T10:58:28.912855987Z");
     assert_eq!(parsed.transit_timestamp.into_utc_datetime().to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
@@ -63,7 +64,7 @@ fn valid_server_packet() {
 
 #[test]
 fn valid_client_packet() {
-    static PACKET: &'static [u8] = &[
+    static PACKET: &[u8] = &[
         0xe3,                                           //unknown leap, ntpv4, client
         0x00,                                           //stratum unspecified
         0x03,                                           //poll interval 3 (interval?)
@@ -101,7 +102,7 @@ fn valid_client_packet() {
 //TODO: padding?
 #[test]
 fn valid_client_packet_with_extensions() {
-    static PACKET: &'static [u8] = &[
+    static PACKET: &[u8] = &[
         0xe3,                                           //unknown leap, ntpv4, client
         0x00,                                           //stratum unspecified
         0x03,                                           //poll interval 3 (interval?)
@@ -113,7 +114,8 @@ fn valid_client_packet_with_extensions() {
         0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //origin timestamp
         0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //receive timestamp
         0xe3, 0x8c, 0x4f, 0xd4, 0xd7, 0x47, 0x2d, 0xcd, //transit timestamp (Dec 22, 2020 10:58:28.840929853 UTC)
-        0x00, 0x20, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, //noop extension field
+        0x00, 0x20, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, //noop extension field, length includes the header
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //padded to the 16 byte minimum
         0x00, 0x00, 0x00, 0x00,                         //auth key_id
         0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
         0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //auth digest
@@ -135,8 +137,8 @@ fn valid_client_packet_with_extensions() {
     assert_eq!(parsed.receive_timestamp, Timestamp::from(0)); 
     assert_eq!(parsed.transit_timestamp, Timestamp::from(0).set_seconds(0xe38c4fd4).set_fraction(0xd7472dcd)); 
     assert_eq!(parsed.extensions.clone().unwrap()[0].field_type, ExtensionFieldType::NOOP);
-    assert_eq!(parsed.auth.unwrap().key_indentifier, 0);
-    assert_eq!(parsed.auth.unwrap().digest, 0);
+    assert_eq!(parsed.auth.as_ref().unwrap().key_indentifier, 0);
+    assert_eq!(parsed.auth.as_ref().unwrap().digest, vec![0; 16]);
 
     assert_eq!(serialize_packet(&parsed).unwrap(), PACKET);
 
@@ -146,7 +148,7 @@ fn valid_client_packet_with_extensions() {
 //packet with extension field with size zero
 #[test]
 fn invalid_packet_ext_field_length_zero() {
-    static PACKET: &'static [u8] = &[
+    static PACKET: &[u8] = &[
         23, 0, 3, 42, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
         0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
         0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
@@ -157,17 +159,14 @@ fn invalid_packet_ext_field_length_zero() {
         0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
     ];
 
-    let parsed = parse_packet(PACKET);
-
-    match parsed.err().unwrap() {
-        nom::Err::Error(err) => assert_eq!(err.code, nom::error::ErrorKind::Verify),
-        _ => assert!(false),
-    }
+    assert!(parse_packet(PACKET).unwrap().1.is_err());
+    assert_eq!(parse_extensions_and_mac(&PACKET[Packet::BASE_SIZE..]),
+               Err(ExtensionError::TooShort { offset: 48, length: 0 }));
 }
 
 #[test]
 fn invalid_packet_auth_size() {
-    static PACKET: &'static [u8] = &[
+    static PACKET: &[u8] = &[
         0xe3,                                           //unknown leap, ntpv4, client
         0x00,                                           //stratum unspecified
         0x03,                                           //poll interval 3 (interval?)
@@ -179,19 +178,17 @@ fn invalid_packet_auth_size() {
         0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //origin timestamp
         0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //receive timestamp
         0xe3, 0x8c, 0x4f, 0xd4, 0xd7, 0x47, 0x2d, 0xcd, //transit timestamp (Dec 22, 2020 10:58:28.840929853 UTC)
-        0x00, 0x20, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, //noop extension field
+        0x00, 0x20, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, //noop extension field, length includes the header
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //padded to the 16 byte minimum
         0x00, 0x00, 0x00, 0x00,                         //auth key_id
         0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
         0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,       //auth digest
     ];
 
-    let parsed = parse_packet(PACKET);
-
-    match parsed.err().unwrap() {
-        //its a bit parser so 8 (8 bits -> 1 byte)
-        nom::Err::Incomplete(err) => assert_eq!(err, nom::Needed::new(8)),
-        _ => assert!(false),
-    }
+    assert!(parse_packet(PACKET).unwrap().1.is_err());
+    //19 bytes can't be a mac, so they're read as an extension field with the key id as its header
+    assert_eq!(parse_extensions_and_mac(&PACKET[Packet::BASE_SIZE..]),
+               Err(ExtensionError::TooShort { offset: 64, length: 0 }));
 }
 
 #[test]
@@ -213,3 +210,126 @@ fn stratum_values() {
     assert_eq!(Stratum::try_from(17).unwrap(), Stratum::Reserved(17));
 }
 
+
+//what follows the header, fields are given by their length on the wire and filled with their index
+fn layout(fields: &[usize], mac: usize) -> Vec<u8> {
+    let mut data = Vec::new();
+    for (n, length) in fields.iter().enumerate() {
+        data.extend_from_slice(&[0x42, n as u8, 0, *length as u8]);
+        data.resize(data.len() + length - 4, n as u8);
+    }
+    data.extend_from_slice(&vec![0xaa; mac]);
+    data
+}
+
+#[test]
+fn extension_layouts() {
+    let sizes = |fields: &[usize], mac: usize| parse_extensions_and_mac(&layout(fields, mac)).map(|(extensions, auth)| (
+        extensions.map(|e| e.iter().map(|e| e.value.len() + 4).collect::<Vec<_>>()),
+        auth.map(|a| a.digest.len()),
+    ));
+
+    assert_eq!(sizes(&[], 0), Ok((None, None)));
+    assert_eq!(sizes(&[], 4), Ok((None, Some(0))));
+    assert_eq!(sizes(&[], 20), Ok((None, Some(16))));
+    assert_eq!(sizes(&[], 24), Ok((None, Some(20))));
+    assert_eq!(sizes(&[28], 0), Ok((Some(vec![28]), None)));
+    assert_eq!(sizes(&[28], 4), Ok((Some(vec![28]), Some(0))));
+    assert_eq!(sizes(&[16], 20), Ok((Some(vec![16]), Some(16))));
+    assert_eq!(sizes(&[16], 24), Ok((Some(vec![16]), Some(20))));
+    assert_eq!(sizes(&[16, 20, 28], 0), Ok((Some(vec![16, 20, 28]), None)));
+    assert_eq!(sizes(&[16, 16], 24), Ok((Some(vec![16, 16]), Some(20))));
+    //20 or 24 bytes at the end are always a mac, even if they were meant as a field or a field and a crypto-nak
+    assert_eq!(sizes(&[16, 24], 0), Ok((Some(vec![16]), Some(20))));
+    assert_eq!(sizes(&[16], 4), Ok((None, Some(16))));
+
+    assert_eq!(sizes(&[16], 0), Err(ExtensionError::LastTooShort { offset: 48, length: 16 }));
+    assert_eq!(sizes(&[12, 28], 0), Err(ExtensionError::TooShort { offset: 48, length: 12 }));
+    assert_eq!(sizes(&[18, 28], 0), Err(ExtensionError::Unaligned { offset: 48, length: 18 }));
+    assert_eq!(sizes(&[28], 8), Err(ExtensionError::Trailing { offset: 76, length: 8 }));
+    assert_eq!(sizes(&[16], 21), Err(ExtensionError::Unaligned { offset: 64, length: 0xaaaa }));
+    assert_eq!(sizes(&[], 13), Err(ExtensionError::Trailing { offset: 48, length: 13 }));
+
+    let mut truncated = layout(&[16, 40], 0);
+    truncated.truncate(16 + 32);
+    assert_eq!(parse_extensions_and_mac(&truncated), Err(ExtensionError::Truncated { offset: 64, length: 40 }));
+}
+
+#[test]
+fn packet_api_errors() {
+    let mut data = layout(&[], 0);
+    data.resize(Packet::BASE_SIZE, 0);
+    data[0] = 0x23;     //ntpv4 client
+    let packet = Packet::parse(&data).unwrap();
+    assert_eq!(packet.to_bytes().unwrap(), data);
+
+    assert_eq!(Packet::parse(&data[..20]).unwrap_err(), Error::Truncated(20));
+    data.extend_from_slice(&layout(&[28], 8));
+    assert_eq!(Packet::parse(&data).unwrap_err(), Error::Extension(ExtensionError::Trailing { offset: 76, length: 8 }));
+    //the nom wrapper only fails on an incomplete header, the rest ends up in its result
+    assert!(parse_packet(&data[..20]).is_err());
+    let (rest, result) = parse_packet(&data).unwrap();
+    assert_eq!((rest.0.len(), result.unwrap_err()), (0, Packet::parse(&data).unwrap_err()));
+
+    assert_eq!(Packet { stratum: Stratum::SecondaryServer(20), ..packet.clone() }.to_bytes(),
+               Err(Error::InvalidStratum(20)));
+    let huge = ExtensionField { field_type: 0x4242, value: vec![0; 65533] };
+    assert_eq!(Packet { extensions: Some(vec![huge]), ..packet }.to_bytes(),
+               Err(Error::ExtensionTooLong { field_type: 0x4242, length: 65540 }));
+}
+
+#[test]
+fn packet_ref() {
+    let mut data = vec![0x23; Packet::BASE_SIZE];
+    data[1] = 2;
+    data.extend_from_slice(&layout(&[16, 32], 24));
+    let packet = PacketRef::parse(&data).unwrap();
+
+    assert_eq!((packet.version, packet.mode, packet.stratum), (4, Mode::Client, Stratum::SecondaryServer(2)));
+    assert_eq!(packet.transit_timestamp, Timestamp(0x23232323_23232323));
+    let extensions = packet.extensions.collect::<Vec<_>>();
+    assert_eq!(extensions.iter().map(|e| e.value.len()).collect::<Vec<_>>(), vec![12, 28]);
+    assert_eq!(extensions[1], ExtensionFieldRef { field_type: 0x4201, value: &[1; 28] });
+    assert_eq!(packet.auth, Some(AuthRef { key_indentifier: 0xaaaaaaaa, digest: &[0xaa; 20] }));
+
+    //serializes back to the same bytes
+    let owned = packet.to_packet();
+    let mut buf = [0xff; 200];
+    let size = owned.serialize_into(&mut buf).unwrap();
+    assert_eq!(&buf[..size], &data[..]);
+    assert_eq!(owned.serialize_into(&mut buf[..100]).unwrap_err(), Error::BufferTooSmall { needed: 120, available: 100 });
+
+    assert_eq!(PacketRef::parse(&data[..47]).unwrap_err(), Error::Truncated(47));
+    assert!(PacketRef::parse(&data[..60]).is_err());
+}
+
+#[test]
+fn parse_modes() {
+    let mut data = vec![0; Packet::BASE_SIZE];
+    data[0] = 0x23;
+    let parse = |data: &[u8], mode| Packet::parse_with(data, mode).map(|(packet, anomalies)| (packet.version, anomalies));
+
+    assert_eq!(parse(&data, ParseMode::Strict), Ok((4, vec![])));
+    assert_eq!(parse(&data[..20], ParseMode::Strict), Err(Error::Truncated(20)));
+    assert_eq!(parse(&data[..20], ParseMode::Lenient), Ok((4, vec![Anomaly::Short(20)])));
+    assert_eq!(parse(&[], ParseMode::Raw), Ok((0, vec![Anomaly::Short(0), Anomaly::UnknownVersion(0)])));
+
+    data[0] = 0x2b;     //version 5
+    assert_eq!(parse(&data, ParseMode::Strict), Err(Error::UnsupportedVersion(5)));
+    assert_eq!(parse(&data, ParseMode::Lenient), Ok((5, vec![Anomaly::UnknownVersion(5)])));
+
+    //raw keeps the fields that made it before the trailing bytes
+    data[0] = 0x23;
+    data.extend_from_slice(&layout(&[28], 8));
+    let trailing = ExtensionError::Trailing { offset: 76, length: 8 };
+    assert_eq!(parse(&data, ParseMode::Lenient), Err(Error::Extension(trailing)));
+    let (packet, anomalies) = Packet::parse_with(&data, ParseMode::Raw).unwrap();
+    assert_eq!(anomalies, vec![Anomaly::Extension(trailing)]);
+    assert_eq!(packet.extensions.unwrap().len(), 1);
+    assert_eq!(packet.auth, None);
+
+    //the borrowed packet still points at the trailing bytes, to_packet drops them the same way
+    let (packet, _) = PacketRef::parse_with(&data, ParseMode::Raw).unwrap();
+    assert_eq!(packet.extensions.count(), 1);
+    assert_eq!(packet.header().extensions, None);
+}
@@ -0,0 +1,105 @@
+use crate::ntp::types::*;
+use crate::ntp::constants::KoD;
+use crate::ntp::builder::PacketBuilder;
+use super::client_request;
+
+fn kod() -> Packet {
+    PacketBuilder::kiss_of_death(&client_request(), KoD::RATE)
+        .poll(6)
+        .root_delay(Short(0x1_8000))
+        .root_dispersion(Short(1))
+        .transit_timestamp(Timestamp(u64::MAX))
+        .extension(Extension::UniqueIdentifier(vec![0xab; 32]))
+        .build_unchecked()
+}
+
+#[test]
+fn json() {
+    let json = serde_json::to_value(kod()).unwrap();
+    assert_eq!(json["leap_indicator"], "unknown");
+    assert_eq!(json["mode"], "server");
+    assert_eq!(json["stratum"], 0);
+    assert_eq!(json["root_delay"], 1.5);
+    assert_eq!(json["reference_id"], "RATE");
+    assert_eq!(json["reference_timestamp"], "1900-01-01T00:00:00.0000000000Z");
+    assert_eq!(json["origin_timestamp"], "2020-12-22T10:58:28.8409298540Z");
+    assert_eq!(json["extensions"][0]["value"], "ab".repeat(32));
+
+    //down to the last fraction bit, the end of the era included
+    let packet: Packet = serde_json::from_value(json).unwrap();
+    assert_eq!(packet.to_bytes(), kod().to_bytes());
+    assert_eq!(packet.transit_timestamp, Timestamp(u64::MAX));
+
+    let mut packet = kod();
+    packet.stratum = Stratum::SecondaryServer(20);
+    assert!(serde_json::to_string(&packet).is_err());
+}
+
+//what a scripted response in the config or a hand written fixture looks like
+#[test]
+fn hand_written() {
+    let packet: Packet = toml::from_str(r#"
+        leap_indicator = "no_warning"
+        version = 3
+        mode = "server"
+        stratum = 2
+        poll = 4
+        precision = -18
+        root_delay = 0.25
+        root_dispersion = 0
+        reference_id = "192.168.1.1"
+        reference_timestamp = "2020-12-22T11:58:00+01:00"
+        origin_timestamp = 0
+        receive_timestamp = "2020-12-22T10:58:28.5Z"
+        transit_timestamp = "2020-12-22T10:58:28.840929853963Z"
+        auth = { key_indentifier = 1, digest = "00112233445566778899aabbccddeeff" }
+    "#).unwrap();
+
+    assert_eq!(packet.stratum, Stratum::SecondaryServer(2));
+    assert_eq!(packet.root_delay, Short(0x4000));
+    assert_eq!(packet.reference_id, [192, 168, 1, 1]);
+    assert_eq!(packet.reference_timestamp, Timestamp(0xe38c4fb8_00000000));
+    assert_eq!(packet.receive_timestamp, Timestamp(0xe38c4fd4_80000000));
+    assert_eq!(packet.transit_timestamp, Timestamp(0xe38c4fd4_d7472dcd));
+    assert_eq!(packet.auth.unwrap().digest[15], 0xff);
+    assert!(packet.extensions.is_none());
+
+    assert!(toml::from_str::<Auth>(r#"key_indentifier = 1
+        digest = "not hex""#).is_err());
+}
+
+#[test]
+fn reference_ids() {
+    let id = |reference_id| serde_json::to_value(Packet { reference_id, ..kod() }).unwrap()["reference_id"].clone();
+    assert_eq!(id(*b"GPS\0"), "GPS");
+    assert_eq!(id([127, 0, 0, 1]), "127.0.0.1");
+    assert_eq!(id(*b"G\0S\0"), "71.0.83.0");
+
+    let parse = |value: &str| {
+        let mut json = serde_json::to_value(kod()).unwrap();
+        json["reference_id"] = value.into();
+        serde_json::from_value::<Packet>(json).map(|packet| packet.reference_id)
+    };
+    assert_eq!(parse("DENY").unwrap(), KoD::DENY);
+    assert_eq!(parse("PPS").unwrap(), *b"PPS\0");
+    assert_eq!(parse("10.0.0.1").unwrap(), [10, 0, 0, 1]);
+    assert!(parse("TOO LONG").is_err());
+}
+
+#[test]
+fn extensions() {
+    let extension = Extension::NtsAuthenticator { nonce: vec![1; 16], ciphertext: vec![2; 16] };
+    let json = serde_json::to_value(&extension).unwrap();
+    assert_eq!(json["nts_authenticator"]["nonce"], "01".repeat(16));
+    assert_eq!(serde_json::from_value::<Extension>(json).unwrap(), extension);
+    assert_eq!(serde_json::to_value(Extension::ChecksumComplement(7)).unwrap(), serde_json::json!({ "checksum_complement": 7 }));
+}
+
+proptest::proptest! {
+    #[test]
+    fn timestamp_text_round_trip(timestamp in proptest::prelude::any::<u64>()) {
+        let text = Timestamp(timestamp).to_rfc3339();
+        proptest::prop_assert_eq!(Timestamp::parse_rfc3339(&text), Some(Timestamp(timestamp)));
+        proptest::prop_assert_eq!(serde_json::from_str::<Timestamp>(&format!("\"{}\"", text)).unwrap(), Timestamp(timestamp));
+    }
+}
@@ -1,4 +1,7 @@
-use crate::ntp::types::{Timestamp,Short,TimestampTrait};
+use std::convert::TryFrom;
+use crate::ntp::types::*;
+use super::client_request;
+use crate::ntp::constants::ExtensionFieldType;
 
 #[test]
 fn timestamp() {
@@ -6,17 +9,17 @@ fn timestamp() {
 
     assert_eq!(0x12345678, timestamp.get_seconds());
     assert_eq!(0x9abcdef0, timestamp.get_fraction());
-    assert_eq!(0x731_9abcdef0 as u64, u64::from(timestamp.set_seconds(0x731)));
+    assert_eq!(0x731_9abcdef0_u64, u64::from(timestamp.set_seconds(0x731)));
     assert_eq!(0x731, timestamp.set_seconds(0x731).get_seconds());
 
     assert_eq!(0x9abcdef0, timestamp.set_seconds(0x731).get_fraction());
     assert_eq!(0x12345678, timestamp.set_fraction(0x52141).get_seconds());
-    assert_eq!(0x12345678_00052141 as u64, u64::from(timestamp.set_fraction(0x52141)));
+    assert_eq!(0x12345678_00052141_u64, u64::from(timestamp.set_fraction(0x52141)));
     assert_eq!(0x52141, timestamp.set_fraction(0x52141).get_fraction());
 
 
     assert_eq!(Timestamp::from(0).set_seconds(0xe38c4fd4).set_fraction(0xd7472dcd).into_utc_datetime().to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
-        "2020-12-22T10:58:28.840929853Z");
+        "2020-12-22T10:58:28.840929854Z");
 }
 
 //loosy - fraction_from_nanoseconds(fraction_as_nanoseconds) != fraction
@@ -37,16 +40,225 @@ fn short() {
 
     assert_eq!(0x1234, short.get_seconds());
     assert_eq!(0x5678, short.get_fraction());
-    assert_eq!(0x731_5678 as u32, u32::from(short.set_seconds(0x731)));
+    assert_eq!(0x731_5678_u32, u32::from(short.set_seconds(0x731)));
     assert_eq!(0x731, short.set_seconds(0x731).get_seconds());
 
     assert_eq!(0x5678, short.set_seconds(0x731).get_fraction());
     assert_eq!(0x1234, short.set_fraction(0x5214).get_seconds());
-    assert_eq!(0x1234_5214 as u32, u32::from(short.set_fraction(0x5214)));
+    assert_eq!(0x1234_5214_u32, u32::from(short.set_fraction(0x5214)));
     assert_eq!(0x5214, short.set_fraction(0x5214).get_fraction());
 
     assert_eq!(Short::from_duration(chrono::Duration::seconds(15)).unwrap().get_seconds(), 15);
     assert_eq!(Short::from_duration(chrono::Duration::seconds(15)).unwrap().get_fraction(), 0);
     assert_eq!(Short(0).set_seconds(15).into_duration(), chrono::Duration::seconds(15));
+    //2^-16s is 15258.79ns, rounded instead of truncated
+    assert_eq!(Short(1).into_duration(), chrono::Duration::nanoseconds(15259));
 }
 
+
+#[test]
+fn typed_extensions() {
+    let extensions = vec![
+        Extension::UniqueIdentifier(vec![1; 32]),
+        Extension::NtsCookie(vec![2; 100]),
+        Extension::NtsCookiePlaceholder(100),
+        Extension::NtsAuthenticator { nonce: vec![3; 13], ciphertext: vec![4; 18] },
+        Extension::ChecksumComplement(0xbeef),
+        Extension::Unknown(ExtensionField { field_type: 0x4242, value: vec![5; 3] }),
+    ];
+    for extension in &extensions {
+        let field = ExtensionField::try_from(extension).unwrap();
+        assert_eq!(field.field_type, extension.field_type());
+        assert_eq!(&Extension::from(&field), extension);
+    }
+
+    let authenticator = ExtensionField::try_from(&extensions[3]).unwrap();
+    assert_eq!(&authenticator.value[..4], &[0, 13, 0, 18]);
+    assert_eq!(authenticator.value.len(), 4 + 16 + 20);
+    assert_eq!(ExtensionField::try_from(&extensions[4]).unwrap().value.len(), Extension::CHECKSUM_COMPLEMENT_SIZE);
+
+    //lengths that don't fit are an error, unless asked for
+    let long = Extension::NtsAuthenticator { nonce: vec![3; 0x10004], ciphertext: vec![4; 16] };
+    assert_eq!(ExtensionField::try_from(&long),
+        Err(crate::ntp::Error::ExtensionTooLong { field_type: ExtensionFieldType::NTP_AUTHENTICATOR, length: 0x10004 }));
+    assert_eq!(&long.to_field_truncated().value[..4], &[0, 4, 0, 16]);
+
+    //malformed known fields are kept as they are
+    let short = ExtensionField { field_type: ExtensionFieldType::NTP_AUTHENTICATOR, value: vec![0, 16, 0, 16, 1] };
+    assert_eq!(Extension::from(&short), Extension::Unknown(short.clone()));
+    let complement = ExtensionField { field_type: ExtensionFieldType::CHECKSUM_COMPLEMENT, value: vec![1; 24] };
+    assert_eq!(Extension::from(&complement), Extension::Unknown(complement.clone()));
+}
+
+#[test]
+fn extension_padding() {
+    let mut packet = Packet {
+        extensions: Some(vec![
+            ExtensionField { field_type: 0x4242, value: vec![1; 5] },
+            ExtensionField { field_type: 0x4242, value: vec![1; 30] },
+            ExtensionField { field_type: 0x4242, value: vec![1; 2] },
+        ]),
+        ..client_request()
+    };
+    packet.pad_extensions();
+    let sizes = |p: &Packet| p.extensions.iter().flatten().map(|e| e.value.len()).collect::<Vec<_>>();
+    assert_eq!(sizes(&packet), vec![12, 32, 24]);
+    assert_eq!(packet.extensions.as_ref().unwrap()[0].value, vec![1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0]);
+
+    //with a mac the last field doesn't need the extra room
+    packet.extensions.as_mut().unwrap()[2].value = vec![1; 2];
+    packet.auth = Some(Auth { key_indentifier: 1, digest: vec![0; 16] });
+    packet.pad_extensions();
+    assert_eq!(sizes(&packet), vec![12, 32, 12]);
+}
+
+#[test]
+fn offset_and_delay() {
+    let at = |seconds: u32, fraction: u32| Timestamp::from(0).set_seconds(seconds).set_fraction(fraction);
+    let half = 1 << 31;
+
+    //server 10s ahead, 1s each way, 0.5s to answer
+    let (t1, t2, t3, t4) = (at(1000, 0), at(1011, 0), at(1011, half), at(1002, half));
+    assert_eq!(offset(t1, t2, t3, t4), TimeDelta(10 << 32));
+    assert_eq!(delay(t1, t2, t3, t4), TimeDelta(2 << 32));
+    let response = Packet { origin_timestamp: t1, receive_timestamp: t2, transit_timestamp: t3, ..client_request() };
+    assert_eq!(response.offset_and_delay(t4), (TimeDelta(10 << 32), TimeDelta(2 << 32)));
+
+    //server behind, across the era boundary in 2036
+    let (t1, t2, t3, t4) = (at(2, 0), at(u32::MAX, 0), at(u32::MAX, 1), at(4, 0));
+    assert_eq!(t2 - t1, TimeDelta(-3 << 32));
+    assert_eq!(offset(t1, t2, t3, t4), TimeDelta(((-3 << 32) + (-5 << 32) + 1) >> 1));
+    assert_eq!(delay(t1, t2, t3, t4), TimeDelta((2 << 32) - 1));
+
+    //a single 2^-32 step survives, nanoseconds round down
+    assert_eq!(at(0, 1) - at(0, 0), TimeDelta(1));
+    assert_eq!(TimeDelta(1).as_nanos(), 0);
+    assert_eq!(TimeDelta(-1).as_nanos(), -1);
+    assert_eq!((TimeDelta(-3 << 31).seconds(), TimeDelta(-3 << 31).fraction()), (-2, half));
+    assert_eq!(TimeDelta(-3 << 31).as_secs_f64(), -1.5);
+    assert_eq!(TimeDelta(-3 << 31).to_string(), "-1.500000000s");
+    assert_eq!(TimeDelta(-3 << 31).into_duration(), chrono::Duration::milliseconds(-1500));
+
+    //nonsense timestamps saturate instead of wrapping
+    assert_eq!(delay(at(0, 0), at(i32::MIN as u32, 0), at(i32::MAX as u32, 0), at(i32::MAX as u32, 0)), TimeDelta::MAX);
+}
+
+#[test]
+fn fixed_conversions() {
+    use std::convert::TryFrom;
+    use std::time::{Duration,SystemTime};
+
+    assert_eq!(Short::from_duration(chrono::Duration::milliseconds(-1)), Err(OutOfRange));
+    assert_eq!(Short::from_duration(chrono::Duration::seconds(65536)), Err(OutOfRange));
+    assert_eq!(Short::from_duration(chrono::Duration::milliseconds(1500)), Ok(Short(0x1_8000)));
+    assert_eq!(Duration::from(Short(0x1_8000)), Duration::from_millis(1500));
+    assert_eq!(Short::try_from(Duration::new(65_535, 999_999_999)), Err(OutOfRange));
+
+    assert_eq!(Fixed::try_from(SystemTime::UNIX_EPOCH), Ok(Fixed::UNIX_EPOCH));
+    assert_eq!(Fixed::from(chrono::DateTime::<chrono::Utc>::from(SystemTime::UNIX_EPOCH)), Fixed::UNIX_EPOCH);
+    assert_eq!(Timestamp::from_system_time(SystemTime::UNIX_EPOCH).get_seconds(), 2_208_988_800);
+    assert_eq!(Fixed::from_seconds(-1).seconds(), -1);
+    assert_eq!(Fixed(-1).fraction(), u64::MAX);
+    assert_eq!(Fixed(-1).nanoseconds(), 0);
+
+    //the first second of 2036's era 1 is 2^32 seconds past 1900
+    let era1 = Fixed::from_seconds(1 << 32);
+    assert_eq!((era1.era(), era1.to_timestamp()), (1, Timestamp(0)));
+    assert_eq!(Fixed::nearest(Timestamp(5 << 32), era1), Fixed::from_seconds((1 << 32) + 5));
+    assert_eq!(Fixed::nearest(Timestamp(u64::MAX << 32), era1), Fixed::from_seconds((1 << 32) - 1));
+
+    assert_eq!(Fixed::MAX.checked_add(Fixed(1)), None);
+    assert_eq!(TimeDelta::MIN.checked_neg(), None);
+    assert_eq!(Timestamp(1) - TimeDelta(2), Timestamp(u64::MAX));
+}
+
+#[test]
+#[should_panic(expected = "Fixed overflow")]
+fn fixed_overflow() {
+    let _ = Fixed::MIN - Fixed(1);
+}
+
+#[test]
+fn reference_ids() {
+    use std::net::{IpAddr,Ipv4Addr,Ipv6Addr};
+
+    let v6: Ipv6Addr = "2001:db8::1".parse().unwrap();
+    assert_eq!(ReferenceId::from_ipv6(Ipv6Addr::LOCALHOST), ReferenceId::Ipv6Hash([0xcf, 0x40, 0x4d, 0xc8]));
+    assert_eq!(ReferenceId::from_ip(IpAddr::V6(v6)).octets(), [0x39, 0xab, 0x9b, 0x37]);
+    assert_eq!(ReferenceId::from_ip(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))).octets(), [192, 0, 2, 1]);
+    assert_eq!(ReferenceId::source("GPS"), Some(ReferenceId::Source(*b"GPS\0")));
+    assert_eq!(ReferenceId::source("TOOLONG"), None);
+
+    let mut packet = client_request();
+    packet.stratum = Stratum::Unspecified;
+    packet.reference_id = *b"RATE";
+    assert_eq!(packet.reference().text(), Some("RATE"));
+    assert_eq!(packet.reference().to_string(), "kiss code RATE");
+    packet.stratum = Stratum::PrimaryServer;
+    packet.reference_id = *b"GPS\0";
+    assert_eq!(packet.reference(), ReferenceId::Source(*b"GPS\0"));
+    assert_eq!(packet.reference().to_string(), "source GPS");
+    packet.stratum = Stratum::SecondaryServer(3);
+    packet.reference_id = [0x39, 0xab, 0x9b, 0x37];
+    assert_eq!(packet.reference(), ReferenceId::Ipv4(Ipv4Addr::new(0x39, 0xab, 0x9b, 0x37)));
+    assert!(packet.reference().matches(IpAddr::V6(v6)));
+    assert!(!packet.reference().matches(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+    assert_eq!(ReferenceId::from_ipv6(v6).to_string(), "ipv6 hash 39ab9b37");
+}
+
+mod properties {
+    use std::convert::TryFrom;
+    use std::time::{Duration,SystemTime};
+    use proptest::prelude::*;
+    use crate::ntp::types::*;
+
+    proptest! {
+        #[test]
+        fn timestamp_round_trip(timestamp in any::<u64>(), era in any::<i32>()) {
+            let fixed = Fixed::from_timestamp(Timestamp(timestamp), era);
+            prop_assert_eq!((fixed.to_timestamp(), fixed.era()), (Timestamp(timestamp), era));
+        }
+
+        #[test]
+        fn short_and_delta_round_trip(short in any::<u32>(), delta in any::<i64>()) {
+            prop_assert_eq!(Short::try_from(Fixed::from(Short(short))), Ok(Short(short)));
+            prop_assert_eq!(TimeDelta::try_from(Fixed::from(TimeDelta(delta))), Ok(TimeDelta(delta)));
+        }
+
+        #[test]
+        fn duration_round_trip(seconds in 0..i64::MAX as u64, nanos in 0..1_000_000_000u32) {
+            let duration = Duration::new(seconds, nanos);
+            prop_assert_eq!(Duration::try_from(Fixed::try_from(duration).unwrap()), Ok(duration));
+        }
+
+        #[test]
+        fn chrono_round_trip(nanoseconds in any::<i64>(), seconds in -(i64::MAX / 1000)..i64::MAX / 1000) {
+            let duration = chrono::Duration::nanoseconds(nanoseconds);
+            prop_assert_eq!(chrono::Duration::try_from(Fixed::from(duration)), Ok(duration));
+            let duration = chrono::Duration::seconds(seconds);
+            prop_assert_eq!(chrono::Duration::try_from(Fixed::from(duration)), Ok(duration));
+        }
+
+        #[test]
+        fn datetime_round_trip(seconds in -(1i64 << 40)..1 << 40, nanos in 0..1_000_000_000u32) {
+            let datetime = chrono::DateTime::<chrono::Utc>::from_utc(chrono::NaiveDateTime::from_timestamp(seconds, nanos), chrono::Utc);
+            prop_assert_eq!(chrono::DateTime::try_from(Fixed::from(datetime)), Ok(datetime));
+        }
+
+        //timestamps are finer than nanoseconds, so system times in era 0 make it through them too
+        #[test]
+        fn system_time_round_trip(seconds in 0..1u64 << 32, nanos in 0..1_000_000_000u32) {
+            let time = SystemTime::UNIX_EPOCH - Duration::from_secs(2_208_988_800) + Duration::new(seconds, nanos);
+            prop_assert_eq!(SystemTime::try_from(Fixed::try_from(time).unwrap()), Ok(time));
+            if seconds < (1 << 32) - 1 {
+                prop_assert_eq!(Timestamp::from_system_time(time).to_system_time(), time);
+            }
+        }
+
+        #[test]
+        fn nearest_era(timestamp in any::<u64>(), pivot in -(1i64 << 40)..1 << 40, offset in -(1i64 << 31) + 1..1 << 31) {
+            let time = Fixed::from_seconds(pivot) + Fixed::from(TimeDelta(offset << 32)) + Fixed(i128::from(timestamp as u32) << 32);
+            prop_assert_eq!(Fixed::nearest(time.to_timestamp(), Fixed::from_seconds(pivot)), time);
+        }
+    }
+}
@@ -1,15 +1,24 @@
 use std::convert::{TryFrom,TryInto,From,Into};
 use std::num::TryFromIntError;
 use std::mem::size_of;
+use std::net::{IpAddr,Ipv4Addr,Ipv6Addr};
+use md5::{Md5,Digest};
 use simple_error::SimpleError;
+use byteorder::{BigEndian,ByteOrder};
+use super::constants::ExtensionFieldType;
+use super::Error;
 use num_enum::{IntoPrimitive,TryFromPrimitive};
-use derive_more::{Add,Mul,From,Into,Deref,DerefMut,LowerHex};
+use derive_more::{From,Into,Deref,DerefMut,LowerHex};
+use serde::{Serialize,Deserialize};
 
-#[derive(Debug,Eq,PartialEq,Clone,Copy,IntoPrimitive,TryFromPrimitive)]
+#[derive(Debug,Eq,PartialEq,Clone,Copy,IntoPrimitive,TryFromPrimitive,Serialize,Deserialize)]
 #[repr(u8)]
+#[serde(rename_all = "snake_case")]
 pub enum LeapIndicator {
     NoWarning = 0,
+    #[serde(rename = "last_minute_61_seconds")]
     LastMinute61Seconds = 1,
+    #[serde(rename = "last_minute_59_seconds")]
     LastMinute59Seconds = 2,
     Unknown = 3,
 }
@@ -53,8 +62,101 @@ impl TryInto<u8> for Stratum {
     }
 }
 
-#[derive(Debug,Eq,PartialEq,Clone,Copy,IntoPrimitive,TryFromPrimitive)]
+//what the reference id means depends on the stratum, rfc 5905 section 7.3
+#[derive(Debug,Eq,PartialEq,Clone,Copy)]
+pub enum ReferenceId {
+    KissCode([u8;4]),       //stratum 0, see constants::KoD
+    Source([u8;4]),         //stratum 1, see constants::ClockSource, nul padded ascii
+    Ipv4(Ipv4Addr),         //stratum 2 and up, the upstream server
+    Ipv6Hash([u8;4]),       //stratum 2 and up, first four octets of the md5 of the upstream address
+}
+
+impl ReferenceId {
+    pub fn from_ip(address: IpAddr) -> Self {
+        match address {
+            IpAddr::V4(address) => ReferenceId::Ipv4(address),
+            IpAddr::V6(address) => ReferenceId::from_ipv6(address),
+        }
+    }
+
+    //ipv4 mapped addresses are hashed too, they're ipv6 on the wire
+    pub fn from_ipv6(address: Ipv6Addr) -> Self {
+        let digest = Md5::digest(address.octets());
+        ReferenceId::Ipv6Hash([digest[0], digest[1], digest[2], digest[3]])
+    }
+
+    //up to four ascii characters, "GPS" or "PPS"
+    pub fn source(source: &str) -> Option<Self> {
+        if source.is_empty() || source.len() > 4 || !source.is_ascii() {
+            return None;
+        }
+        let mut id = [0; 4];
+        id[..source.len()].copy_from_slice(source.as_bytes());
+        Some(ReferenceId::Source(id))
+    }
+
+    //a hash and an ipv4 address look the same, from stratum 2 on everything reads as an address
+    //ntpd sends stratum 16 with kiss codes like INIT and STEP, so those are read as kiss codes too
+    pub fn interpret(stratum: Stratum, id: [u8;4]) -> Self {
+        match stratum {
+            Stratum::Unspecified | Stratum::Unsynchronized => ReferenceId::KissCode(id),
+            Stratum::PrimaryServer => ReferenceId::Source(id),
+            Stratum::SecondaryServer(_) | Stratum::Reserved(_) => ReferenceId::Ipv4(Ipv4Addr::from(id)),
+        }
+    }
+
+    pub fn octets(self) -> [u8;4] {
+        match self {
+            ReferenceId::KissCode(id) | ReferenceId::Source(id) | ReferenceId::Ipv6Hash(id) => id,
+            ReferenceId::Ipv4(address) => address.octets(),
+        }
+    }
+
+    //kiss codes and sources without the padding, None for addresses or when it's not printable
+    pub fn text(&self) -> Option<&str> {
+        let id = match self {
+            ReferenceId::KissCode(id) | ReferenceId::Source(id) => id,
+            _ => return None,
+        };
+        let length = id.iter().position(|b| *b == 0).unwrap_or(4);
+        if id[..length].iter().all(|b| b.is_ascii_graphic() || *b == b' ') && id[length..].iter().all(|b| *b == 0) {
+            std::str::from_utf8(&id[..length]).ok()
+        } else {
+            None
+        }
+    }
+
+    //whether the upstream could be this address, a stratum 2 id is never known to be a hash
+    pub fn matches(self, address: IpAddr) -> bool {
+        match self {
+            ReferenceId::Ipv4(_) | ReferenceId::Ipv6Hash(_) => self.octets() == ReferenceId::from_ip(address).octets(),
+            _ => false,
+        }
+    }
+}
+
+impl From<ReferenceId> for [u8;4] {
+    fn from(id: ReferenceId) -> [u8;4] {
+        id.octets()
+    }
+}
+
+impl std::fmt::Display for ReferenceId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self, self.text()) {
+            (ReferenceId::KissCode(_), Some(text)) => write!(f, "kiss code {}", text),
+            (ReferenceId::Source(_), Some(text)) => write!(f, "source {}", text),
+            (ReferenceId::KissCode(id), None) => write!(f, "kiss code {:02x?}", id),
+            (ReferenceId::Source(id), None) => write!(f, "source {:02x?}", id),
+            (ReferenceId::Ipv4(address), _) => write!(f, "{}", address),
+            (ReferenceId::Ipv6Hash(id), _) => write!(f, "ipv6 hash {}", hex::encode(id)),
+        }
+    }
+}
+
+#[derive(Debug,Eq,PartialEq,Clone,Copy,IntoPrimitive,TryFromPrimitive,Serialize,Deserialize)]
 #[repr(u8)]
+#[serde(rename_all = "snake_case")]
 pub enum Mode {
     Reserved = 0,
     SymmetricActive = 1,
@@ -62,17 +164,37 @@ pub enum Mode {
     Client = 3,
     Server = 4,
     Broadcast = 5,
+    #[serde(rename = "control")]
     NTPControlMessage = 6,
     ReservedForPrivate = 7,
 }
 
-#[derive(Debug,Clone,Copy)]
+impl Mode {
+    //for metric labels, same as the debug output
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::Reserved => "Reserved",
+            Mode::SymmetricActive => "SymmetricActive",
+            Mode::SymmetricPassive => "SymmetricPassive",
+            Mode::Client => "Client",
+            Mode::Server => "Server",
+            Mode::Broadcast => "Broadcast",
+            Mode::NTPControlMessage => "NTPControlMessage",
+            Mode::ReservedForPrivate => "ReservedForPrivate",
+        }
+    }
+}
+
+//the digest is 128 bits for md5 and aes-cmac, 160 bits for sha1 and empty for a crypto-nak
+#[derive(Debug,Clone,PartialEq,Eq,Serialize,Deserialize)]
 pub struct Auth {
     pub key_indentifier: u32,   //32 bits, optional
-    pub digest: u128,           //128 bits, optional
+    #[serde(with = "super::serde_impls::hex_bytes")]
+    pub digest: Vec<u8>,        //0, 128 or 160 bits, optional
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct Date {
     era_number: u32,
     era_offset: u32,
@@ -86,10 +208,10 @@ pub struct Date {
 //just assume that it came from my era?
 //update: i think i know why
 
-#[derive(Debug,Clone,Eq,PartialEq,Ord,PartialOrd,Copy,Add,Mul,Deref,DerefMut,From,Into,LowerHex)]
+#[derive(Debug,Clone,Eq,PartialEq,Ord,PartialOrd,Copy,Deref,DerefMut,From,Into,LowerHex)]
 pub struct Timestamp(pub u64);
 
-#[derive(Debug,Clone,Eq,PartialEq,Ord,PartialOrd,Copy,Add,Mul,Deref,DerefMut,From,Into,LowerHex)]
+#[derive(Debug,Clone,Eq,PartialEq,Ord,PartialOrd,Copy,Deref,DerefMut,From,Into,LowerHex)]
 pub struct Short(pub u32);
 
 pub trait TimestampTrait<T, H> {
@@ -107,34 +229,419 @@ pub trait TimestampTrait<T, H> {
 impl Timestamp {
     //seems like chrono does not handle leap seconds yet...
     //is this really an issue?
+    //era 0, rounded to the nearest nanosecond
     pub fn into_utc_datetime(self) -> chrono::DateTime<chrono::offset::Utc> {
-        //2208988800 - 1970-1900 as seconds
-        let ntp_epoch = chrono::naive::NaiveDate::from_ymd(1900, 1, 1).and_hms(0, 0, 0);
-        let seconds = chrono::Duration::seconds(self.get_seconds().into());
-        let nanoseconds = chrono::Duration::nanoseconds(self.fraction_as_nanoseconds().into());
-        chrono::DateTime::from_utc(ntp_epoch + seconds + nanoseconds, chrono::offset::Utc)
+        chrono::DateTime::try_from(Fixed::from_timestamp(self, 0)).expect("era 0 fits in a chrono date")
     }
 
+    //rounded to the nearest 2^-32, wraps around at era boundaries so it never actually fails
     pub fn from_utc_datetime(datetime: chrono::DateTime<chrono::offset::Utc>) -> Result<Self,TryFromIntError> {
-        let ntp_epoch = chrono::naive::NaiveDate::from_ymd(1900, 1, 1).and_hms(0, 0, 0);
-        let duration = datetime.naive_utc()-ntp_epoch;
-        let nanoseconds = duration.num_nanoseconds().unwrap_or(0)
-            - chrono::Duration::seconds(duration.num_seconds()).num_nanoseconds().unwrap_or(0);
-        Ok(Self::from((duration.num_seconds() as u64) << 32u32).fraction_from_nanoseconds(nanoseconds.try_into()?)?)
+        Ok(Fixed::from(datetime).to_timestamp())
+    }
+
+    //same as from_utc_datetime, dates before 1900 or after 2036 wrap around
+    //times more than 2^63 seconds away from 1970 are clamped first
+    pub fn from_system_time(time: std::time::SystemTime) -> Self {
+        let clamped = if time < std::time::SystemTime::UNIX_EPOCH { Fixed::MIN } else { Fixed::MAX };
+        Fixed::try_from(time).unwrap_or(clamped).to_timestamp()
+    }
+
+    //assumes era 0 like into_utc_datetime, use Fixed::nearest for anything else
+    pub fn to_system_time(self) -> std::time::SystemTime {
+        std::time::SystemTime::try_from(Fixed::from_timestamp(self, 0)).expect("era 0 fits in a SystemTime")
     }
 }
 
 impl Short {
+    //rounded to the nearest nanosecond
+    pub fn into_duration(self) -> chrono::Duration {
+        chrono::Duration::try_from(Fixed::from(self)).expect("shorts are less than 65536 seconds")
+    }
+
+    //rounded to the nearest 2^-16, negative durations and anything from 65536 seconds on don't fit
+    pub fn from_duration(duration: chrono::Duration) -> Result<Self,OutOfRange> {
+        Short::try_from(Fixed::from(duration))
+    }
+}
+
+//signed difference between two timestamps, 32.32 fixed point like Timestamp
+//good for about 68 years either way, with the same 2^-32 second (~233ps) resolution
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord,Hash,Default)]
+pub struct TimeDelta(pub i64);
+
+impl TimeDelta {
+    pub const ZERO: TimeDelta = TimeDelta(0);
+    pub const MAX: TimeDelta = TimeDelta(i64::MAX);
+    pub const MIN: TimeDelta = TimeDelta(i64::MIN);
+
+    //whole seconds, rounded towards negative infinity like the fraction is
+    pub fn seconds(self) -> i32 {
+        (self.0 >> 32) as i32
+    }
+
+    pub fn fraction(self) -> u32 {
+        self.0 as u32
+    }
+
+    pub fn as_secs_f64(self) -> f64 {
+        self.0 as f64 / (1u64 << 32) as f64
+    }
+
+    //rounded towards negative infinity, a full 68 years still fits
+    pub fn as_nanos(self) -> i64 {
+        ((i128::from(self.0) * 1_000_000_000) >> 32) as i64
+    }
+
     pub fn into_duration(self) -> chrono::Duration {
-        chrono::Duration::seconds(self.get_seconds().into()) + 
-            chrono::Duration::nanoseconds(self.fraction_as_nanoseconds().into())
+        chrono::Duration::nanoseconds(self.as_nanos())
+    }
+
+    //anything that doesn't fit saturates
+    fn saturating_from(value: i128) -> TimeDelta {
+        TimeDelta(value.clamp(i128::from(i64::MIN), i128::from(i64::MAX)) as i64)
     }
+}
 
-    pub fn from_duration(duration: chrono::Duration) -> Result<Self,TryFromIntError> {
-        Ok(Self((duration.num_seconds() as u32) << 16u16).fraction_from_nanoseconds(
-                duration.checked_sub(&chrono::Duration::seconds(duration.num_seconds()))
-                    .unwrap().num_nanoseconds().unwrap().try_into()?
-            )?)
+impl std::fmt::Display for TimeDelta {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let nanos = i128::from(self.as_nanos());
+        let sign = if nanos < 0 { "-" } else { "" };
+        write!(f, "{}{}.{:09}s", sign, nanos.abs() / 1_000_000_000, nanos.abs() % 1_000_000_000)
+    }
+}
+
+//rfc 5905 section 6, the difference is taken modulo 2^64 so it is right across era boundaries
+//as long as the timestamps are less than 68 years apart
+impl std::ops::Sub for Timestamp {
+    type Output = TimeDelta;
+
+    fn sub(self, earlier: Timestamp) -> TimeDelta {
+        TimeDelta(self.0.wrapping_sub(earlier.0) as i64)
+    }
+}
+
+//t1 client transmit, t2 server receive, t3 server transmit, t4 client receive
+//θ = ((t2 - t1) + (t3 - t4)) / 2, the half is rounded towards negative infinity
+pub fn offset(t1: Timestamp, t2: Timestamp, t3: Timestamp, t4: Timestamp) -> TimeDelta {
+    TimeDelta::saturating_from((i128::from((t2 - t1).0) + i128::from((t3 - t4).0)) >> 1)
+}
+
+//δ = (t4 - t1) - (t3 - t2), negative if the server's clock runs faster than the client's
+//saturates for timestamps that make no sense, a server claiming it took longer than 68 years to answer
+pub fn delay(t1: Timestamp, t2: Timestamp, t3: Timestamp, t4: Timestamp) -> TimeDelta {
+    TimeDelta::saturating_from(i128::from((t4 - t1).0) - i128::from((t3 - t2).0))
+}
+
+impl TimeDelta {
+    pub fn checked_add(self, other: TimeDelta) -> Option<TimeDelta> {
+        self.0.checked_add(other.0).map(TimeDelta)
+    }
+
+    pub fn checked_sub(self, other: TimeDelta) -> Option<TimeDelta> {
+        self.0.checked_sub(other.0).map(TimeDelta)
+    }
+
+    pub fn checked_neg(self) -> Option<TimeDelta> {
+        self.0.checked_neg().map(TimeDelta)
+    }
+}
+
+//the operators panic on overflow, in release builds too, use the checked versions to handle it
+impl std::ops::Add for TimeDelta {
+    type Output = TimeDelta;
+
+    fn add(self, other: TimeDelta) -> TimeDelta {
+        self.checked_add(other).expect("TimeDelta overflow")
+    }
+}
+
+impl std::ops::Sub for TimeDelta {
+    type Output = TimeDelta;
+
+    fn sub(self, other: TimeDelta) -> TimeDelta {
+        self.checked_sub(other).expect("TimeDelta overflow")
+    }
+}
+
+impl std::ops::Neg for TimeDelta {
+    type Output = TimeDelta;
+
+    fn neg(self) -> TimeDelta {
+        self.checked_neg().expect("TimeDelta overflow")
+    }
+}
+
+//moving a timestamp wraps around at the end of the era, just like the difference does
+impl std::ops::Add<TimeDelta> for Timestamp {
+    type Output = Timestamp;
+
+    fn add(self, delta: TimeDelta) -> Timestamp {
+        Timestamp(self.0.wrapping_add(delta.0 as u64))
+    }
+}
+
+impl std::ops::Sub<TimeDelta> for Timestamp {
+    type Output = Timestamp;
+
+    fn sub(self, delta: TimeDelta) -> Timestamp {
+        Timestamp(self.0.wrapping_sub(delta.0 as u64))
+    }
+}
+
+//a time that doesn't fit the type it was converted to
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct OutOfRange;
+
+impl std::fmt::Display for OutOfRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "time out of range")
+    }
+}
+
+impl std::error::Error for OutOfRange {}
+
+//signed 64.64 fixed point seconds, the rfc 5905 date format when counted from the ntp epoch
+//(era number, era offset and fraction in one number) and a duration otherwise
+//everything else converts to it and back without losing anything it could represent:
+//timestamps, shorts and time deltas exactly, nanoseconds (std, chrono) round to the nearest 2^-64
+//and come back as the same nanosecond, the other way around rounds to the nearest nanosecond
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord,Hash,Default)]
+pub struct Fixed(pub i128);
+
+const NANOS_PER_SECOND: i128 = 1_000_000_000;
+
+impl Fixed {
+    pub const ZERO: Fixed = Fixed(0);
+    pub const MAX: Fixed = Fixed(i128::MAX);
+    pub const MIN: Fixed = Fixed(i128::MIN);
+    //seconds between the ntp epoch (1900-01-01) and the unix epoch (1970-01-01)
+    pub const UNIX_EPOCH: Fixed = Fixed(2_208_988_800 << 64);
+
+    pub fn from_seconds(seconds: i64) -> Fixed {
+        Fixed(i128::from(seconds) << 64)
+    }
+
+    //rounded towards negative infinity, fraction() is always positive
+    pub fn seconds(self) -> i64 {
+        (self.0 >> 64) as i64
+    }
+
+    pub fn fraction(self) -> u64 {
+        self.0 as u64
+    }
+
+    pub fn as_secs_f64(self) -> f64 {
+        self.0 as f64 / 2f64.powi(64)
+    }
+
+    //era 0 starts at the ntp epoch, era -1 are the 136 years before it
+    pub fn from_timestamp(timestamp: Timestamp, era: i32) -> Fixed {
+        Fixed((i128::from(era) << 96) | (i128::from(timestamp.0) << 32))
+    }
+
+    //the era the timestamp was in, closest to pivot, rfc 5905 section 6
+    //pivot is usually the current time, it works as long as they are less than 68 years apart
+    pub fn nearest(timestamp: Timestamp, pivot: Fixed) -> Fixed {
+        let pivot_timestamp = pivot.to_timestamp();
+        pivot.checked_add(Fixed::from(timestamp - pivot_timestamp))
+            .unwrap_or_else(|| Fixed::from_timestamp(timestamp, pivot.era()))
+    }
+
+    pub fn era(self) -> i32 {
+        (self.0 >> 96) as i32
+    }
+
+    //the era is dropped, the fraction rounded to the nearest 2^-32
+    pub fn to_timestamp(self) -> Timestamp {
+        Timestamp((round_shift(self.0, 32)) as u64)
+    }
+
+    //rounded to the nearest nanosecond
+    pub fn nanoseconds(self) -> i128 {
+        let seconds = i128::from(self.seconds());
+        let nanos = round_shift(i128::from(self.fraction()) * NANOS_PER_SECOND, 64);
+        seconds * NANOS_PER_SECOND + nanos
+    }
+
+    pub fn from_nanoseconds(nanoseconds: i128) -> Result<Fixed, OutOfRange> {
+        let seconds = nanoseconds.div_euclid(NANOS_PER_SECOND);
+        let nanos = nanoseconds.rem_euclid(NANOS_PER_SECOND);
+        //2^64 / 10^9 isn't a whole number, half a step is added to round to nearest
+        let fraction = ((nanos << 64) + NANOS_PER_SECOND / 2) / NANOS_PER_SECOND;
+        let seconds = i64::try_from(seconds).map_err(|_| OutOfRange)?;
+        Fixed::from_seconds(seconds).checked_add(Fixed(fraction)).ok_or(OutOfRange)
+    }
+
+    pub fn checked_add(self, other: Fixed) -> Option<Fixed> {
+        self.0.checked_add(other.0).map(Fixed)
+    }
+
+    pub fn checked_sub(self, other: Fixed) -> Option<Fixed> {
+        self.0.checked_sub(other.0).map(Fixed)
+    }
+
+    pub fn checked_neg(self) -> Option<Fixed> {
+        self.0.checked_neg().map(Fixed)
+    }
+}
+
+//nearest integer to value / 2^bits, halves round up, without overflowing
+fn round_shift(value: i128, bits: u32) -> i128 {
+    ((value >> (bits - 1)) + 1) >> 1
+}
+
+impl std::ops::Add for Fixed {
+    type Output = Fixed;
+
+    fn add(self, other: Fixed) -> Fixed {
+        self.checked_add(other).expect("Fixed overflow")
+    }
+}
+
+impl std::ops::Sub for Fixed {
+    type Output = Fixed;
+
+    fn sub(self, other: Fixed) -> Fixed {
+        self.checked_sub(other).expect("Fixed overflow")
+    }
+}
+
+impl std::ops::Neg for Fixed {
+    type Output = Fixed;
+
+    fn neg(self) -> Fixed {
+        self.checked_neg().expect("Fixed overflow")
+    }
+}
+
+impl From<TimeDelta> for Fixed {
+    fn from(delta: TimeDelta) -> Fixed {
+        Fixed(i128::from(delta.0) << 32)
+    }
+}
+
+impl TryFrom<Fixed> for TimeDelta {
+    type Error = OutOfRange;
+
+    fn try_from(fixed: Fixed) -> Result<TimeDelta, OutOfRange> {
+        i64::try_from(round_shift(fixed.0, 32)).map(TimeDelta).map_err(|_| OutOfRange)
+    }
+}
+
+impl From<Short> for Fixed {
+    fn from(short: Short) -> Fixed {
+        Fixed(i128::from(short.0) << 48)
+    }
+}
+
+impl TryFrom<Fixed> for Short {
+    type Error = OutOfRange;
+
+    fn try_from(fixed: Fixed) -> Result<Short, OutOfRange> {
+        u32::try_from(round_shift(fixed.0, 48)).map(Short).map_err(|_| OutOfRange)
+    }
+}
+
+impl TryFrom<std::time::Duration> for Fixed {
+    type Error = OutOfRange;
+
+    fn try_from(duration: std::time::Duration) -> Result<Fixed, OutOfRange> {
+        Fixed::from_nanoseconds(duration.as_nanos() as i128)
+    }
+}
+
+impl TryFrom<Fixed> for std::time::Duration {
+    type Error = OutOfRange;
+
+    fn try_from(fixed: Fixed) -> Result<std::time::Duration, OutOfRange> {
+        let nanoseconds = u128::try_from(fixed.nanoseconds()).map_err(|_| OutOfRange)?;
+        let seconds = u64::try_from(nanoseconds / NANOS_PER_SECOND as u128).map_err(|_| OutOfRange)?;
+        Ok(std::time::Duration::new(seconds, (nanoseconds % NANOS_PER_SECOND as u128) as u32))
+    }
+}
+
+impl From<chrono::Duration> for Fixed {
+    fn from(duration: chrono::Duration) -> Fixed {
+        let seconds = duration.num_seconds();
+        let nanos = (duration - chrono::Duration::seconds(seconds)).num_nanoseconds().expect("less than a second");
+        Fixed::from_nanoseconds(i128::from(seconds) * NANOS_PER_SECOND + i128::from(nanos))
+            .expect("chrono durations are milliseconds in an i64")
+    }
+}
+
+impl TryFrom<Fixed> for chrono::Duration {
+    type Error = OutOfRange;
+
+    fn try_from(fixed: Fixed) -> Result<chrono::Duration, OutOfRange> {
+        let nanoseconds = fixed.nanoseconds();
+        let seconds = i64::try_from(nanoseconds.div_euclid(NANOS_PER_SECOND)).map_err(|_| OutOfRange)?;
+        let nanos = nanoseconds.rem_euclid(NANOS_PER_SECOND) as i64;
+        //chrono::Duration::seconds panics past i64::MAX milliseconds
+        if !(-(i64::MAX / 1000)..i64::MAX / 1000).contains(&seconds) {
+            return Err(OutOfRange);
+        }
+        chrono::Duration::seconds(seconds).checked_add(&chrono::Duration::nanoseconds(nanos)).ok_or(OutOfRange)
+    }
+}
+
+//dates count from the ntp epoch, a leap second (nanoseconds past 10^9 in chrono) overlaps the
+//second after it, the same second ntp timestamps repeat
+impl From<chrono::DateTime<chrono::offset::Utc>> for Fixed {
+    fn from(datetime: chrono::DateTime<chrono::offset::Utc>) -> Fixed {
+        let nanoseconds = i128::from(datetime.timestamp()) * NANOS_PER_SECOND + i128::from(datetime.timestamp_subsec_nanos());
+        Fixed::UNIX_EPOCH + Fixed::from_nanoseconds(nanoseconds).expect("chrono dates are seconds in an i64")
+    }
+}
+
+impl TryFrom<Fixed> for chrono::DateTime<chrono::offset::Utc> {
+    type Error = OutOfRange;
+
+    fn try_from(fixed: Fixed) -> Result<chrono::DateTime<chrono::offset::Utc>, OutOfRange> {
+        let nanoseconds = fixed.checked_sub(Fixed::UNIX_EPOCH).ok_or(OutOfRange)?.nanoseconds();
+        let seconds = i64::try_from(nanoseconds.div_euclid(NANOS_PER_SECOND)).map_err(|_| OutOfRange)?;
+        let nanos = nanoseconds.rem_euclid(NANOS_PER_SECOND) as u32;
+        chrono::NaiveDateTime::from_timestamp_opt(seconds, nanos)
+            .map(|naive| chrono::DateTime::from_utc(naive, chrono::offset::Utc))
+            .ok_or(OutOfRange)
+    }
+}
+
+impl TryFrom<std::time::SystemTime> for Fixed {
+    type Error = OutOfRange;
+
+    fn try_from(time: std::time::SystemTime) -> Result<Fixed, OutOfRange> {
+        match time.duration_since(std::time::SystemTime::UNIX_EPOCH) {
+            Ok(since_epoch) => Fixed::UNIX_EPOCH.checked_add(Fixed::try_from(since_epoch)?),
+            Err(err) => Fixed::UNIX_EPOCH.checked_sub(Fixed::try_from(err.duration())?),
+        }.ok_or(OutOfRange)
+    }
+}
+
+impl TryFrom<Fixed> for std::time::SystemTime {
+    type Error = OutOfRange;
+
+    fn try_from(fixed: Fixed) -> Result<std::time::SystemTime, OutOfRange> {
+        let since_epoch = fixed.checked_sub(Fixed::UNIX_EPOCH).ok_or(OutOfRange)?;
+        if since_epoch < Fixed::ZERO {
+            let before_epoch = std::time::Duration::try_from(since_epoch.checked_neg().ok_or(OutOfRange)?)?;
+            std::time::SystemTime::UNIX_EPOCH.checked_sub(before_epoch)
+        } else {
+            std::time::SystemTime::UNIX_EPOCH.checked_add(std::time::Duration::try_from(since_epoch)?)
+        }.ok_or(OutOfRange)
+    }
+}
+
+impl TryFrom<std::time::Duration> for Short {
+    type Error = OutOfRange;
+
+    fn try_from(duration: std::time::Duration) -> Result<Short, OutOfRange> {
+        Short::try_from(Fixed::try_from(duration)?)
+    }
+}
+
+impl From<Short> for std::time::Duration {
+    fn from(short: Short) -> std::time::Duration {
+        std::time::Duration::try_from(Fixed::from(short)).expect("shorts are less than 65536 seconds")
     }
 }
 
@@ -155,7 +662,7 @@ macro_rules! gen_timestamp_trait {
 
             fn set_seconds(self, seconds: $halfsize) -> Self { 
                 (((seconds as $size) << (size_of::<$halfsize>()*8)) 
-                | $size::from((self.get_fraction()))).into() 
+                | $size::from(self.get_fraction())).into() 
             }
 
             fn set_fraction(self, fraction: $halfsize) -> Self { 
@@ -163,7 +670,7 @@ macro_rules! gen_timestamp_trait {
                  | (fraction as $size)).into() 
             }
 
-            //loosy - fraction_from_nanoseconds(fraction_as_nanoseconds) != fraction
+            //loosy - fraction_from_nanoseconds(fraction_as_nanoseconds) != fraction, go through Fixed for exact ones
             fn fraction_as_nanoseconds(self) -> u32 {
                 //u32::try_from((((self.get_fraction() as u64)*1_000_000_000u64)/(1u64 << 32))).unwrap()
                 u32::try_from(((self.get_fraction() as u64)*1_000_000_000u64) >> 32).unwrap()
@@ -180,14 +687,245 @@ macro_rules! gen_timestamp_trait {
 gen_timestamp_trait!(Timestamp, u64, u32);
 gen_timestamp_trait!(Short, u32, u16);
 
-#[derive(Debug,Clone)]
+#[derive(Debug,Clone,PartialEq,Eq,Serialize,Deserialize)]
 pub struct ExtensionField {
     pub field_type: u16,
     //pub length: u16,
+    #[serde(with = "super::serde_impls::hex_bytes")]
     pub value: Vec<u8>,
 }
 
-#[derive(Debug,Clone)]
+impl ExtensionField {
+    //values are padded to a multiple of 4 bytes on the wire
+    pub fn padded_len(&self) -> usize {
+        (self.value.len() + 3) & !3
+    }
+}
+
+//extension fields we know how to read, anything else (or anything malformed) is Unknown
+//converting an Unknown back gives the same field, so a packet survives Extension and back
+#[derive(Debug,Clone,PartialEq,Eq,Serialize,Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Extension {
+    #[serde(with = "super::serde_impls::hex_bytes")]
+    UniqueIdentifier(Vec<u8>),          //rfc 8915, at least 32 random bytes
+    #[serde(with = "super::serde_impls::hex_bytes")]
+    NtsCookie(Vec<u8>),
+    NtsCookiePlaceholder(usize),        //length of the cookie the client wants in its place
+    NtsAuthenticator {
+        #[serde(with = "super::serde_impls::hex_bytes")]
+        nonce: Vec<u8>,
+        #[serde(with = "super::serde_impls::hex_bytes")]
+        ciphertext: Vec<u8>,            //synthetic iv followed by the encrypted extension fields
+    },
+    ChecksumComplement(u16),            //rfc 7821, the rest of the field must be zero
+    Unknown(ExtensionField),
+}
+
+impl Extension {
+    pub const CHECKSUM_COMPLEMENT_SIZE: usize = 24;
+
+    pub fn field_type(&self) -> u16 {
+        match self {
+            Extension::UniqueIdentifier(_) => ExtensionFieldType::UNIQUE,
+            Extension::NtsCookie(_) => ExtensionFieldType::NTS_COOKIE,
+            Extension::NtsCookiePlaceholder(_) => ExtensionFieldType::NTS_COOKIE_PLACEHOLDER,
+            Extension::NtsAuthenticator { .. } => ExtensionFieldType::NTP_AUTHENTICATOR,
+            Extension::ChecksumComplement(_) => ExtensionFieldType::CHECKSUM_COMPLEMENT,
+            Extension::Unknown(field) => field.field_type,
+        }
+    }
+
+    //nonce length, ciphertext length, nonce and ciphertext, both padded to 4 bytes
+    fn parse_authenticator(value: &[u8]) -> Option<Extension> {
+        let nonce_len = usize::from(BigEndian::read_u16(value.get(..2)?));
+        let ciphertext_len = usize::from(BigEndian::read_u16(value.get(2..4)?));
+        let ciphertext_start = (4 + nonce_len + 3) & !3;
+        Some(Extension::NtsAuthenticator {
+            nonce: value.get(4..4 + nonce_len)?.to_vec(),
+            ciphertext: value.get(ciphertext_start..ciphertext_start + ciphertext_len)?.to_vec(),
+        })
+    }
+}
+
+impl From<&ExtensionField> for Extension {
+    fn from(field: &ExtensionField) -> Self {
+        let value = &field.value;
+        let typed = match field.field_type {
+            ExtensionFieldType::UNIQUE => Some(Extension::UniqueIdentifier(value.clone())),
+            ExtensionFieldType::NTS_COOKIE => Some(Extension::NtsCookie(value.clone())),
+            //the body of a placeholder is ignored, only its length matters
+            ExtensionFieldType::NTS_COOKIE_PLACEHOLDER => Some(Extension::NtsCookiePlaceholder(value.len())),
+            ExtensionFieldType::NTP_AUTHENTICATOR => Extension::parse_authenticator(value),
+            ExtensionFieldType::CHECKSUM_COMPLEMENT if value.len() == Extension::CHECKSUM_COMPLEMENT_SIZE
+                && value[..Extension::CHECKSUM_COMPLEMENT_SIZE - 2].iter().all(|b| *b == 0) =>
+                Some(Extension::ChecksumComplement(BigEndian::read_u16(&value[Extension::CHECKSUM_COMPLEMENT_SIZE - 2..]))),
+            _ => None,
+        };
+        typed.unwrap_or_else(|| Extension::Unknown(field.clone()))
+    }
+}
+
+impl Extension {
+    //lengths that don't fit in 16 bits are truncated instead of being an error
+    //crafting broken authenticators is the point sometimes, everything else should use try_from
+    pub fn to_field_truncated(&self) -> ExtensionField {
+        self.to_field(true).expect("nothing is checked when truncating")
+    }
+
+    fn to_field(&self, truncate: bool) -> Result<ExtensionField, Error> {
+        let length = |bytes: &[u8]| match u16::try_from(bytes.len()) {
+            Ok(length) => Ok(length),
+            Err(_) if truncate => Ok(bytes.len() as u16),
+            Err(_) => Err(Error::ExtensionTooLong { field_type: self.field_type(), length: bytes.len() }),
+        };
+        let value = match self {
+            Extension::UniqueIdentifier(value) | Extension::NtsCookie(value) => value.clone(),
+            Extension::NtsCookiePlaceholder(len) => vec![0; *len],
+            Extension::NtsAuthenticator { nonce, ciphertext } => {
+                let mut value = vec![0; 4];
+                BigEndian::write_u16(&mut value[..2], length(nonce)?);
+                BigEndian::write_u16(&mut value[2..], length(ciphertext)?);
+                value.extend_from_slice(nonce);
+                value.resize((value.len() + 3) & !3, 0);
+                value.extend_from_slice(ciphertext);
+                value.resize((value.len() + 3) & !3, 0);
+                value
+            },
+            Extension::ChecksumComplement(complement) => {
+                let mut value = vec![0; Extension::CHECKSUM_COMPLEMENT_SIZE];
+                BigEndian::write_u16(&mut value[Extension::CHECKSUM_COMPLEMENT_SIZE - 2..], *complement);
+                value
+            },
+            Extension::Unknown(field) => return Ok(field.clone()),
+        };
+        Ok(ExtensionField { field_type: self.field_type(), value })
+    }
+}
+
+//fails if a length inside the field doesn't fit, the length of the whole field is checked when serializing
+impl TryFrom<&Extension> for ExtensionField {
+    type Error = Error;
+
+    fn try_from(extension: &Extension) -> Result<Self, Error> {
+        extension.to_field(false)
+    }
+}
+
+impl TryFrom<Extension> for ExtensionField {
+    type Error = Error;
+
+    fn try_from(extension: Extension) -> Result<Self, Error> {
+        extension.to_field(false)
+    }
+}
+
+//borrowed versions of ExtensionField and Auth, see PacketRef
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct ExtensionFieldRef<'a> {
+    pub field_type: u16,
+    pub value: &'a [u8],
+}
+
+impl From<ExtensionFieldRef<'_>> for ExtensionField {
+    fn from(field: ExtensionFieldRef) -> Self {
+        ExtensionField { field_type: field.field_type, value: field.value.to_vec() }
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct AuthRef<'a> {
+    pub key_indentifier: u32,
+    pub digest: &'a [u8],
+}
+
+//iterates over extension fields on the wire, stops at the first one that doesn't fit
+#[derive(Debug,Clone,Copy)]
+pub struct ExtensionFields<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> ExtensionFields<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+impl<'a> Iterator for ExtensionFields<'a> {
+    type Item = ExtensionFieldRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let length = usize::from(BigEndian::read_u16(self.data.get(2..4)?));
+        if length < Packet::EXT_HEAD_SIZE || length > self.data.len() {
+            self.data = &[];
+            return None;
+        }
+        let field = ExtensionFieldRef {
+            field_type: BigEndian::read_u16(&self.data[..2]),
+            value: &self.data[Packet::EXT_HEAD_SIZE..length],
+        };
+        self.data = &self.data[length..];
+        Some(field)
+    }
+}
+
+//a packet that still points into the datagram it was parsed from, nothing is allocated until to_packet
+#[derive(Debug,Clone,Copy)]
+pub struct PacketRef<'a> {
+    pub leap_indicator: LeapIndicator,
+    pub version: u8,
+    pub mode: Mode,
+    pub stratum: Stratum,
+    pub poll: i8,
+    pub precision: i8,
+    pub root_delay: Short,
+    pub root_dispersion: Short,
+    pub reference_id: [u8;4],
+    pub reference_timestamp: Timestamp,
+    pub origin_timestamp: Timestamp,
+    pub receive_timestamp: Timestamp,
+    pub transit_timestamp: Timestamp,
+    pub extensions: ExtensionFields<'a>,
+    pub auth: Option<AuthRef<'a>>,
+}
+
+impl PacketRef<'_> {
+    pub fn to_packet(&self) -> Packet {
+        let extensions = self.extensions.map(ExtensionField::from).collect::<Vec<_>>();
+        Packet {
+            extensions: if extensions.is_empty() { None } else { Some(extensions) },
+            auth: self.auth.map(|auth| Auth { key_indentifier: auth.key_indentifier, digest: auth.digest.to_vec() }),
+            ..self.header()
+        }
+    }
+
+    //to_packet without the extension fields and the mac, never allocates
+    pub fn header(&self) -> Packet {
+        Packet {
+            leap_indicator: self.leap_indicator,
+            version: self.version,
+            mode: self.mode,
+            stratum: self.stratum,
+            poll: self.poll,
+            precision: self.precision,
+            root_delay: self.root_delay,
+            root_dispersion: self.root_dispersion,
+            reference_id: self.reference_id,
+            reference_timestamp: self.reference_timestamp,
+            origin_timestamp: self.origin_timestamp,
+            receive_timestamp: self.receive_timestamp,
+            transit_timestamp: self.transit_timestamp,
+            extensions: None,
+            auth: None,
+        }
+    }
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize)]
 pub struct Packet {
     pub leap_indicator: LeapIndicator,      //2 bits
     pub version: u8,                        //3 bits
@@ -197,13 +935,16 @@ pub struct Packet {
     pub precision: i8,                      //8 bits
     pub root_delay: Short,                  //32 bits
     pub root_dispersion: Short,             //32 bits
+    #[serde(with = "super::serde_impls::reference_id")]
     pub reference_id: [u8;4],               //4 bytes (server ip address?)
     pub reference_timestamp: Timestamp,     //64 bits?
     pub origin_timestamp: Timestamp,        //64 bits?
     pub receive_timestamp: Timestamp,       //64 bits?
     pub transit_timestamp: Timestamp,       //64 bits?
+    #[serde(default)]
     pub extensions: Option<Vec<ExtensionField>>, //depends
-    pub auth: Option<Auth>                  //32 bits, 128 bits, optional
+    #[serde(default)]
+    pub auth: Option<Auth>                  //32 bits, 0/128/160 bits, optional
 }
 //big endian
 
@@ -211,21 +952,54 @@ impl Packet {
     //TODO: maybe all of this should be moved to the parser
     pub const BASE_SIZE: usize = 48;
     pub const AUTH_SIZE: usize = 20;
+    pub const MAX_AUTH_SIZE: usize = 24;    //sha1
+    pub const CRYPTO_NAK_SIZE: usize = 4;   //key id only
     pub const EXT_HEAD_SIZE: usize = 4;
     pub const MAX_SIZE: usize = 65527; //max udp payload
+    pub const MIN_EXT_SIZE: usize = 16;         //rfc 7822
+    pub const MIN_LAST_EXT_SIZE: usize = 28;    //without a mac after it, so it can't be mistaken for one
+
+    pub fn reference(&self) -> ReferenceId {
+        ReferenceId::interpret(self.stratum, self.reference_id)
+    }
+
+    //offset and delay of a response that arrived at destination, t1 is the origin timestamp
+    //the server echoed, check that it's the one that was sent first
+    pub fn offset_and_delay(&self, destination: Timestamp) -> (TimeDelta, TimeDelta) {
+        let (t1, t2, t3) = (self.origin_timestamp, self.receive_timestamp, self.transit_timestamp);
+        (offset(t1, t2, t3, destination), delay(t1, t2, t3, destination))
+    }
 
     pub fn size(&self) -> usize {
         let mut size = Self::BASE_SIZE; 
-        if self.auth.is_some() {
-            size += Self::AUTH_SIZE;
+        if let Some(auth) = &self.auth {
+            size += Self::CRYPTO_NAK_SIZE + auth.digest.len();
         }
         if let Some(extensions) = &self.extensions {
             for n in extensions {
                 size += Self::EXT_HEAD_SIZE;
-                size += n.value.len();
+                size += n.padded_len();
             }
         }
         size
     }
-}
 
+    pub fn typed_extensions(&self) -> Vec<Extension> {
+        self.extensions.iter().flatten().map(Extension::from).collect()
+    }
+
+    //zero pads the values so every field meets the rfc 7822 minimum sizes
+    //padding changes what the fields cover, so do this before signing or sealing the packet
+    pub fn pad_extensions(&mut self) {
+        let last_size = if self.auth.is_some() { Self::MIN_EXT_SIZE } else { Self::MIN_LAST_EXT_SIZE };
+        if let Some(extensions) = &mut self.extensions {
+            let count = extensions.len();
+            for (n, field) in extensions.iter_mut().enumerate() {
+                let min = if n + 1 == count { last_size } else { Self::MIN_EXT_SIZE };
+                let len = field.padded_len().max(min - Self::EXT_HEAD_SIZE);
+                field.value.resize(len, 0);
+            }
+        }
+    }
+
+}
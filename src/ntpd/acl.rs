@@ -0,0 +1,93 @@
+use std::net::IpAddr;
+use ipnet::IpNet;
+use crate::ntp::constants::KoD;
+use super::response_strategy::{ResponseStrategy,StrategyContext,find_strategy};
+use super::server_config::{self,AclAction};
+
+pub enum Action {
+    Allow,
+    Ignore,
+    KissOfDeath([u8;4]),
+    Strategy(&'static str, Box<dyn ResponseStrategy>),    //name is only used for logs and metrics
+}
+
+impl Action {
+    //for logs and metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Allow => "allow",
+            Action::Ignore => "ignore",
+            Action::KissOfDeath(_) => "kod",
+            Action::Strategy(_, _) => "strategy",
+        }
+    }
+
+    fn from_config(action: AclAction, strategy: Option<&str>, ctx: &StrategyContext) -> std::io::Result<Self> {
+        Ok(match action {
+            AclAction::Allow => Action::Allow,
+            AclAction::Ignore => Action::Ignore,
+            AclAction::Deny => Action::KissOfDeath(KoD::DENY),
+            AclAction::Restrict => Action::KissOfDeath(KoD::RSTR),
+            AclAction::Strategy => {
+                let name = strategy.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                    "acl strategy action requires a strategy name"))?;
+                let ctor = find_strategy(name)
+                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("unknown strategy {}", name)))?;
+                Action::Strategy(ctor.name(), ctor.new_boxed(ctx))
+            },
+        })
+    }
+}
+
+pub struct Rule {
+    pub network: IpNet,
+    pub action: Action,
+}
+
+//rules are checked in order, the first one containing the client address decides what happens to
+//the request, clients not matched by any rule get the default action
+pub struct Acl {
+    pub rules: Vec<Rule>,
+    pub default: Action,
+}
+
+impl Default for Acl {
+    //allows everyone
+    fn default() -> Self {
+        Self { rules: Vec::new(), default: Action::Allow }
+    }
+}
+
+impl Acl {
+    //every strategy rule gets its own instance of the strategy
+    pub fn from_config(config: &server_config::Acl, ctx: &StrategyContext) -> std::io::Result<Self> {
+        let rules = config.rules.iter()
+            .map(|rule| Ok(Rule {
+                network: rule.network,
+                action: Action::from_config(rule.action, rule.strategy.as_deref(), ctx)?,
+            }))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        //the default can't be a strategy, that's what resp_strategy is for
+        let default = Action::from_config(config.default, None, ctx)?;
+        Ok(Self { rules, default })
+    }
+
+    pub fn check(&mut self, client: IpAddr) -> &mut Action {
+        let rule = self.find(client);
+        self.action(rule)
+    }
+
+    //index of the first rule containing the client, none means the default applies
+    pub fn find(&self, client: IpAddr) -> Option<usize> {
+        //ipv4 clients of a dual stack socket show up as ::ffff:a.b.c.d
+        let client = client.to_canonical();
+        self.rules.iter().position(|rule| rule.network.contains(&client))
+    }
+
+    pub fn action(&mut self, rule: Option<usize>) -> &mut Action {
+        match rule {
+            Some(index) => &mut self.rules[index].action,
+            None => &mut self.default,
+        }
+    }
+}
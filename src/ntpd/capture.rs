@@ -0,0 +1,337 @@
+use std::fs::File;
+use std::io::{Read,Write,BufReader,BufWriter};
+use std::net::{IpAddr,Ipv4Addr,Ipv6Addr,SocketAddr};
+use std::path::Path;
+use std::time::{Duration,SystemTime};
+use std::convert::TryInto;
+use byteorder::{BigEndian,LittleEndian,ByteOrder,WriteBytesExt};
+use crate::ntp;
+use std::sync::Arc;
+use super::response_strategy::{ResponseStrategyCtor,StrategyContext,RequestContext,Response};
+use super::random::Randomness;
+use super::clock::{VirtualClock,system_time_to_timestamp};
+
+//classic libpcap format, see https://wiki.wireshark.org/Development/LibpcapFileFormat
+//pcapng is not supported, wireshark and tcpdump can convert between the two
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const PCAP_VERSION: (u16, u16) = (2, 4);
+const PCAP_HEADER_SIZE: usize = 24;
+const RECORD_HEADER_SIZE: usize = 16;
+const SNAPLEN: u32 = 65535;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const IPPROTO_UDP: u8 = 17;
+const UDP_HEADER_SIZE: usize = 8;
+
+//udp datagram read from or written to a capture
+#[derive(Debug,Clone)]
+pub struct UdpRecord {
+    pub time: Duration, //since unix epoch
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+//writes udp datagrams as raw ip packets (LINKTYPE_RAW), headers are made up from the socket addresses
+pub struct PcapWriter<W: Write> {
+    out: W,
+}
+
+impl PcapWriter<BufWriter<File>> {
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> PcapWriter<W> {
+    pub fn new(mut out: W) -> std::io::Result<Self> {
+        out.write_u32::<LittleEndian>(PCAP_MAGIC)?;
+        out.write_u16::<LittleEndian>(PCAP_VERSION.0)?;
+        out.write_u16::<LittleEndian>(PCAP_VERSION.1)?;
+        out.write_i32::<LittleEndian>(0)?;  //thiszone
+        out.write_u32::<LittleEndian>(0)?;  //sigfigs
+        out.write_u32::<LittleEndian>(SNAPLEN)?;
+        out.write_u32::<LittleEndian>(LINKTYPE_RAW)?;
+        out.flush()?;
+        Ok(Self { out })
+    }
+
+    //flushed after every record so that the capture is usable even if the server gets killed
+    pub fn write_udp(&mut self, time: SystemTime, src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> std::io::Result<()> {
+        let time = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        let packet = ip_packet(src, dst, payload)?;
+        let len = packet.len() as u32;
+
+        self.out.write_u32::<LittleEndian>(time.as_secs() as u32)?;
+        self.out.write_u32::<LittleEndian>(time.subsec_micros())?;
+        self.out.write_u32::<LittleEndian>(len.min(SNAPLEN))?;
+        self.out.write_u32::<LittleEndian>(len)?;
+        self.out.write_all(&packet[..packet.len().min(SNAPLEN as usize)])?;
+        self.out.flush()
+    }
+}
+
+fn checksum(chunks: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for chunk in chunks {
+        for pair in chunk.chunks(2) {
+            let word = if pair.len() == 2 { BigEndian::read_u16(pair) } else { u16::from(pair[0]) << 8 };
+            sum += u32::from(word);
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+//ipv4 addresses are mapped to ipv6 if the other side is ipv6 (dual stack sockets)
+fn ip_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> std::io::Result<Vec<u8>> {
+    let udp_len: u16 = (payload.len() + UDP_HEADER_SIZE).try_into()
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "payload too large"))?;
+
+    let mut udp = Vec::with_capacity(usize::from(udp_len));
+    udp.write_u16::<BigEndian>(src.port())?;
+    udp.write_u16::<BigEndian>(dst.port())?;
+    udp.write_u16::<BigEndian>(udp_len)?;
+    udp.write_u16::<BigEndian>(0)?; //checksum, filled in below
+    udp.write_all(payload)?;
+
+    let mut packet = Vec::new();
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let total_len: u16 = (udp.len() + 20).try_into()
+                .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "payload too large"))?;
+            let pseudo = [&src_ip.octets()[..], &dst_ip.octets()[..], &[0, IPPROTO_UDP], &udp_len.to_be_bytes()];
+            let udp_checksum = checksum(&[&pseudo.concat(), &udp]);
+            BigEndian::write_u16(&mut udp[6..8], if udp_checksum == 0 { 0xffff } else { udp_checksum });
+
+            packet.write_u8(0x45)?;     //version 4, 5 words of header
+            packet.write_u8(0)?;        //dscp, ecn
+            packet.write_u16::<BigEndian>(total_len)?;
+            packet.write_u16::<BigEndian>(0)?;  //identification
+            packet.write_u16::<BigEndian>(0x4000)?; //don't fragment
+            packet.write_u8(64)?;       //ttl
+            packet.write_u8(IPPROTO_UDP)?;
+            packet.write_u16::<BigEndian>(0)?;  //header checksum, filled in below
+            packet.write_all(&src_ip.octets())?;
+            packet.write_all(&dst_ip.octets())?;
+            let header_checksum = checksum(&[&packet]);
+            BigEndian::write_u16(&mut packet[10..12], header_checksum);
+        },
+        (src_ip, dst_ip) => {
+            let src_ip = to_ipv6(src_ip);
+            let dst_ip = to_ipv6(dst_ip);
+            let pseudo = [&src_ip.octets()[..], &dst_ip.octets()[..], &u32::from(udp_len).to_be_bytes(), &[0, 0, 0, IPPROTO_UDP]];
+            let udp_checksum = checksum(&[&pseudo.concat(), &udp]);
+            BigEndian::write_u16(&mut udp[6..8], if udp_checksum == 0 { 0xffff } else { udp_checksum });
+
+            packet.write_u32::<BigEndian>(6 << 28)?;    //version 6, no traffic class or flow label
+            packet.write_u16::<BigEndian>(udp_len)?;
+            packet.write_u8(IPPROTO_UDP)?;
+            packet.write_u8(64)?;       //hop limit
+            packet.write_all(&src_ip.octets())?;
+            packet.write_all(&dst_ip.octets())?;
+        }
+    }
+    packet.write_all(&udp)?;
+    Ok(packet)
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+//reads udp datagrams from a capture, everything that is not udp over ip is skipped
+pub struct PcapReader<R: Read> {
+    input: R,
+    big_endian: bool,
+    nanos: bool,
+    snaplen: u32,
+    linktype: u32,
+}
+
+impl PcapReader<BufReader<File>> {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut input: R) -> std::io::Result<Self> {
+        let mut header = [0u8; PCAP_HEADER_SIZE];
+        input.read_exact(&mut header)?;
+
+        let (big_endian, nanos) = match (LittleEndian::read_u32(&header[0..4]), BigEndian::read_u32(&header[0..4])) {
+            (PCAP_MAGIC, _) => (false, false),
+            (PCAP_MAGIC_NANOS, _) => (false, true),
+            (_, PCAP_MAGIC) => (true, false),
+            (_, PCAP_MAGIC_NANOS) => (true, true),
+            _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "not a pcap file (pcapng is not supported)")),
+        };
+
+        let mut reader = Self { input, big_endian, nanos, snaplen: 0, linktype: 0 };
+        reader.snaplen = reader.read_u32(&header[16..20]);
+        reader.linktype = reader.read_u32(&header[20..24]);
+        match reader.linktype {
+            LINKTYPE_NULL | LINKTYPE_ETHERNET | LINKTYPE_RAW | LINKTYPE_LINUX_SLL => Ok(reader),
+            other => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("unsupported link type {}", other))),
+        }
+    }
+
+    fn read_u32(&self, data: &[u8]) -> u32 {
+        if self.big_endian { BigEndian::read_u32(data) } else { LittleEndian::read_u32(data) }
+    }
+
+    //Ok(None) on a clean end of file
+    fn next_record(&mut self) -> std::io::Result<Option<(Duration, Vec<u8>)>> {
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        match self.input.read_exact(&mut header) {
+            Ok(()) => (),
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+
+        let seconds = self.read_u32(&header[0..4]);
+        let subseconds = self.read_u32(&header[4..8]);
+        let time = Duration::from_secs(seconds.into())
+            + if self.nanos { Duration::from_nanos(subseconds.into()) } else { Duration::from_micros(subseconds.into()) };
+
+        //the length comes from the file, a corrupt one shouldn't make us allocate gigabytes
+        let len = self.read_u32(&header[8..12]);
+        if len > self.snaplen.min(SNAPLEN) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                format!("record of {} bytes is longer than the snapshot length", len)));
+        }
+        let mut data = vec![0u8; len as usize];
+        self.input.read_exact(&mut data)?;
+        Ok(Some((time, data)))
+    }
+}
+
+impl<R: Read> Iterator for PcapReader<R> {
+    type Item = std::io::Result<UdpRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (time, data) = match self.next_record() {
+                Ok(Some(record)) => record,
+                Ok(None) => return None,
+                Err(err) => return Some(Err(err)),
+            };
+
+            if let Some((src, dst, payload)) = link_payload(self.linktype, &data).and_then(udp_datagram) {
+                return Some(Ok(UdpRecord { time, src, dst, payload: payload.to_vec() }));
+            }
+        }
+    }
+}
+
+//strips the link layer header, returns the ip packet
+fn link_payload(linktype: u32, data: &[u8]) -> Option<&[u8]> {
+    match linktype {
+        LINKTYPE_RAW => Some(data),
+        LINKTYPE_NULL => data.get(4..),
+        LINKTYPE_LINUX_SLL => ethertype_payload(BigEndian::read_u16(data.get(14..16)?), data.get(16..)?),
+        LINKTYPE_ETHERNET => ethertype_payload(BigEndian::read_u16(data.get(12..14)?), data.get(14..)?),
+        _ => None,
+    }
+}
+
+fn ethertype_payload(ethertype: u16, data: &[u8]) -> Option<&[u8]> {
+    match ethertype {
+        ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => Some(data),
+        ETHERTYPE_VLAN => ethertype_payload(BigEndian::read_u16(data.get(2..4)?), data.get(4..)?),
+        _ => None,
+    }
+}
+
+//fragmented packets and ipv6 extension headers are not supported, ntp packets rarely need them
+fn udp_datagram(packet: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let (src, dst, udp) = match packet.first()? >> 4 {
+        4 => {
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            let total_len = usize::from(BigEndian::read_u16(packet.get(2..4)?));
+            if *packet.get(9)? != IPPROTO_UDP || BigEndian::read_u16(packet.get(6..8)?) & 0x3fff != 0 {
+                return None;
+            }
+            let src: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+            (IpAddr::from(Ipv4Addr::from(src)), IpAddr::from(Ipv4Addr::from(dst)), packet.get(header_len..total_len)?)
+        },
+        6 => {
+            let payload_len = usize::from(BigEndian::read_u16(packet.get(4..6)?));
+            if *packet.get(6)? != IPPROTO_UDP {
+                return None;
+            }
+            let src: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+            (IpAddr::from(Ipv6Addr::from(src)), IpAddr::from(Ipv6Addr::from(dst)), packet.get(40..40+payload_len)?)
+        },
+        _ => return None,
+    };
+
+    let src_port = BigEndian::read_u16(udp.get(0..2)?);
+    let dst_port = BigEndian::read_u16(udp.get(2..4)?);
+    let len = usize::from(BigEndian::read_u16(udp.get(4..6)?));
+    Some((SocketAddr::new(src, src_port), SocketAddr::new(dst, dst_port), udp.get(UDP_HEADER_SIZE..len)?))
+}
+
+fn format_time(time: Duration) -> String {
+    let naive = chrono::NaiveDateTime::from_timestamp(time.as_secs() as i64, time.subsec_nanos());
+    chrono::DateTime::<chrono::Utc>::from_utc(naive, chrono::Utc).to_rfc3339_opts(chrono::SecondsFormat::Micros, true)
+}
+
+//feeds every datagram sent to port in the capture into the strategy and writes what it would respond with to out
+//short packets are padded with zeros, just like the server does
+//the strategy gets a virtual clock that is set to the capture time of each request
+pub fn replay(path: &Path, port: u16, ctor: &dyn ResponseStrategyCtor, seed: u64, out: &mut dyn Write) -> std::io::Result<()> {
+    let clock = Arc::new(VirtualClock::new(0.into()));
+    let mut strategy = ctor.new_boxed(&StrategyContext { clock: clock.clone(), ..Default::default() });
+    let mut randomness = Randomness::new(seed);
+
+    for record in PcapReader::open(path)? {
+        let record = record?;
+        if record.dst.port() != port {
+            continue;
+        }
+        clock.set(system_time_to_timestamp(SystemTime::UNIX_EPOCH + record.time));
+
+        writeln!(out, "request from {} to {} at {}", record.src, record.dst, format_time(record.time))?;
+
+        let packet = match ntp::types::PacketRef::parse_with(&record.payload, ntp::parser::ParseMode::Lenient) {
+            Ok((packet, anomalies)) => {
+                for anomaly in anomalies {
+                    writeln!(out, "  parse anomaly: {}", anomaly)?;
+                }
+                packet
+            },
+            Err(err) => { writeln!(out, "  parse error: {}, data: {:x?}", err, record.payload)?; continue; },
+        };
+        writeln!(out, "  request: {:?}", packet.to_packet())?;
+
+        let mut req = RequestContext { client: record.src, rng: randomness.for_client(record.src.ip()), nts: None };
+        match strategy.process_packet(&packet, &mut req) {
+            Ok(Response::Packet(packet)) => writeln!(out, "  response: {:?}", packet)?,
+            Ok(Response::Multiple(packets)) => for packet in packets {
+                writeln!(out, "  response: {:?}", packet)?;
+            },
+            Ok(Response::Raw(data)) => writeln!(out, "  raw response: {:x?}", data)?,
+            Ok(Response::Nothing) => writeln!(out, "  no response")?,
+            Err(err) => writeln!(out, "  strategy error: {}", err)?,
+        }
+    }
+
+    Ok(())
+}
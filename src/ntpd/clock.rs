@@ -0,0 +1,111 @@
+use std::convert::TryFrom;
+use std::sync::{Arc,Mutex};
+use std::time::{Duration,Instant,SystemTime};
+use crate::ntp::types::{Timestamp,Fixed};
+use super::server_config::{self,ClockKind};
+
+//source of "real" time for strategies and the server
+//everything that wants to know the current time should ask the clock instead of the system, so
+//that strategies can be tested deterministically and scenarios can run in simulated time
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Timestamp;
+
+    fn system_time(&self) -> SystemTime {
+        timestamp_to_system_time(self.now())
+    }
+}
+
+//timestamps wrap around at the end of the era (2036), just like the ones on the wire
+pub fn system_time_to_timestamp(time: SystemTime) -> Timestamp {
+    Timestamp::from_system_time(time)
+}
+
+//assumes era 0
+pub fn timestamp_to_system_time(timestamp: Timestamp) -> SystemTime {
+    timestamp.to_system_time()
+}
+
+//rounded to the nearest 2^-32, wraps around at the end of the era
+//panics for durations close to 2^63 seconds, those don't fit in Fixed
+fn add_duration(timestamp: Timestamp, duration: Duration) -> Timestamp {
+    let duration = Fixed::try_from(duration).expect("duration out of range");
+    (Fixed::from_timestamp(timestamp, 0) + duration).to_timestamp()
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        system_time_to_timestamp(SystemTime::now())
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+//starts at a given time and advances with the monotonic clock, rate times faster than real time
+//not affected by changes of the system time, a rate above 1 runs scenarios in accelerated time
+pub struct MonotonicClock {
+    start: Timestamp,
+    started: Instant,
+    rate: f64,
+}
+
+impl MonotonicClock {
+    pub fn new(start: Timestamp, rate: f64) -> Self {
+        Self { start, started: Instant::now(), rate }
+    }
+
+    //starts at the current system time and runs at real time speed
+    pub fn from_system_time() -> Self {
+        Self::new(SystemClock.now(), 1.0)
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Timestamp {
+        add_duration(self.start, self.started.elapsed().mul_f64(self.rate))
+    }
+}
+
+//only moves when told to
+pub struct VirtualClock {
+    now: Mutex<Timestamp>,
+}
+
+impl VirtualClock {
+    pub fn new(start: Timestamp) -> Self {
+        Self { now: Mutex::new(start) }
+    }
+
+    pub fn set(&self, now: Timestamp) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now = add_duration(*now, duration);
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Timestamp {
+        *self.now.lock().unwrap()
+    }
+}
+
+pub fn from_config(config: &server_config::Clock) -> std::io::Result<Arc<dyn Clock>> {
+    Ok(match config.kind {
+        ClockKind::System => Arc::new(SystemClock),
+        ClockKind::Monotonic => {
+            let start = match &config.start {
+                Some(start) => chrono::DateTime::parse_from_rfc3339(start)
+                    .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))
+                    .map(|start| system_time_to_timestamp(start.into()))?,
+                None => SystemClock.now(),
+            };
+            Arc::new(MonotonicClock::new(start, config.rate))
+        },
+    })
+}
@@ -0,0 +1,270 @@
+use std::net::{SocketAddr,ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::thread::JoinHandle;
+use super::server::{Server,Stats};
+use super::server_config::ResponseVersion;
+use super::response_strategy::{ResponseStrategy,StrategyContext,find_strategy};
+use super::clock::Clock;
+use super::random::Randomness;
+use super::metrics::Metrics;
+use super::capture::PcapWriter;
+use super::rate_limit::RateLimiter;
+use super::acl::Acl;
+use super::nts::{NtsKe,Certificate};
+use crate::ntp::auth::Keys;
+use crate::ntp::parser::ParseMode;
+use crate::ntp::nts::CookieKey;
+
+//runs the server on a background thread, meant for tests that need a misbehaving ntp server
+//
+//let server = ChaosServer::builder()
+//    .strategy_name("transit_timestamp")
+//    .bind("127.0.0.1:0")
+//    .spawn()?;
+//let port = server.port();
+//...
+//let stats = server.shutdown()?;
+pub struct ChaosServer;
+
+impl ChaosServer {
+    pub fn builder() -> ChaosServerBuilder {
+        ChaosServerBuilder::default()
+    }
+}
+
+enum StrategyChoice {
+    Named(String),
+    Custom(Box<dyn ResponseStrategy>),
+}
+
+#[derive(Default)]
+pub struct ChaosServerBuilder {
+    strategy: Option<StrategyChoice>,
+    clock: Option<Arc<dyn Clock>>,
+    seed: Option<u64>,
+    bind: Option<std::io::Result<SocketAddr>>,
+    log_all_requests: bool,
+    parse_mode: ParseMode,
+    response_version: ResponseVersion,
+    capture: Option<PathBuf>,
+    rate_limiter: Option<RateLimiter>,
+    acl: Option<Acl>,
+    keys: Keys,
+    require_auth: bool,
+    nts: bool,
+}
+
+impl ChaosServerBuilder {
+    pub fn strategy<S: ResponseStrategy + 'static>(self, strategy: S) -> Self {
+        self.strategy_boxed(Box::new(strategy))
+    }
+
+    pub fn strategy_boxed(mut self, strategy: Box<dyn ResponseStrategy>) -> Self {
+        self.strategy = Some(StrategyChoice::Custom(strategy));
+        self
+    }
+
+    //one of the strategies registered with inventory, spawn fails if there is no such strategy
+    pub fn strategy_name(mut self, name: &str) -> Self {
+        self.strategy = Some(StrategyChoice::Named(name.to_string()));
+        self
+    }
+
+    //used by the server and passed to strategies created with strategy_name, defaults to the system clock
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    //random seed if not set, see Randomness
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    //defaults to 127.0.0.1:0, use port 0 to get a random free port
+    pub fn bind<A: ToSocketAddrs>(mut self, addr: A) -> Self {
+        self.bind = Some(addr.to_socket_addrs().and_then(|mut addrs| addrs.next()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::AddrNotAvailable, "address did not resolve"))));
+        self
+    }
+
+    pub fn log_all_requests(mut self, log_all_requests: bool) -> Self {
+        self.log_all_requests = log_all_requests;
+        self
+    }
+
+    //lenient if not set
+    pub fn parse_mode(mut self, parse_mode: ParseMode) -> Self {
+        self.parse_mode = parse_mode;
+        self
+    }
+
+    //mirrors the request if not set, spawn fails if a fixed version doesn't fit in 3 bits
+    pub fn response_version(mut self, response_version: ResponseVersion) -> Self {
+        self.response_version = response_version;
+        self
+    }
+
+    pub fn capture<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.capture = Some(path.into());
+        self
+    }
+
+    //no rate limiting if not set
+    pub fn rate_limit(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    //allows everyone if not set
+    pub fn acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    //keys for verifying requests and signing responses, also passed to strategies created with strategy_name
+    pub fn keys(mut self, keys: Keys) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    pub fn require_auth(mut self, require_auth: bool) -> Self {
+        self.require_auth = require_auth;
+        self
+    }
+
+    //also runs an nts-ke server with a self-signed certificate for localhost on the same ip, see ServerHandle::nts_ke_addr
+    pub fn nts(mut self, nts: bool) -> Self {
+        self.nts = nts;
+        self
+    }
+
+    //binds the socket before returning, requests sent after spawn returns will be answered
+    pub fn spawn(self) -> std::io::Result<ServerHandle> {
+        let addr = self.bind.unwrap_or_else(|| Ok(SocketAddr::from(([127, 0, 0, 1], 0))))?;
+        self.response_version.check().map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        let ctx = StrategyContext {
+            clock: self.clock.unwrap_or_else(|| StrategyContext::default().clock),
+            keys: Arc::new(self.keys),
+            nts: if self.nts { Some(Arc::new(CookieKey::random(&mut rand::rngs::OsRng))) } else { None },
+        };
+        let (strategy_name, strategy) = match self.strategy.unwrap_or_else(|| StrategyChoice::Named("current_time".to_string())) {
+            StrategyChoice::Custom(strategy) => ("custom", strategy),
+            StrategyChoice::Named(name) => {
+                let ctor = find_strategy(&name)
+                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("unknown strategy {}", name)))?;
+                (ctor.name(), ctor.new_boxed(&ctx))
+            },
+        };
+        let capture = match self.capture {
+            Some(path) => Some(PcapWriter::create(&path)?),
+            None => None,
+        };
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let nts = match &ctx.nts {
+            Some(cookie_key) => {
+                let certificate = Certificate::self_signed("localhost")?;
+                let der = certificate.chain[0].to_vec();
+                let ke = Arc::new(NtsKe::new(certificate, cookie_key.clone())?);
+                let listener = NtsKe::bind(SocketAddr::new(addr.ip(), 0))?;
+                let ke_addr = listener.local_addr()?;
+                let ke_shutdown = shutdown.clone();
+                let thread = std::thread::Builder::new()
+                    .name(format!("nts-ke {}", ke_addr))
+                    .spawn(move || ke.serve(listener, ke_shutdown))?;
+                Some(NtsHandle { addr: ke_addr, certificate: der, thread: Some(thread) })
+            },
+            None => None,
+        };
+
+        let mut server = Server {
+            port: addr.port(),
+            addr: addr.ip(),
+            log_all_requests: self.log_all_requests,
+            parse_mode: self.parse_mode,
+            response_version: self.response_version,
+            response_strategy: strategy,
+            response_strategy_name: strategy_name,
+            metrics: Metrics::new().map_err(std::io::Error::other)?,
+            capture,
+            clock: ctx.clock.clone(),
+            randomness: self.seed.map(Randomness::new).unwrap_or_else(Randomness::from_entropy),
+            rate_limiter: self.rate_limiter,
+            acl: self.acl.unwrap_or_default(),
+            keys: ctx.keys.clone(),
+            require_auth: self.require_auth,
+            nts: ctx.nts.clone(),
+            shutdown: shutdown.clone(),
+            stats: Stats::default(),
+        };
+
+        let socket = server.bind()?;
+        let local_addr = socket.local_addr()?;
+        let thread = std::thread::Builder::new()
+            .name(format!("chaos-ntpd {}", local_addr))
+            .spawn(move || server.serve(socket).map(|_| server.stats))?;
+
+        Ok(ServerHandle { local_addr, shutdown, thread: Some(thread), nts })
+    }
+}
+
+//the server is shut down when the handle is dropped
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<std::io::Result<Stats>>>,
+    nts: Option<NtsHandle>,
+}
+
+struct NtsHandle {
+    addr: SocketAddr,
+    certificate: Vec<u8>,
+    thread: Option<JoinHandle<std::io::Result<()>>>,
+}
+
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn port(&self) -> u16 {
+        self.local_addr.port()
+    }
+
+    //None if the server was built without nts
+    pub fn nts_ke_addr(&self) -> Option<SocketAddr> {
+        self.nts.as_ref().map(|nts| nts.addr)
+    }
+
+    //der encoded, for clients that need to trust the self-signed certificate
+    pub fn nts_certificate(&self) -> Option<&[u8]> {
+        self.nts.as_ref().map(|nts| &nts.certificate[..])
+    }
+
+    //waits for the server thread to finish
+    pub fn shutdown(mut self) -> std::io::Result<Stats> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> std::io::Result<Stats> {
+        self.shutdown.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.nts.as_mut().and_then(|nts| nts.thread.take()) {
+            thread.join().map_err(|_| std::io::Error::other("nts-ke thread panicked"))??;
+        }
+        match self.thread.take() {
+            Some(thread) => thread.join()
+                .map_err(|_| std::io::Error::other("server thread panicked"))?,
+            None => Ok(Stats::default()),
+        }
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.stop().ok();
+    }
+}
@@ -0,0 +1,116 @@
+use std::fs::{File,OpenOptions};
+use std::io::Write;
+use std::path::{Path,PathBuf};
+use slog::{o,Drain};
+use super::server_config::{Log,LogFormat};
+
+//keeps the global logger alive, dropping it flushes whatever is still queued in the async drain
+pub struct LoggerGuard {
+    _scope_guard: slog_scope::GlobalLoggerGuard,
+    _async_guard: slog_async::AsyncGuard,
+}
+
+//file that gets renamed to path.1 (path.1 to path.2 and so on) once it grows over max_size bytes
+//rotation only happens at line boundaries, drains write single records in many small chunks
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    line_start: bool,
+    max_size: u64,
+    max_files: usize,   //rotated files to keep, 0 means the log is truncated instead
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_size: u64, max_files: usize) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path: path.to_path_buf(), file, size, line_start: true, max_size, max_files })
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        if self.max_files > 0 {
+            for n in (1..self.max_files).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    std::fs::rename(from, self.rotated_path(n+1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.max_size > 0 && self.line_start && self.size >= self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        if written > 0 {
+            self.line_start = buf[written-1] == b'\n';
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn output(config: &Log) -> std::io::Result<Box<dyn Write + Send>> {
+    Ok(match &config.file {
+        Some(path) => Box::new(RotatingFile::open(path, config.max_file_size, config.max_files)?),
+        None => Box::new(std::io::stdout()),
+    })
+}
+
+fn setup_async<D>(drain: D, config: &Log) -> LoggerGuard
+    where D: Drain<Ok = (), Err = slog::Never> + Send + 'static {
+    let (drain, async_guard) = slog_async::Async::new(drain)
+        .build_with_guard();
+    let drain = drain
+        .filter_level(config.level)
+        .fuse();
+    let logger = slog::Logger::root(drain, o!());
+
+    LoggerGuard {
+        _scope_guard: slog_scope::set_global_logger(logger),
+        _async_guard: async_guard,
+    }
+}
+
+pub fn setup_logger(config: &Log) -> std::io::Result<LoggerGuard> {
+    Ok(match (config.format, &config.file) {
+        (LogFormat::Json, _) => {
+            let drain = slog_json::Json::new(output(config)?)
+                .add_default_keys()
+                .build().fuse();
+            setup_async(std::sync::Mutex::new(drain).fuse(), config)
+        },
+        (LogFormat::Term, None) => {
+            let decorator = slog_term::TermDecorator::new().build();
+            //TODO: use a custom header when they finally publish the new package version with this
+            //.use_custom_header_print(|timestamp, rd, record, use_file_location| {
+            //})
+            let drain = slog_term::FullFormat::new(decorator).build().fuse();
+            setup_async(drain, config)
+        },
+        (LogFormat::Term, Some(_)) => {
+            let decorator = slog_term::PlainDecorator::new(output(config)?);
+            let drain = slog_term::FullFormat::new(decorator).build().fuse();
+            setup_async(drain, config)
+        },
+    })
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::net::{SocketAddr,IpAddr};
+use ipnet::IpNet;
+use prometheus::{Registry,IntCounter,IntCounterVec,Histogram,HistogramOpts,Opts,Encoder,TextEncoder};
+use slog_scope::{error,info};
+
+//offsets applied by chaos strategies can be anything from milliseconds to decades
+const OFFSET_BUCKETS: &[f64] = &[
+    -3_153_600_000.0, -31_536_000.0, -86_400.0, -3_600.0, -60.0, -1.0, -0.1, -0.01, -0.001,
+    0.0, 0.001, 0.01, 0.1, 1.0, 60.0, 3_600.0, 86_400.0, 31_536_000.0, 3_153_600_000.0,
+];
+
+//clients are counted by prefix, spoofed source addresses would add a time series each otherwise
+const CLIENT_PREFIX_V4: u8 = 24;
+const CLIENT_PREFIX_V6: u8 = 64;
+//prefixes past this many are all counted under "other"
+const MAX_CLIENT_PREFIXES: usize = 1024;
+
+pub struct Metrics {
+    pub registry: Registry,
+    pub requests_per_client: IntCounterVec,
+    client_counters: HashMap<IpNet, IntCounter>,    //so the label is only formatted once per prefix
+    other_clients: Option<IntCounter>,
+    pub requests_per_mode: IntCounterVec,
+    pub requests_per_strategy: IntCounterVec,
+    pub requests_per_acl_action: IntCounterVec,
+    pub parse_errors: IntCounter,
+    pub parse_anomalies: IntCounterVec,
+    pub strategy_errors: IntCounter,
+    pub serialization_errors: IntCounter,
+    pub responses_dropped: IntCounter,
+    pub rate_limited: IntCounter,
+    pub auth_failures: IntCounter,
+    pub applied_offset: Histogram, //seconds between the transmit timestamp of a response and the real time
+}
+
+impl Metrics {
+    pub fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("chaos_ntpd".to_string()), None)?;
+
+        let metrics = Metrics {
+            requests_per_client: IntCounterVec::new(
+                Opts::new("requests_per_client_total", "requests received, by client /24 or /64 prefix"), &["client"])?,
+            client_counters: HashMap::new(),
+            other_clients: None,
+            requests_per_mode: IntCounterVec::new(
+                Opts::new("requests_per_mode_total", "requests received, by ntp mode"), &["mode"])?,
+            requests_per_strategy: IntCounterVec::new(
+                Opts::new("requests_per_strategy_total", "requests passed to a response strategy"), &["strategy"])?,
+            requests_per_acl_action: IntCounterVec::new(
+                Opts::new("requests_per_acl_action_total", "requests received, by the acl action applied to them"), &["action"])?,
+            parse_errors: IntCounter::new("parse_errors_total", "requests that could not be parsed")?,
+            parse_anomalies: IntCounterVec::new(
+                Opts::new("parse_anomalies_total", "requests parsed despite something being off, by anomaly"), &["anomaly"])?,
+            strategy_errors: IntCounter::new("strategy_errors_total", "requests a response strategy failed on")?,
+            serialization_errors: IntCounter::new("serialization_errors_total", "responses that could not be serialized")?,
+            responses_dropped: IntCounter::new("responses_dropped_total", "requests deliberately left without a response")?,
+            rate_limited: IntCounter::new("rate_limited_total", "requests over the per-client rate limit")?,
+            auth_failures: IntCounter::new("auth_failures_total", "requests with a missing or invalid mac")?,
+            applied_offset: Histogram::with_opts(
+                HistogramOpts::new("applied_offset_seconds", "offset of sent transmit timestamps from the real time")
+                    .buckets(OFFSET_BUCKETS.to_vec()))?,
+            registry,
+        };
+
+        metrics.registry.register(Box::new(metrics.requests_per_client.clone()))?;
+        metrics.registry.register(Box::new(metrics.requests_per_mode.clone()))?;
+        metrics.registry.register(Box::new(metrics.requests_per_strategy.clone()))?;
+        metrics.registry.register(Box::new(metrics.requests_per_acl_action.clone()))?;
+        metrics.registry.register(Box::new(metrics.parse_errors.clone()))?;
+        metrics.registry.register(Box::new(metrics.parse_anomalies.clone()))?;
+        metrics.registry.register(Box::new(metrics.strategy_errors.clone()))?;
+        metrics.registry.register(Box::new(metrics.serialization_errors.clone()))?;
+        metrics.registry.register(Box::new(metrics.responses_dropped.clone()))?;
+        metrics.registry.register(Box::new(metrics.rate_limited.clone()))?;
+        metrics.registry.register(Box::new(metrics.auth_failures.clone()))?;
+        metrics.registry.register(Box::new(metrics.applied_offset.clone()))?;
+
+        Ok(metrics)
+    }
+
+    pub fn count_client(&mut self, ip: IpAddr) {
+        let prefix = match ip {
+            IpAddr::V4(_) => CLIENT_PREFIX_V4,
+            IpAddr::V6(_) => CLIENT_PREFIX_V6,
+        };
+        let prefix = IpNet::new(ip, prefix).expect("prefix fits the address").trunc();
+        if let Some(counter) = self.client_counters.get(&prefix) {
+            return counter.inc();
+        }
+        if self.client_counters.len() < MAX_CLIENT_PREFIXES {
+            let counter = self.requests_per_client.with_label_values(&[&prefix.to_string()]);
+            counter.inc();
+            self.client_counters.insert(prefix, counter);
+            return;
+        }
+        let requests_per_client = &self.requests_per_client;
+        self.other_clients.get_or_insert_with(|| requests_per_client.with_label_values(&["other"])).inc();
+    }
+
+    //serves the registry on http://addr/metrics from a background thread
+    pub fn serve(&self, addr: SocketAddr) -> std::io::Result<()> {
+        let http = tiny_http::Server::http(addr).map_err(std::io::Error::other)?;
+        let registry = self.registry.clone();
+
+        info!("metrics endpoint started on http://{:}/metrics", addr);
+
+        std::thread::spawn(move || {
+            for request in http.incoming_requests() {
+                let response = if request.url() == "/metrics" {
+                    let encoder = TextEncoder::new();
+                    let mut data = Vec::new();
+                    if let Err(err) = encoder.encode(&registry.gather(), &mut data) {
+                        error!("metrics encoding error: {}", err);
+                    }
+                    let header = tiny_http::Header::from_bytes(&b"Content-Type"[..], encoder.format_type().as_bytes())
+                        .expect("static header is valid");
+                    tiny_http::Response::from_data(data).with_header(header)
+                } else {
+                    tiny_http::Response::from_data(b"not found".to_vec()).with_status_code(404)
+                };
+
+                if let Err(err) = request.respond(response) {
+                    error!("metrics endpoint error: {}", err);
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
@@ -0,0 +1,17 @@
+pub mod server;
+pub mod response_strategy;
+pub mod logger;
+pub mod server_config;
+pub mod metrics;
+pub mod capture;
+pub mod embedded;
+pub mod clock;
+pub mod random;
+pub mod rate_limit;
+pub mod acl;
+pub mod nts;
+
+pub use embedded::{ChaosServer,ChaosServerBuilder,ServerHandle};
+
+#[cfg(test)]
+pub mod tests;
@@ -0,0 +1,226 @@
+use std::io::{Read,Write};
+use std::net::{SocketAddr,TcpListener,TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+use std::time::Duration;
+use rustls::pki_types::{CertificateDer,PrivateKeyDer,pem::PemObject};
+use slog_scope::{error,info};
+use crate::ntp::nts::{self,Record,RecordType,KeError,CookieKey,SessionKeys};
+use super::server_config;
+
+//nts key establishment server, rfc 8915 section 4
+//every connection gets its own thread, a client has KE_TIMEOUT to send its request
+//connections over NtsKe::MAX_SESSIONS are closed right away instead of getting a thread
+
+const KE_TIMEOUT: Duration = Duration::from_secs(5);
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+
+fn io_error<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+}
+
+pub struct Certificate {
+    pub chain: Vec<CertificateDer<'static>>,
+    pub key: PrivateKeyDer<'static>,
+    pub pem: Option<String>,    //self-signed only, for clients that have to be told to trust it
+}
+
+impl Certificate {
+    pub fn self_signed(hostname: &str) -> std::io::Result<Self> {
+        let generated = rcgen::generate_simple_self_signed(vec![hostname.to_string()]).map_err(io_error)?;
+        Ok(Self {
+            chain: vec![generated.cert.der().clone()],
+            key: PrivateKeyDer::Pkcs8(generated.key_pair.serialize_der().into()),
+            pem: Some(generated.cert.pem()),
+        })
+    }
+
+    pub fn load(certificate: &Path, key: &Path) -> std::io::Result<Self> {
+        let chain = CertificateDer::pem_file_iter(certificate).map_err(io_error)?
+            .collect::<Result<Vec<_>, _>>().map_err(io_error)?;
+        let key = PrivateKeyDer::from_pem_file(key).map_err(io_error)?;
+        Ok(Self { chain, key, pem: None })
+    }
+
+    //self-signed if the config has no certificate, written to export_certificate if that's set
+    pub fn from_config(config: &server_config::Nts) -> std::io::Result<Self> {
+        match (&config.certificate, &config.private_key) {
+            (Some(certificate), Some(key)) => Self::load(certificate, key),
+            (None, None) => {
+                let certificate = Self::self_signed(&config.hostname)?;
+                if let (Some(path), Some(pem)) = (&config.export_certificate, &certificate.pem) {
+                    std::fs::write(path, pem)?;
+                    info!("self-signed nts-ke certificate written to {}", path.display());
+                }
+                Ok(certificate)
+            },
+            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                "nts certificate and private_key have to be set together")),
+        }
+    }
+}
+
+pub struct NtsKe {
+    tls: Arc<rustls::ServerConfig>,
+    cookie_key: Arc<CookieKey>,
+    ntp_server: Option<String>, //sent to clients if set, they use the ke server otherwise
+    ntp_port: Option<u16>,
+    sessions: AtomicUsize,
+}
+
+//one per session thread, gives the slot back even if the session panics
+struct Session(Arc<NtsKe>);
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.0.sessions.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl NtsKe {
+    pub const MAX_SESSIONS: usize = 64;
+
+    pub fn new(certificate: Certificate, cookie_key: Arc<CookieKey>) -> std::io::Result<Self> {
+        let mut tls = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_protocol_versions(&[&rustls::version::TLS13]).map_err(io_error)?
+            .with_no_client_auth()
+            .with_single_cert(certificate.chain, certificate.key).map_err(io_error)?;
+        tls.alpn_protocols = vec![nts::ALPN.to_vec()];
+
+        Ok(Self { tls: Arc::new(tls), cookie_key, ntp_server: None, ntp_port: None, sessions: AtomicUsize::new(0) })
+    }
+
+    pub fn from_config(config: &server_config::Nts, cookie_key: Arc<CookieKey>) -> std::io::Result<Self> {
+        let mut ke = Self::new(Certificate::from_config(config)?, cookie_key)?;
+        ke.ntp_server = config.ntp_server.clone();
+        ke.ntp_port = config.ntp_port;
+        Ok(ke)
+    }
+
+    pub fn bind(addr: SocketAddr) -> std::io::Result<TcpListener> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(listener)
+    }
+
+    //returns after shutdown is set, sessions in progress are finished on their own threads
+    pub fn serve(self: Arc<Self>, listener: TcpListener, shutdown: Arc<AtomicBool>) -> std::io::Result<()> {
+        let addr = listener.local_addr()?;
+        info!("nts-ke server started"; "address" => %addr);
+
+        while !shutdown.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok((_, addr)) if self.sessions.fetch_add(1, Ordering::SeqCst) >= Self::MAX_SESSIONS => {
+                    self.sessions.fetch_sub(1, Ordering::SeqCst);
+                    info!("nts-ke session"; "client" => %addr, "outcome" => "too_many_sessions");
+                },
+                Ok((stream, addr)) => {
+                    let session = Session(self.clone());
+                    std::thread::spawn(move || match session.0.handle(stream) {
+                        Ok(outcome) => info!("nts-ke session"; "client" => %addr, "outcome" => outcome),
+                        Err(err) => info!("nts-ke error"; "client" => %addr, "error" => %err),
+                    });
+                },
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => std::thread::sleep(ACCEPT_POLL_INTERVAL),
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(err) => error!("nts-ke accept error"; "error" => %err),
+            }
+        }
+
+        info!("nts-ke server stopped");
+        Ok(())
+    }
+
+    fn handle(&self, stream: TcpStream) -> std::io::Result<&'static str> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(KE_TIMEOUT))?;
+        stream.set_write_timeout(Some(KE_TIMEOUT))?;
+        let connection = rustls::ServerConnection::new(self.tls.clone()).map_err(io_error)?;
+        let mut tls = rustls::StreamOwned::new(connection, stream);
+
+        let mut data = Vec::new();
+        let mut buf = [0; 1024];
+        let request = loop {
+            if let Some(request) = nts::parse_message(&data) {
+                break request;
+            }
+            if data.len() > MAX_REQUEST_SIZE {
+                return Err(io_error("nts-ke request too long"));
+            }
+            match tls.read(&mut buf)? {
+                0 => return Err(io_error("connection closed before the end of the request")),
+                n => data.extend_from_slice(&buf[..n]),
+            }
+        };
+        //clients that don't ask for ntske/1 get through the handshake with no protocol at all
+        if tls.conn.alpn_protocol() != Some(nts::ALPN) {
+            return Err(io_error("client did not negotiate ntske/1"));
+        }
+
+        let (outcome, response) = match negotiate(&request) {
+            Ok(aead) => {
+                let keys = SessionKeys {
+                    c2s: tls.conn.export_keying_material([0; 32], nts::EXPORTER_LABEL,
+                        Some(&nts::exporter_context(aead, false))).map_err(io_error)?,
+                    s2c: tls.conn.export_keying_material([0; 32], nts::EXPORTER_LABEL,
+                        Some(&nts::exporter_context(aead, true))).map_err(io_error)?,
+                };
+                ("cookies", self.accept(aead, &keys))
+            },
+            Err(code) => ("error", vec![
+                Record::from_u16s(true, RecordType::ERROR, &[code]),
+                Record::end_of_message(),
+            ]),
+        };
+
+        tls.write_all(&nts::serialize_message(&response).map_err(io_error)?)?;
+        tls.conn.send_close_notify();
+        tls.flush()?;
+        Ok(outcome)
+    }
+
+    fn accept(&self, aead: u16, keys: &SessionKeys) -> Vec<Record> {
+        let mut response = vec![
+            Record::from_u16s(true, RecordType::NEXT_PROTOCOL, &[nts::PROTOCOL_NTPV4]),
+            Record::from_u16s(true, RecordType::AEAD_ALGORITHM, &[aead]),
+        ];
+        if let Some(server) = &self.ntp_server {
+            response.push(Record::new(true, RecordType::SERVER, server.as_bytes().to_vec()));
+        }
+        if let Some(port) = self.ntp_port {
+            response.push(Record::from_u16s(true, RecordType::PORT, &[port]));
+        }
+        for _ in 0..nts::COOKIES_PER_KE {
+            response.push(Record::new(false, RecordType::NEW_COOKIE, self.cookie_key.seal(keys, &mut rand::rngs::OsRng)));
+        }
+        response.push(Record::end_of_message());
+        response
+    }
+}
+
+//picks the aead, Err is the error code to send back
+fn negotiate(request: &[Record]) -> Result<u16, u16> {
+    let mut protocols = None;
+    let mut algorithms = None;
+    for record in request {
+        //rfc 8915 4.1.5, both records have to be there exactly once
+        let previous = match record.record_type {
+            RecordType::NEXT_PROTOCOL => protocols.replace(record.u16s()),
+            RecordType::AEAD_ALGORITHM => algorithms.replace(record.u16s()),
+            RecordType::WARNING | RecordType::SERVER | RecordType::PORT => None,
+            _ if record.critical => return Err(KeError::UNRECOGNIZED_CRITICAL_RECORD),
+            _ => None,
+        };
+        if previous.is_some() {
+            return Err(KeError::BAD_REQUEST);
+        }
+    }
+
+    let (protocols, algorithms) = protocols.zip(algorithms).ok_or(KeError::BAD_REQUEST)?;
+    if !protocols.contains(&nts::PROTOCOL_NTPV4) || !algorithms.contains(&nts::AEAD_AES_SIV_CMAC_256) {
+        return Err(KeError::BAD_REQUEST);
+    }
+    Ok(nts::AEAD_AES_SIV_CMAC_256)
+}
@@ -0,0 +1,59 @@
+use std::collections::{HashMap,VecDeque};
+use std::net::IpAddr;
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+
+//every random decision made by strategies comes from here, so that a chaos run can be reproduced by
+//starting the server with the same seed and sending it the same requests
+//each client gets its own stream derived from the seed and its address, so the responses one
+//client gets don't depend on what other clients were doing at the same time
+//streams are kept for MAX_CLIENTS clients, the oldest one is dropped to make room and starts over
+//if that client comes back, which is still the same for the same requests
+pub struct Randomness {
+    seed: u64,
+    streams: HashMap<IpAddr, ChaCha20Rng>,
+    order: VecDeque<IpAddr>,    //clients in the order their streams were created
+}
+
+impl Randomness {
+    pub const MAX_CLIENTS: usize = 4096;
+
+    pub fn new(seed: u64) -> Self {
+        Self { seed, streams: HashMap::new(), order: VecDeque::new() }
+    }
+
+    //seeds are kept below 2^63 so that they can be pasted into the (toml) config file
+    pub fn from_entropy() -> Self {
+        Self::new(rand::random::<u64>() >> 1)
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn for_client(&mut self, client: IpAddr) -> &mut ChaCha20Rng {
+        if !self.streams.contains_key(&client) {
+            if self.streams.len() >= Self::MAX_CLIENTS {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.streams.remove(&oldest);
+                }
+            }
+            self.order.push_back(client);
+        }
+        let seed = self.seed;
+        self.streams.entry(client).or_insert_with(|| ChaCha20Rng::from_seed(client_seed(seed, client)))
+    }
+}
+
+//seed bytes, then the client address as ipv6 (ipv4 addresses are mapped), then zeros
+fn client_seed(seed: u64, client: IpAddr) -> [u8; 32] {
+    let client = match client {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+
+    let mut res = [0u8; 32];
+    res[0..8].copy_from_slice(&seed.to_le_bytes());
+    res[8..24].copy_from_slice(&client.octets());
+    res
+}
@@ -0,0 +1,109 @@
+use std::collections::{HashMap,VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration,Instant};
+use super::server_config::{self,RateLimitAction};
+
+//clients are forgotten once their bucket is full again, but only when there are this many of them
+const PRUNE_THRESHOLD: usize = 4096;
+//and at most this often, so a flood of new addresses doesn't make every request go over all of them
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    created: u64,   //matches the entry in RateLimiter::order, a client can be pruned and come back
+}
+
+//token bucket per client address, every request takes a token, a token comes back every min_interval
+//and a client can have at most burst of them
+//buckets are timed with the real monotonic clock, chaos clocks would make the limit run fast or backwards
+pub struct RateLimiter {
+    min_interval: f64,
+    burst: f64,
+    pub action: RateLimitAction,
+    clients: HashMap<IpAddr, Bucket>,
+    order: VecDeque<(IpAddr, u64)>, //oldest bucket first, may still have pruned clients in it
+    created: u64,
+    next_prune: Instant,
+}
+
+impl RateLimiter {
+    //past this many clients the oldest bucket is dropped for every new one
+    pub const MAX_CLIENTS: usize = 65536;
+
+    pub fn new(min_interval: f64, burst: u32, action: RateLimitAction) -> Self {
+        Self {
+            min_interval,
+            burst: f64::from(burst.max(1)),
+            action,
+            clients: HashMap::new(),
+            order: VecDeque::new(),
+            created: 0,
+            next_prune: Instant::now(),
+        }
+    }
+
+    //None if rate limiting is disabled
+    pub fn from_config(config: &server_config::RateLimit) -> Option<Self> {
+        if config.enabled {
+            Some(Self::new(config.min_interval, config.burst, config.action))
+        } else {
+            None
+        }
+    }
+
+    //takes a token from the client's bucket, returns false if there was none left
+    pub fn check(&mut self, client: IpAddr, now: Instant) -> bool {
+        if self.clients.len() >= PRUNE_THRESHOLD && now >= self.next_prune {
+            self.prune(now);
+            self.next_prune = now + PRUNE_INTERVAL;
+        }
+        if !self.clients.contains_key(&client) {
+            self.insert(client, now);
+        }
+
+        let (min_interval, burst) = (self.min_interval, self.burst);
+        let bucket = self.clients.get_mut(&client).expect("inserted above");
+        bucket.tokens = refill(bucket, now, min_interval, burst);
+        bucket.last = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn insert(&mut self, client: IpAddr, now: Instant) {
+        while self.clients.len() >= Self::MAX_CLIENTS {
+            match self.order.pop_front() {
+                Some((oldest, created)) => if self.clients.get(&oldest).is_some_and(|b| b.created == created) {
+                    self.clients.remove(&oldest);
+                },
+                None => break,
+            }
+        }
+        self.created += 1;
+        self.clients.insert(client, Bucket { tokens: self.burst, last: now, created: self.created });
+        self.order.push_back((client, self.created));
+    }
+
+    fn prune(&mut self, now: Instant) {
+        let (min_interval, burst) = (self.min_interval, self.burst);
+        self.clients.retain(|_, bucket| refill(bucket, now, min_interval, burst) < burst);
+        let clients = &self.clients;
+        self.order.retain(|(client, created)| clients.get(client).is_some_and(|b| b.created == *created));
+    }
+}
+
+fn refill(bucket: &Bucket, now: Instant, min_interval: f64, burst: f64) -> f64 {
+    let elapsed = now.saturating_duration_since(bucket.last).as_secs_f64();
+    if elapsed <= 0.0 {
+        return bucket.tokens;
+    }
+    if min_interval <= 0.0 {
+        return burst;
+    }
+    (bucket.tokens + elapsed / min_interval).min(burst)
+}
@@ -0,0 +1,507 @@
+use std::sync::Arc;
+use std::net::SocketAddr;
+use rand::RngCore;
+use crate::ntp;
+use crate::ntp::types::{TimestampTrait,Short,TimeDelta,PacketRef,ExtensionField};
+use crate::ntp::builder::PacketBuilder;
+use crate::ntp::auth::{self,Keys,Key};
+use crate::ntp::nts::{self,CookieKey,NtsRequest};
+use crate::ntp::constants::{KoD,ExtensionFieldType};
+use super::clock::{Clock,SystemClock};
+
+inventory::collect!(&'static dyn ResponseStrategyCtor);
+
+//timestamps are left to the strategies, unchecked until they fill them in
+fn default_packet() -> ntp::types::Packet {
+    PacketBuilder::server()
+        .stratum(ntp::types::Stratum::SecondaryServer(4))
+        .precision(-16)
+        .root_delay(Short::from(0).set_fraction(1000))
+        .root_dispersion(Short::from(0).set_fraction(1000))
+        .build_unchecked()
+}
+
+//kiss-o'-death, like ntpd it only echoes the client's transmit timestamp so no time is given away
+//unchecked, the request can be anything the parser let through
+pub fn kiss_of_death(request: &PacketRef, code: [u8;4]) -> ntp::types::Packet {
+    PacketBuilder::kiss_of_death(&request.header(), code).build_unchecked()
+}
+
+//NTSN kiss-o'-death, echoes the unique identifier so the client knows the nak is meant for it
+//extensions are always set so the server doesn't try to protect it
+pub fn nts_nak(request: &PacketRef) -> ntp::types::Packet {
+    ntp::types::Packet {
+        extensions: Some(request.extensions
+            .filter(|e| e.field_type == ExtensionFieldType::UNIQUE)
+            .take(1)
+            .map(ExtensionField::from)
+            .collect()),
+        ..kiss_of_death(request, KoD::NTSN)
+    }
+}
+
+//everything a strategy gets from the server when it is created
+#[derive(Clone)]
+pub struct StrategyContext {
+    pub clock: Arc<dyn Clock>,
+    pub keys: Arc<Keys>,    //same keys the server verifies requests with
+    pub nts: Option<Arc<CookieKey>>,    //the server's cookie key, if nts is enabled
+}
+
+impl Default for StrategyContext {
+    fn default() -> Self {
+        Self {
+            clock: Arc::new(SystemClock),
+            keys: Arc::new(Keys::default()),
+            nts: None,
+        }
+    }
+}
+
+//everything a strategy gets from the server with each request
+pub struct RequestContext<'a> {
+    pub client: SocketAddr,
+    pub rng: &'a mut dyn RngCore,   //seeded stream for this client, use it for anything random
+    pub nts: Option<&'a NtsRequest>,    //set if the request came with a valid nts cookie and authenticator
+}
+
+pub trait ResponseStrategyCtor {
+    fn new_boxed(&self, ctx: &StrategyContext) -> Box<dyn ResponseStrategy>;
+    fn name(&self) -> &'static str;
+}
+
+pub fn find_strategy(name: &str) -> Option<&'static dyn ResponseStrategyCtor> {
+    inventory::iter::<&dyn ResponseStrategyCtor>.into_iter().find(|s| s.name() == name).copied()
+}
+
+//what the server should do with a request after the strategy is done with it
+#[derive(Debug,Clone)]
+pub enum Response {
+    Packet(ntp::types::Packet),         //serialize and send a single packet
+    Raw(Vec<u8>),                       //send bytes as they are, no serialization involved
+    Multiple(Vec<ntp::types::Packet>),  //send every packet, in order
+    Nothing,                            //pretend the request never arrived
+}
+
+impl From<ntp::types::Packet> for Response {
+    fn from(packet: ntp::types::Packet) -> Self {
+        Response::Packet(packet)
+    }
+}
+
+//errors are logged by the server together with the address of the client, nothing is sent back
+pub type StrategyResult = Result<Response, Box<dyn std::error::Error>>;
+
+//strategies are moved to the thread the server runs on
+pub trait ResponseStrategy: Send {
+    fn process_packet(&mut self, packet: &PacketRef, req: &mut RequestContext) -> StrategyResult;
+}
+
+macro_rules! empty_ctor {
+    ($name:ident) => {
+        paste::paste! {
+            pub struct [<$name Ctor>];
+            impl ResponseStrategyCtor for [<$name Ctor>] {
+                fn new_boxed(&self, _ctx: &StrategyContext) -> Box<dyn ResponseStrategy> { 
+                    Box::new($name {})
+                }
+
+                fn name(&self) -> &'static str { stringify!([<$name:snake>]) }
+            }
+
+            inventory::submit! {
+                &[<$name Ctor>] as &dyn ResponseStrategyCtor
+            }
+        }
+    }
+}
+
+//for strategies that need something from the context, $name::new(ctx) is used to create them
+macro_rules! ctx_ctor {
+    ($name:ident) => {
+        paste::paste! {
+            pub struct [<$name Ctor>];
+            impl ResponseStrategyCtor for [<$name Ctor>] {
+                fn new_boxed(&self, ctx: &StrategyContext) -> Box<dyn ResponseStrategy> {
+                    Box::new($name::new(ctx))
+                }
+
+                fn name(&self) -> &'static str { stringify!([<$name:snake>]) }
+            }
+
+            inventory::submit! {
+                &[<$name Ctor>] as &dyn ResponseStrategyCtor
+            }
+        }
+    }
+}
+
+pub struct SingleOffset {
+    clock: Arc<dyn Clock>,
+    time_offset: i64, //time offset in seconds
+    counter: u32,
+}
+ctx_ctor!(SingleOffset);
+
+impl SingleOffset {
+    pub fn new(ctx: &StrategyContext) -> Self {
+        Self {
+            clock: ctx.clock.clone(),
+            time_offset: 0,
+            counter: 0,
+        }
+    }
+
+    //offset to a strategy
+    //seconds since the ntp epoch, wraps around just like the timestamps do
+    pub fn get_time(&mut self) -> u32 {
+        self.time_offset += 1;
+        self.counter += 1;
+
+        (i64::from(self.clock.now().get_seconds()) + self.time_offset) as u32
+    }
+}
+
+impl ResponseStrategy for SingleOffset {
+    //TODO: use config
+    fn process_packet(&mut self, packet: &PacketRef, _req: &mut RequestContext) -> StrategyResult {
+        let rand_time = (ntp::types::Timestamp::from(0)).set_seconds(self.get_time()); 
+        let fraction = 0;//rand::random::<u32>();
+
+        Ok(ntp::types::Packet {
+            origin_timestamp: packet.transit_timestamp,
+            reference_timestamp: rand_time.set_seconds(rand_time.get_seconds()).set_fraction(fraction), //last set
+            receive_timestamp: rand_time.set_fraction(fraction),
+            transit_timestamp: rand_time.set_seconds(rand_time.get_seconds()).set_fraction(fraction),
+            ..default_packet()
+        }.into())
+    }
+}
+
+pub struct TransitTimestamp;
+empty_ctor!(TransitTimestamp);
+impl ResponseStrategy for TransitTimestamp {
+    fn process_packet(&mut self, packet: &PacketRef, _req: &mut RequestContext) -> StrategyResult {
+        Ok(ntp::types::Packet {
+            origin_timestamp: packet.transit_timestamp,
+            //wraps around at the end of the era, zero padded requests have a transmit time of 0
+            reference_timestamp: packet.transit_timestamp - TimeDelta(5 << 32),
+            receive_timestamp: packet.transit_timestamp + TimeDelta(1 << 32),
+            transit_timestamp: packet.transit_timestamp + TimeDelta(1 << 32),
+            ..default_packet()
+        }.into())
+    }
+}
+
+//TODO reference/receive/transit timestamps should be probably be different from each other
+pub struct CurrentTime {
+    clock: Arc<dyn Clock>,
+}
+ctx_ctor!(CurrentTime);
+
+impl CurrentTime {
+    pub fn new(ctx: &StrategyContext) -> Self {
+        Self { clock: ctx.clock.clone() }
+    }
+}
+
+fn current_time_packet(clock: &dyn Clock, packet: &PacketRef) -> ntp::types::Packet {
+    ntp::types::Packet {
+        origin_timestamp: packet.transit_timestamp,
+        //time at the client when the request departed for the server
+        reference_timestamp: clock.now(),
+        //Time when the system clock was last set or corrected, in NTP timestamp format
+        receive_timestamp: clock.now(),
+        //time at the server when the request arrived from the client
+        transit_timestamp: clock.now(),
+        //time at the server when the response left for the client
+        ..default_packet()
+    }
+}
+
+impl ResponseStrategy for CurrentTime {
+    fn process_packet(&mut self, packet: &PacketRef, _req: &mut RequestContext) -> StrategyResult {
+        Ok(current_time_packet(self.clock.as_ref(), packet).into())
+    }
+}
+
+//authentication failures
+//the server only signs responses the strategy left without a mac, so these are sent as they are
+
+//key the request was signed with, the one with the lowest id if the request was not signed
+fn response_key<'a>(keys: &'a Keys, request: &PacketRef) -> Option<&'a Key> {
+    request.auth.and_then(|auth| keys.get(auth.key_indentifier))
+        .or_else(|| keys.iter().min_by_key(|key| key.id))
+}
+
+//a mac that can't be right, used when there are no keys to make a real one
+fn random_auth(rng: &mut dyn RngCore) -> ntp::types::Auth {
+    let mut digest = vec![0; 16];
+    rng.fill_bytes(&mut digest);
+    ntp::types::Auth { key_indentifier: rng.next_u32().max(1), digest }
+}
+
+//current time, correct key id, one bit of the digest flipped
+pub struct WrongDigest {
+    clock: Arc<dyn Clock>,
+    keys: Arc<Keys>,
+}
+ctx_ctor!(WrongDigest);
+
+impl WrongDigest {
+    pub fn new(ctx: &StrategyContext) -> Self {
+        Self { clock: ctx.clock.clone(), keys: ctx.keys.clone() }
+    }
+}
+
+impl ResponseStrategy for WrongDigest {
+    fn process_packet(&mut self, packet: &PacketRef, req: &mut RequestContext) -> StrategyResult {
+        let mut response = current_time_packet(self.clock.as_ref(), packet);
+        match response_key(&self.keys, packet) {
+            Some(key) => {
+                auth::sign(&mut response, key)?;
+                let digest = &mut response.auth.as_mut().expect("just signed").digest;
+                let bit = req.rng.next_u32() as usize % (digest.len() * 8);
+                digest[bit / 8] ^= 1 << (bit % 8);
+            },
+            None => response.auth = Some(random_auth(req.rng)),
+        }
+        Ok(response.into())
+    }
+}
+
+//current time, digest made with the right key but labeled with another key id
+pub struct WrongKeyId {
+    clock: Arc<dyn Clock>,
+    keys: Arc<Keys>,
+}
+ctx_ctor!(WrongKeyId);
+
+impl WrongKeyId {
+    pub fn new(ctx: &StrategyContext) -> Self {
+        Self { clock: ctx.clock.clone(), keys: ctx.keys.clone() }
+    }
+}
+
+impl ResponseStrategy for WrongKeyId {
+    fn process_packet(&mut self, packet: &PacketRef, req: &mut RequestContext) -> StrategyResult {
+        let mut response = current_time_packet(self.clock.as_ref(), packet);
+        match response_key(&self.keys, packet) {
+            Some(key) => {
+                auth::sign(&mut response, key)?;
+                //key id 0 would turn it into a crypto-nak
+                response.auth.as_mut().expect("just signed").key_indentifier = key.id.wrapping_add(1).max(1);
+            },
+            None => response.auth = Some(random_auth(req.rng)),
+        }
+        Ok(response.into())
+    }
+}
+
+//current time with a crypto-nak (key id 0, no digest) instead of a mac
+pub struct CryptoNak {
+    clock: Arc<dyn Clock>,
+}
+ctx_ctor!(CryptoNak);
+
+impl CryptoNak {
+    pub fn new(ctx: &StrategyContext) -> Self {
+        Self { clock: ctx.clock.clone() }
+    }
+}
+
+impl ResponseStrategy for CryptoNak {
+    fn process_packet(&mut self, packet: &PacketRef, _req: &mut RequestContext) -> StrategyResult {
+        Ok(ntp::types::Packet {
+            auth: Some(auth::crypto_nak()),
+            ..current_time_packet(self.clock.as_ref(), packet)
+        }.into())
+    }
+}
+
+//AUTH kiss-o'-death, server authentication failed
+pub struct AuthKod;
+empty_ctor!(AuthKod);
+impl ResponseStrategy for AuthKod {
+    fn process_packet(&mut self, packet: &PacketRef, _req: &mut RequestContext) -> StrategyResult {
+        Ok(kiss_of_death(packet, KoD::AUTH).into())
+    }
+}
+
+//CRYP kiss-o'-death with a crypto-nak, cryptographic authentication failed
+pub struct CrypKod;
+empty_ctor!(CrypKod);
+impl ResponseStrategy for CrypKod {
+    fn process_packet(&mut self, packet: &PacketRef, _req: &mut RequestContext) -> StrategyResult {
+        Ok(ntp::types::Packet {
+            auth: Some(auth::crypto_nak()),
+            ..kiss_of_death(packet, KoD::CRYP)
+        }.into())
+    }
+}
+
+//valid mac over timestamps that are off by an hour up to ten years in either direction
+//an attacker with the key, without keys the response just isn't signed
+pub struct ForgedTimestamps {
+    clock: Arc<dyn Clock>,
+    keys: Arc<Keys>,
+}
+ctx_ctor!(ForgedTimestamps);
+
+impl ForgedTimestamps {
+    pub fn new(ctx: &StrategyContext) -> Self {
+        Self { clock: ctx.clock.clone(), keys: ctx.keys.clone() }
+    }
+}
+
+impl ResponseStrategy for ForgedTimestamps {
+    fn process_packet(&mut self, packet: &PacketRef, req: &mut RequestContext) -> StrategyResult {
+        const HOUR: u32 = 3_600;
+        const TEN_YEARS: u32 = 10 * 365 * 24 * HOUR;
+        let offset = HOUR + req.rng.next_u32() % (TEN_YEARS - HOUR);
+        let now = self.clock.now();
+        let forged = if req.rng.next_u32() % 2 == 0 {
+            now.set_seconds(now.get_seconds().wrapping_add(offset))
+        } else {
+            now.set_seconds(now.get_seconds().wrapping_sub(offset))
+        };
+
+        let mut response = ntp::types::Packet {
+            origin_timestamp: packet.transit_timestamp,
+            reference_timestamp: forged,
+            receive_timestamp: forged,
+            transit_timestamp: forged,
+            ..default_packet()
+        };
+        if let Some(key) = response_key(&self.keys, packet) {
+            auth::sign(&mut response, key)?;
+        }
+        Ok(response.into())
+    }
+}
+
+
+//the nts strategies answer plain requests with the current time, there is nothing to break
+//responses with extensions set are sent as they are, the server only protects the ones without
+fn nts_cookie_key(cookie_key: &Option<Arc<CookieKey>>) -> Result<&CookieKey, Box<dyn std::error::Error>> {
+    cookie_key.as_deref().ok_or_else(|| "nts request but no cookie key, nts is not enabled".into())
+}
+
+fn flip_bit(data: &mut [u8], rng: &mut dyn RngCore) {
+    let bit = rng.next_u32() as usize % (data.len() * 8);
+    data[bit / 8] ^= 1 << (bit % 8);
+}
+
+//current time, properly protected, but every cookie in it has one bit flipped
+//clients only find out with the next request, which should get an NTSN
+pub struct NtsCorruptCookie {
+    clock: Arc<dyn Clock>,
+    cookie_key: Option<Arc<CookieKey>>,
+}
+ctx_ctor!(NtsCorruptCookie);
+
+impl NtsCorruptCookie {
+    pub fn new(ctx: &StrategyContext) -> Self {
+        Self { clock: ctx.clock.clone(), cookie_key: ctx.nts.clone() }
+    }
+}
+
+impl ResponseStrategy for NtsCorruptCookie {
+    fn process_packet(&mut self, packet: &PacketRef, req: &mut RequestContext) -> StrategyResult {
+        let mut response = current_time_packet(self.clock.as_ref(), packet);
+        if let Some(request) = req.nts {
+            let cookie_key = nts_cookie_key(&self.cookie_key)?;
+            let cookies = (0..request.cookies_wanted).map(|_| {
+                let mut cookie = cookie_key.seal(&request.keys, req.rng);
+                flip_bit(&mut cookie, req.rng);
+                nts::cookie(cookie)
+            }).collect::<Vec<_>>();
+            nts::seal_response(&mut response, request, &cookies, req.rng)?;
+        }
+        Ok(response.into())
+    }
+}
+
+//current time, properly protected, without echoing the unique identifier
+pub struct NtsNoUniqueId {
+    clock: Arc<dyn Clock>,
+    cookie_key: Option<Arc<CookieKey>>,
+}
+ctx_ctor!(NtsNoUniqueId);
+
+impl NtsNoUniqueId {
+    pub fn new(ctx: &StrategyContext) -> Self {
+        Self { clock: ctx.clock.clone(), cookie_key: ctx.nts.clone() }
+    }
+}
+
+impl ResponseStrategy for NtsNoUniqueId {
+    fn process_packet(&mut self, packet: &PacketRef, req: &mut RequestContext) -> StrategyResult {
+        let mut response = current_time_packet(self.clock.as_ref(), packet);
+        if let Some(request) = req.nts {
+            let request = NtsRequest { unique_id: None, ..request.clone() };
+            nts::protect_response(&mut response, &request, nts_cookie_key(&self.cookie_key)?, req.rng)?;
+        }
+        Ok(response.into())
+    }
+}
+
+//current time and fresh cookies, with one bit of the authenticator's nonce or tag flipped
+pub struct NtsBadAuthenticator {
+    clock: Arc<dyn Clock>,
+    cookie_key: Option<Arc<CookieKey>>,
+}
+ctx_ctor!(NtsBadAuthenticator);
+
+impl NtsBadAuthenticator {
+    pub fn new(ctx: &StrategyContext) -> Self {
+        Self { clock: ctx.clock.clone(), cookie_key: ctx.nts.clone() }
+    }
+}
+
+impl ResponseStrategy for NtsBadAuthenticator {
+    fn process_packet(&mut self, packet: &PacketRef, req: &mut RequestContext) -> StrategyResult {
+        let mut response = current_time_packet(self.clock.as_ref(), packet);
+        if let Some(request) = req.nts {
+            nts::protect_response(&mut response, request, nts_cookie_key(&self.cookie_key)?, req.rng)?;
+            let authenticator = response.extensions.iter_mut().flatten()
+                .find(|e| e.field_type == ExtensionFieldType::NTP_AUTHENTICATOR)
+                .expect("just protected");
+            //past the nonce and ciphertext lengths, the padding at the end wouldn't be noticed
+            flip_bit(&mut authenticator.value[4..4 + nts::NONCE_SIZE + ntp::aes_siv::TAG_SIZE], req.rng);
+        }
+        Ok(response.into())
+    }
+}
+
+//current time, properly protected, but no new cookies
+//clients run out after a few requests and have to go back to nts-ke
+pub struct NtsNoCookies {
+    clock: Arc<dyn Clock>,
+}
+ctx_ctor!(NtsNoCookies);
+
+impl NtsNoCookies {
+    pub fn new(ctx: &StrategyContext) -> Self {
+        Self { clock: ctx.clock.clone() }
+    }
+}
+
+impl ResponseStrategy for NtsNoCookies {
+    fn process_packet(&mut self, packet: &PacketRef, req: &mut RequestContext) -> StrategyResult {
+        let mut response = current_time_packet(self.clock.as_ref(), packet);
+        if let Some(request) = req.nts {
+            nts::seal_response(&mut response, request, &[], req.rng)?;
+        }
+        Ok(response.into())
+    }
+}
+
+//NTSN kiss-o'-death for every request, valid cookies or not
+pub struct NtsNak;
+empty_ctor!(NtsNak);
+impl ResponseStrategy for NtsNak {
+    fn process_packet(&mut self, packet: &PacketRef, _req: &mut RequestContext) -> StrategyResult {
+        Ok(nts_nak(packet).into())
+    }
+}
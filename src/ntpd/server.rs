@@ -0,0 +1,437 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::net::{UdpSocket,IpAddr,SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::time::{Duration,Instant};
+use rand::RngCore;
+use slog_scope::{error,info,debug};
+use crate::ntp;
+use crate::ntp::types::PacketRef;
+use crate::ntp::parser::ParseMode;
+use super::response_strategy::{ResponseStrategy,RequestContext,Response,kiss_of_death,nts_nak};
+use super::rate_limit::RateLimiter;
+use super::acl::{Acl,Action};
+use crate::ntp::auth::{self,Keys,Key,AuthError};
+use crate::ntp::nts::{self,CookieKey,NtsRequest,NtsError};
+use super::server_config::{RateLimitAction,ResponseVersion};
+use super::random::Randomness;
+use super::metrics::Metrics;
+use super::capture::PcapWriter;
+use super::clock::Clock;
+
+//requests are logged on info if log_all_requests is set, on debug otherwise
+macro_rules! request_log {
+    ($server:expr, $($args:tt)+) => {
+        if $server.log_all_requests { info!($($args)+) } else { debug!($($args)+) }
+    }
+}
+
+//how long to block on the socket before checking if the server should shut down
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(250);
+//plenty for a response with a mac or a few extension fields
+const SEND_BUFFER_SIZE: usize = 1024;
+
+#[derive(Debug,Default,Clone,Copy)]
+pub struct Stats {
+    pub requests: u64,
+    pub responses: u64,
+    pub dropped: u64,
+    pub rate_limited: u64,
+    pub acl_rejected: u64,
+    pub auth_failures: u64,
+    pub parse_errors: u64,
+    pub parse_anomalies: u64,
+    pub strategy_errors: u64,
+    pub serialization_errors: u64,
+    pub send_errors: u64,
+}
+
+//how the response has to be protected, follows how the request was
+pub enum Protection {
+    None,
+    Mac(Key),
+    Nts(NtsRequest),
+}
+
+pub struct Server {
+    pub port: u16,
+    pub addr: IpAddr,
+    pub log_all_requests: bool,
+    pub parse_mode: ParseMode,
+    pub response_version: ResponseVersion,  //applies to whatever the strategy sends back
+    pub response_strategy: Box<dyn ResponseStrategy>,
+    pub response_strategy_name: &'static str,
+    pub metrics: Metrics,
+    pub capture: Option<PcapWriter<BufWriter<File>>>,  //every request and response gets written here
+    pub clock: Arc<dyn Clock>,
+    pub randomness: Randomness,
+    pub rate_limiter: Option<RateLimiter>,   //requests over the limit never reach the strategy
+    pub acl: Acl,   //checked before the rate limit
+    pub keys: Arc<Keys>,    //authenticated requests get responses signed with the same key
+    pub require_auth: bool, //drop requests without a mac
+    pub nts: Option<Arc<CookieKey>>,    //nts requests are only accepted if set, with cookies made by the nts-ke server
+    pub shutdown: Arc<AtomicBool>,  //set to true to stop the server after the current request
+    pub stats: Stats,
+}
+
+impl Server {
+    pub fn bind(&self) -> std::io::Result<UdpSocket> {
+        let socket = UdpSocket::bind(SocketAddr::new(self.addr, self.port))?;
+        socket.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL))?;
+        Ok(socket)
+    }
+
+    //returns after self.shutdown is set, the request being processed at that time is still answered
+    pub fn start_server(&mut self) -> std::io::Result<()> {
+        let socket = self.bind()?;
+        self.serve(socket)
+    }
+
+    //same as start_server but with a socket that was already bound with bind
+    pub fn serve(&mut self, socket: UdpSocket) -> std::io::Result<()> {
+        let local_addr = socket.local_addr()?;
+        let mut buf = [0;65527];
+
+        info!("server started"; "address" => %local_addr.ip(), "port" => local_addr.port(),
+              "seed" => self.randomness.seed());
+
+        while !self.shutdown.load(Ordering::SeqCst) {
+            match socket.recv_from(&mut buf) {
+                Ok((amt, addr)) => {
+                    self.capture(addr, local_addr, &buf[..amt]);
+                    self.handle_request(&socket, addr, &buf[..amt]);
+                },
+                //read timeouts and signals interrupting recv, both just mean checking the shutdown flag again
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock
+                    || err.kind() == std::io::ErrorKind::TimedOut
+                    || err.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(err) => {
+                    error!("receiving error"; "error" => %err);
+                }
+            }
+        }
+
+        info!("server stopped"; "stats" => ?self.stats);
+        Ok(())
+    }
+
+    //data is exactly what was received, the parser pads short packets itself
+    pub fn handle_request(&mut self, socket: &UdpSocket, addr: SocketAddr, data: &[u8]) {
+        let size = data.len();
+        self.stats.requests += 1;
+        self.metrics.count_client(addr.ip());
+        debug!("request received"; "client" => %addr, "size" => size, "data" => ?data);
+
+        let mut packet = match PacketRef::parse_with(data, self.parse_mode) {
+            Ok((packet, anomalies)) => {
+                for anomaly in anomalies {
+                    self.stats.parse_anomalies += 1;
+                    self.metrics.parse_anomalies.with_label_values(&[anomaly.name()]).inc();
+                    info!("parse anomaly"; "client" => %addr, "anomaly" => %anomaly, "data" => %Hex(data));
+                }
+                packet
+            },
+            Err(err) => return self.parse_error(addr, &err, data),
+        };
+
+        //ntpv1 has no mode, the bits are always 0 and every request is a client request
+        if packet.version == 1 && packet.mode == ntp::types::Mode::Reserved {
+            packet.mode = ntp::types::Mode::Client;
+        }
+
+        let request_timestamp = Rfc3339(packet.transit_timestamp);
+        request_log!(self, "request";
+            "client" => %addr, "size" => size, "mode" => ?packet.mode,
+            "request_timestamp" => %request_timestamp, "packet" => ?packet);
+
+        self.metrics.requests_per_mode.with_label_values(&[packet.mode.name()]).inc();
+
+        let checks = self.access_control(socket, addr, &packet)
+            .and_then(|rule| self.rate_limit(socket, addr, &packet).map_or(Ok(rule), Err))
+            .and_then(|rule| Ok((rule, self.authenticate(socket, addr, &packet)?)));
+        let (rule, protection) = match checks {
+            Ok(checks) => checks,
+            Err(outcome) => return request_log!(self, "request handled";
+                "client" => %addr, "request_timestamp" => %request_timestamp, "outcome" => outcome),
+        };
+
+        //reuses the rule access_control matched instead of scanning the acl again
+        let (strategy_name, strategy) = match self.acl.action(rule) {
+            Action::Strategy(name, strategy) => (*name, strategy.as_mut()),
+            _ => (self.response_strategy_name, self.response_strategy.as_mut()),
+        };
+        self.metrics.requests_per_strategy.with_label_values(&[strategy_name]).inc();
+
+        let nts = match &protection {
+            Protection::Nts(request) => Some(request),
+            _ => None,
+        };
+        let mut req = RequestContext { client: addr, rng: self.randomness.for_client(addr.ip()), nts };
+        let outcome = match strategy.process_packet(&packet, &mut req) {
+            Ok(response) => {
+                let version = self.response_version(addr, packet.version);
+                self.respond(socket, addr, strategy_name, &protection, version, response)
+            },
+            Err(err) => {
+                self.stats.strategy_errors += 1;
+                self.metrics.strategy_errors.inc();
+                error!("strategy error"; "client" => %addr, "strategy" => strategy_name,
+                       "error" => %err, "request" => ?packet);
+                "strategy_error"
+            }
+        };
+
+        request_log!(self, "request handled";
+            "client" => %addr, "strategy" => strategy_name,
+            "request_timestamp" => %request_timestamp, "outcome" => outcome);
+    }
+
+    //returns the matched rule so the strategy lookup doesn't have to check again,
+    //or the outcome if the acl rejected the request
+    fn access_control(&mut self, socket: &UdpSocket, addr: SocketAddr, packet: &PacketRef) -> Result<Option<usize>, &'static str> {
+        let rule = self.acl.find(addr.ip());
+        let action = self.acl.action(rule);
+        self.metrics.requests_per_acl_action.with_label_values(&[action.name()]).inc();
+        match *action {
+            Action::Ignore => {
+                self.stats.acl_rejected += 1;
+                self.stats.dropped += 1;
+                self.metrics.responses_dropped.inc();
+                Err("acl_ignore")
+            },
+            Action::KissOfDeath(code) => {
+                self.stats.acl_rejected += 1;
+                request_log!(self, "kiss-o'-death"; "client" => %addr, "code" => %String::from_utf8_lossy(&code));
+                self.serialize_and_send(socket, addr, &kiss_of_death(packet, code));
+                Err("acl_kod")
+            },
+            Action::Allow | Action::Strategy(_, _) => Ok(rule),
+        }
+    }
+
+    //returns the outcome if the request was over the limit and has been dealt with
+    fn rate_limit(&mut self, socket: &UdpSocket, addr: SocketAddr, packet: &PacketRef) -> Option<&'static str> {
+        let limiter = self.rate_limiter.as_mut()?;
+        if limiter.check(addr.ip(), Instant::now()) {
+            return None;
+        }
+        let action = limiter.action;
+
+        self.stats.rate_limited += 1;
+        self.metrics.rate_limited.inc();
+        Some(match action {
+            RateLimitAction::Drop => {
+                self.stats.dropped += 1;
+                self.metrics.responses_dropped.inc();
+                "rate_limited"
+            },
+            RateLimitAction::Kod => {
+                request_log!(self, "kiss-o'-death"; "client" => %addr, "code" => "RATE");
+                self.serialize_and_send(socket, addr, &kiss_of_death(packet, ntp::constants::KoD::RATE));
+                "rate_kod"
+            },
+        })
+    }
+
+    //returns how the response has to be protected, or the outcome if the request was rejected
+    //only requests with a mac or extension fields are copied, plain ones are the common case
+    fn authenticate(&mut self, socket: &UdpSocket, addr: SocketAddr, packet: &PacketRef) -> Result<Protection, &'static str> {
+        if packet.auth.is_none() && packet.extensions.is_empty() && !self.require_auth {
+            return Ok(Protection::None);
+        }
+        let owned = packet.to_packet();
+
+        if let Some(cookie_key) = &self.nts {
+            //nts is ntpv4 only, older versions have no extension fields to begin with
+            if packet.version == 4 && nts::is_nts_request(&owned) {
+                return match nts::open_request(&owned, cookie_key) {
+                    Ok(request) => Ok(Protection::Nts(request)),
+                    Err(err) => Err(self.nts_nak(socket, addr, packet, err)),
+                };
+            }
+        }
+
+        let err = match auth::verify(&owned, &self.keys) {
+            Ok(id) => return Ok(self.keys.get(id).cloned().map(Protection::Mac).unwrap_or(Protection::None)),
+            Err(AuthError::Missing) if !self.require_auth => return Ok(Protection::None),
+            Err(err) => err,
+        };
+
+        self.stats.auth_failures += 1;
+        self.metrics.auth_failures.inc();
+        info!("authentication failed"; "client" => %addr, "error" => %err);
+        if err == AuthError::Missing {
+            self.stats.dropped += 1;
+            self.metrics.responses_dropped.inc();
+            return Err("auth_required");
+        }
+
+        //crypto-nak, without giving away any time
+        let mut nak = kiss_of_death(packet, ntp::constants::KoD::CRYP);
+        nak.auth = Some(auth::crypto_nak());
+        self.serialize_and_send(socket, addr, &nak);
+        Err("crypto_nak")
+    }
+
+    fn nts_nak(&mut self, socket: &UdpSocket, addr: SocketAddr, packet: &PacketRef, err: NtsError) -> &'static str {
+        self.stats.auth_failures += 1;
+        self.metrics.auth_failures.inc();
+        info!("nts authentication failed"; "client" => %addr, "error" => %err);
+
+        let nak = nts_nak(packet);
+        self.serialize_and_send(socket, addr, &nak);
+        "nts_nak"
+    }
+
+    fn parse_error(&mut self, addr: SocketAddr, err: &ntp::Error, data: &[u8]) {
+        self.stats.parse_errors += 1;
+        self.metrics.parse_errors.inc();
+        info!("parse error"; "client" => %addr, "error" => %err, "data" => %Hex(data),
+              "outcome" => "parse_error");
+    }
+
+    //returns the outcome of the request for logging
+    fn respond(&mut self, socket: &UdpSocket, addr: SocketAddr, strategy: &str, protection: &Protection,
+               version: u8, response: Response) -> &'static str {
+        match response {
+            Response::Packet(mut packet) => {
+                packet.version = version;
+                self.protect(&mut packet, protection);
+                self.send_packet(socket, addr, strategy, &packet);
+                "packet"
+            },
+            Response::Multiple(mut packets) => {
+                for packet in &mut packets {
+                    packet.version = version;
+                    self.protect(packet, protection);
+                    self.send_packet(socket, addr, strategy, packet);
+                }
+                "multiple"
+            },
+            Response::Raw(data) => {
+                request_log!(self, "response";
+                    "client" => %addr, "strategy" => strategy,
+                    "data" => %Hex(&data));
+                self.send(socket, addr, &data);
+                "raw"
+            },
+            Response::Nothing => {
+                self.stats.dropped += 1;
+                self.metrics.responses_dropped.inc();
+                "nothing"
+            }
+        }
+    }
+
+    //the version is set before protecting, macs and nts authenticators cover it
+    fn response_version(&mut self, addr: SocketAddr, request_version: u8) -> u8 {
+        match self.response_version {
+            ResponseVersion::Mirror => request_version,
+            ResponseVersion::Fixed(version) => version,
+            ResponseVersion::Mismatch => {
+                let rng = self.randomness.for_client(addr.ip());
+                let (min, max) = (ntp::types::Packet::MIN_VERSION, ntp::types::Packet::MAX_VERSION);
+                //versions we don't know can't be mismatched, any known one will do
+                if !(min..=max).contains(&request_version) {
+                    return min + (rng.next_u32() % u32::from(max - min + 1)) as u8;
+                }
+                let version = min + (rng.next_u32() % u32::from(max - min)) as u8;
+                //skips over the request's version
+                if version >= request_version { version + 1 } else { version }
+            },
+        }
+    }
+
+    //responses to authenticated requests are protected the same way, unless the strategy already
+    //put a mac (or extension fields, for nts) there
+    fn protect(&self, packet: &mut ntp::types::Packet, protection: &Protection) {
+        let result = match (protection, &self.nts) {
+            (Protection::Mac(key), _) if packet.auth.is_none() => auth::sign(packet, key)
+                .map_err(|err| err.to_string()),
+            (Protection::Nts(request), Some(cookie_key)) if packet.extensions.is_none() =>
+                nts::protect_response(packet, request, cookie_key, &mut rand::rngs::OsRng)
+                    .map_err(|err| err.to_string()),
+            _ => Ok(()),
+        };
+        if let Err(err) = result {
+            error!("signing error"; "error" => err);
+        }
+    }
+
+    fn send_packet(&mut self, socket: &UdpSocket, addr: SocketAddr, strategy: &str, packet: &ntp::types::Packet) {
+        //the difference wraps with the era, so offsets up to 68 years are right even across 2036
+        let applied_offset = (packet.transit_timestamp - self.clock.now()).as_secs_f64();
+        self.metrics.applied_offset.observe(applied_offset);
+
+        request_log!(self, "response";
+            "client" => %addr, "strategy" => strategy,
+            "reference_timestamp" => %Rfc3339(packet.reference_timestamp),
+            "origin_timestamp" => %Rfc3339(packet.origin_timestamp),
+            "receive_timestamp" => %Rfc3339(packet.receive_timestamp),
+            "response_timestamp" => %Rfc3339(packet.transit_timestamp),
+            "applied_offset" => applied_offset);
+
+        self.serialize_and_send(socket, addr, packet);
+    }
+
+    fn serialize_and_send(&mut self, socket: &UdpSocket, addr: SocketAddr, packet: &ntp::types::Packet) {
+        //responses are serialized on the stack, only ones that don't fit (lots of nts cookies) need a vec
+        let mut small = [0; SEND_BUFFER_SIZE];
+        let mut large = Vec::new();
+        let buf: &mut [u8] = if packet.size() <= small.len() {
+            &mut small
+        } else {
+            large.resize(packet.size(), 0);
+            &mut large
+        };
+        match packet.serialize_into(buf) {
+            Ok(size) => self.send(socket, addr, &buf[..size]),
+            Err(err) => {
+                self.stats.serialization_errors += 1;
+                self.metrics.serialization_errors.inc();
+                error!("serializing error"; "client" => %addr, "error" => %err, "packet" => ?packet);
+            }
+        }
+    }
+
+    fn capture(&mut self, src: SocketAddr, dst: SocketAddr, data: &[u8]) {
+        if let Some(capture) = &mut self.capture {
+            if let Err(err) = capture.write_udp(self.clock.system_time(), src, dst, data) {
+                error!("capture error"; "error" => %err);
+            }
+        }
+    }
+
+    fn send(&mut self, socket: &UdpSocket, addr: SocketAddr, data: &[u8]) {
+        match socket.send_to(data, addr) {
+            Ok(_) => {
+                self.stats.responses += 1;
+                if let Ok(local_addr) = socket.local_addr() {
+                    self.capture(local_addr, addr, data);
+                }
+            },
+            Err(err) => {
+                self.stats.send_errors += 1;
+                error!("sending error"; "client" => %addr, "error" => %err);
+            }
+        }
+    }
+}
+
+//log values are wrapped so they are only formatted if the record isn't filtered out
+struct Rfc3339(ntp::types::Timestamp);
+
+impl std::fmt::Display for Rfc3339 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0.to_rfc3339())
+    }
+}
+
+struct Hex<'a>(&'a [u8]);
+
+impl std::fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:x?}", self.0)
+    }
+}
@@ -0,0 +1,273 @@
+use std::net::{IpAddr,SocketAddr};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use serde::{Deserialize,Deserializer,Serialize};
+use ipnet::IpNet;
+use toml::value::Value;
+use slog::Level;
+use crate::ntp::parser::ParseMode;
+
+#[derive(Debug,Serialize,Deserialize,Clone,)]
+pub struct Server {
+    pub address: IpAddr,
+    pub port: u16,
+    pub resp_strategy: String,
+    #[serde(default)]
+    pub seed: Option<u64>,  //seed for everything random, picked at startup (and logged) if not set
+    #[serde(default)]
+    pub parse_mode: ParseMode,  //strict, lenient or raw, see ntp::parser::ParseMode
+    #[serde(default)]
+    pub response_version: ResponseVersion,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self {
+            address: IpAddr::from_str("0.0.0.0").unwrap(),
+            port: 123,
+            resp_strategy: "current_time".to_string(),
+            seed: None,
+            parse_mode: ParseMode::default(),
+            response_version: ResponseVersion::default(),
+        }
+    }
+}
+
+//version the responses of the strategy are sent with, kiss-o'-death from the acl and rate limit always mirror
+//response_version = "mismatch" or response_version = { fixed = 3 }
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq,Eq,Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseVersion {
+    #[default]
+    Mirror,     //same as the request, like every real server
+    Mismatch,   //any version from 1 to 4 but the one in the request
+    #[serde(deserialize_with = "fixed_version")]
+    Fixed(u8),  //always this one, even for clients that can't understand it
+}
+
+impl ResponseVersion {
+    //the version is 3 bits on the wire, anything bigger would end up in the leap indicator
+    pub const MAX_FIXED: u8 = 7;
+
+    pub fn check(&self) -> Result<(), String> {
+        match *self {
+            ResponseVersion::Fixed(version) if version > Self::MAX_FIXED =>
+                Err(format!("response version {} is out of range, at most {}", version, Self::MAX_FIXED)),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn fixed_version<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    let version = u8::deserialize(deserializer)?;
+    ResponseVersion::Fixed(version).check().map_err(serde::de::Error::custom)?;
+    Ok(version)
+}
+
+#[derive(Serialize,Deserialize)]
+#[serde(remote = "Level")]
+#[serde(rename_all = "lowercase")]
+enum LevelDef {
+    Trace,
+    Debug,
+    Info,
+    Error,
+    Warning,
+    Critical,
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq,Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Term,   //human readable
+    Json,   //one json object per line
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone)]
+#[serde(default)]
+pub struct Log {
+    pub log_all_requests: bool, //log requests
+    #[serde(with = "LevelDef")]
+    pub level: Level,
+    pub format: LogFormat,
+    pub file: Option<PathBuf>,  //log to stdout if not set
+    pub max_file_size: u64,     //bytes, rotate the log file after it grows this big, 0 disables rotation
+    pub max_files: usize,       //number of rotated log files to keep
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Self {
+            log_all_requests: false,
+            level: Level::Info,
+            format: LogFormat::Term,
+            file: None,
+            max_file_size: 10 * 1024 * 1024,
+            max_files: 5,
+        }
+    }
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub struct Metrics {
+    pub enabled: bool,
+    pub address: SocketAddr, //prometheus endpoint is served on http://address/metrics
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: SocketAddr::from_str("127.0.0.1:9123").unwrap(),
+        }
+    }
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,Default)]
+pub struct Capture {
+    pub file: Option<PathBuf>,  //pcap file to record requests and responses to, nothing is recorded if not set
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq,Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClockKind {
+    System,     //follows the system time
+    Monotonic,  //follows the monotonic clock, can start at a different time and run faster
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone)]
+#[serde(default)]
+pub struct Clock {
+    pub kind: ClockKind,
+    pub start: Option<String>,  //rfc3339, monotonic clock only, starts at the current time if not set
+    pub rate: f64,              //monotonic clock only, 2.0 makes a simulated second last half a second
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self {
+            kind: ClockKind::System,
+            start: None,
+            rate: 1.0,
+        }
+    }
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq,Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitAction {
+    Drop,   //ignore the request
+    Kod,    //answer with a RATE kiss-o'-death
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone)]
+#[serde(default)]
+pub struct RateLimit {
+    pub enabled: bool,
+    pub min_interval: f64,  //seconds, average interval between requests a client is allowed to keep up
+    pub burst: u32,         //requests a client can send at once after being quiet for a while
+    pub action: RateLimitAction,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_interval: 2.0,
+            burst: 8,
+            action: RateLimitAction::Kod,
+        }
+    }
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq,Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AclAction {
+    Allow,      //serve resp_strategy
+    Ignore,     //no response at all
+    Deny,       //answer with a DENY kiss-o'-death
+    Restrict,   //answer with a RSTR kiss-o'-death
+    Strategy,   //serve the strategy named in the rule
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub struct AclRule {
+    pub network: IpNet,             //cidr, 10.0.0.0/8 or fd00::/8
+    pub action: AclAction,
+    #[serde(default)]
+    pub strategy: Option<String>,   //strategy action only
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone)]
+#[serde(default)]
+pub struct Acl {
+    pub default: AclAction,     //applies to clients not matched by any rule, can't be strategy
+    pub rules: Vec<AclRule>,    //first matching rule wins
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Self {
+            default: AclAction::Allow,
+            rules: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,Default)]
+#[serde(default)]
+pub struct Auth {
+    pub keys: Option<PathBuf>,  //ntp.keys compatible key file, requests with a mac get a crypto-nak if not set
+    pub require: bool,          //drop requests without a mac
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone)]
+#[serde(default)]
+pub struct Nts {
+    pub enabled: bool,
+    pub address: SocketAddr,                //nts-ke server, ntp requests still go to the server address
+    pub certificate: Option<PathBuf>,       //pem, a self-signed certificate is generated if not set
+    pub private_key: Option<PathBuf>,       //pem
+    pub hostname: String,                   //self-signed certificate only
+    pub export_certificate: Option<PathBuf>, //self-signed certificate only, written here for clients to trust
+    pub ntp_server: Option<String>,         //sent to clients, they use the nts-ke address if not set
+    pub ntp_port: Option<u16>,              //sent to clients, they use 123 if not set
+}
+
+impl Default for Nts {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: SocketAddr::from_str("0.0.0.0:4460").unwrap(),
+            certificate: None,
+            private_key: None,
+            hostname: "localhost".to_string(),
+            export_certificate: None,
+            ntp_server: None,
+            ntp_port: None,
+        }
+    }
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,Default)]
+pub struct ServerConfig {
+    pub server: Server,
+    pub log: Log,
+    #[serde(default)]
+    pub metrics: Metrics,
+    #[serde(default)]
+    pub capture: Capture,
+    #[serde(default)]
+    pub clock: Clock,
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+    #[serde(default)]
+    pub acl: Acl,
+    #[serde(default)]
+    pub auth: Auth,
+    #[serde(default)]
+    pub nts: Nts,
+    pub resp_strategy_conf: HashMap<String, HashMap<String, Value>>,
+}
+
@@ -0,0 +1,103 @@
+use crate::ntp::types::*;
+use crate::ntp::constants::KoD;
+use crate::ntpd::ChaosServer;
+use crate::ntpd::acl::*;
+use crate::ntpd::response_strategy::StrategyContext;
+use crate::ntpd::server_config;
+use super::embedded::query;
+
+fn from_toml(config: &str) -> Acl {
+    let config: server_config::Acl = toml::from_str(config).unwrap();
+    Acl::from_config(&config, &StrategyContext::default()).unwrap()
+}
+
+#[test]
+fn first_match_wins() {
+    let mut acl = from_toml(r#"
+        default = "ignore"
+
+        [[rules]]
+        network = "10.1.0.0/16"
+        action = "restrict"
+
+        [[rules]]
+        network = "10.0.0.0/8"
+        action = "strategy"
+        strategy = "transit_timestamp"
+
+        [[rules]]
+        network = "fd00::/8"
+        action = "allow"
+    "#);
+
+    assert_eq!(acl.check("10.1.2.3".parse().unwrap()).name(), "kod");
+    assert_eq!(acl.check("10.2.3.4".parse().unwrap()).name(), "strategy");
+    assert_eq!(acl.check("::ffff:10.2.3.4".parse().unwrap()).name(), "strategy");
+    assert_eq!(acl.check("fd12::1".parse().unwrap()).name(), "allow");
+    assert_eq!(acl.check("192.168.0.1".parse().unwrap()).name(), "ignore");
+
+    assert_eq!(acl.find("10.1.2.3".parse().unwrap()), Some(0));
+    assert_eq!(acl.find("::ffff:10.2.3.4".parse().unwrap()), Some(1));
+    assert_eq!(acl.find("192.168.0.1".parse().unwrap()), None);
+}
+
+#[test]
+fn invalid_config() {
+    let ctx = StrategyContext::default();
+    let config: server_config::Acl = toml::from_str(r#"
+        [[rules]]
+        network = "10.0.0.0/8"
+        action = "strategy"
+    "#).unwrap();
+    assert!(Acl::from_config(&config, &ctx).is_err());
+
+    let config: server_config::Acl = toml::from_str(r#"
+        [[rules]]
+        network = "10.0.0.0/8"
+        action = "strategy"
+        strategy = "no_such_strategy"
+    "#).unwrap();
+    assert!(Acl::from_config(&config, &ctx).is_err());
+
+    assert!(toml::from_str::<server_config::Acl>(r#"
+        [[rules]]
+        network = "10.0.0.300/8"
+        action = "allow"
+    "#).is_err());
+}
+
+#[test]
+fn deny() {
+    let server = ChaosServer::builder()
+        .acl(from_toml(r#"
+            [[rules]]
+            network = "127.0.0.0/8"
+            action = "deny"
+        "#))
+        .spawn()
+        .unwrap();
+
+    let kod = query(server.port()).unwrap();
+    assert_eq!(kod.stratum, Stratum::Unspecified);
+    assert_eq!(kod.reference_id, KoD::DENY);
+
+    let stats = server.shutdown().unwrap();
+    assert_eq!(stats.acl_rejected, 1);
+}
+
+#[test]
+fn per_rule_strategy() {
+    let server = ChaosServer::builder()
+        .strategy_name("current_time")
+        .acl(from_toml(r#"
+            [[rules]]
+            network = "127.0.0.1/32"
+            action = "strategy"
+            strategy = "transit_timestamp"
+        "#))
+        .spawn()
+        .unwrap();
+
+    let response = query(server.port()).unwrap();
+    assert_eq!(response.transit_timestamp.get_seconds(), response.origin_timestamp.get_seconds()+1);
+}
@@ -0,0 +1,84 @@
+use crate::ntp::types::*;
+use crate::ntp::tests::client_request;
+use crate::ntp::auth::*;
+use crate::ntp::constants::KoD;
+use crate::ntpd::ChaosServer;
+use super::embedded::{query,send};
+use crate::ntpd::clock::Clock;
+
+fn keys() -> Keys {
+    Keys::parse("1 SHA1 0123456789abcdef0123456789abcdef01234567\n2 M password").unwrap()
+}
+
+#[test]
+fn signed_responses() {
+    let server = ChaosServer::builder().keys(keys()).spawn().unwrap();
+
+    for id in [1, 2] {
+        let mut signed = client_request();
+        sign(&mut signed, keys().get(id).unwrap()).unwrap();
+        let response = send(server.port(), &signed).unwrap();
+        assert_eq!(verify(&response, &keys()), Ok(id));
+    }
+
+    //unauthenticated requests get unauthenticated responses
+    assert!(query(server.port()).unwrap().auth.is_none());
+}
+
+#[test]
+fn crypto_nak() {
+    let server = ChaosServer::builder().keys(keys()).spawn().unwrap();
+
+    let mut signed = client_request();
+    sign(&mut signed, keys().get(2).unwrap()).unwrap();
+    signed.poll += 1;
+
+    let nak = send(server.port(), &signed).unwrap();
+    assert_eq!(verify(&nak, &keys()), Err(AuthError::CryptoNak));
+    assert_eq!(nak.reference_id, KoD::CRYP);
+
+    let stats = server.shutdown().unwrap();
+    assert_eq!(stats.auth_failures, 1);
+}
+
+#[test]
+fn required() {
+    let server = ChaosServer::builder().keys(keys()).require_auth(true).spawn().unwrap();
+
+    assert!(query(server.port()).is_none());
+
+    let stats = server.shutdown().unwrap();
+    assert_eq!(stats.auth_failures, 1);
+    assert_eq!(stats.dropped, 1);
+}
+
+fn signed_exchange(strategy: &str, key: u32) -> Packet {
+    let server = ChaosServer::builder().strategy_name(strategy).keys(keys()).seed(0).spawn().unwrap();
+    let mut signed = client_request();
+    sign(&mut signed, keys().get(key).unwrap()).unwrap();
+    send(server.port(), &signed).unwrap()
+}
+
+#[test]
+fn failure_strategies() {
+    assert_eq!(verify(&signed_exchange("wrong_digest", 1), &keys()), Err(AuthError::BadDigest(1)));
+    assert_eq!(verify(&signed_exchange("wrong_key_id", 1), &keys()), Err(AuthError::WrongLength(2)));
+    assert_eq!(verify(&signed_exchange("wrong_key_id", 2), &keys()), Err(AuthError::UnknownKey(3)));
+    assert_eq!(verify(&signed_exchange("crypto_nak", 1), &keys()), Err(AuthError::CryptoNak));
+
+    let kod = signed_exchange("auth_kod", 1);
+    assert_eq!((kod.stratum, kod.reference_id), (Stratum::Unspecified, KoD::AUTH));
+    let kod = signed_exchange("cryp_kod", 1);
+    assert_eq!((kod.stratum, kod.reference_id), (Stratum::Unspecified, KoD::CRYP));
+    assert_eq!(verify(&kod, &keys()), Err(AuthError::CryptoNak));
+}
+
+#[test]
+fn forged_timestamps() {
+    let response = signed_exchange("forged_timestamps", 2);
+    assert_eq!(verify(&response, &keys()), Ok(2));
+
+    let now = crate::ntpd::clock::SystemClock.now().get_seconds();
+    let offset = i64::from(response.transit_timestamp.get_seconds()) - i64::from(now);
+    assert!(offset.abs() >= 3_600);
+}
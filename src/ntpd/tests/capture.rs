@@ -0,0 +1,68 @@
+use std::time::{Duration,SystemTime};
+use crate::ntp::tests::client_request;
+use crate::ntpd::capture::{PcapWriter,PcapReader,replay};
+use crate::ntpd::response_strategy::find_strategy;
+
+fn capture(records: &[(&str, &str, &[u8])]) -> Vec<u8> {
+    let mut data = Vec::new();
+    {
+        let mut writer = PcapWriter::new(&mut data).unwrap();
+        for (n, (src, dst, payload)) in records.iter().enumerate() {
+            let time = SystemTime::UNIX_EPOCH + Duration::from_micros(1_608_634_708_840_929 + n as u64);
+            writer.write_udp(time, src.parse().unwrap(), dst.parse().unwrap(), payload).unwrap();
+        }
+    }
+    data
+}
+
+#[test]
+fn write_and_read() {
+    let request = client_request().to_bytes().unwrap();
+    let data = capture(&[
+        ("192.0.2.1:40000", "192.0.2.2:123", &request),
+        ("[2001:db8::1]:40000", "[2001:db8::2]:123", b"short"),
+        ("192.0.2.1:40000", "[2001:db8::2]:123", &[]),    //dual stack, mapped to ipv6
+    ]);
+
+    let records = PcapReader::new(&data[..]).unwrap().collect::<std::io::Result<Vec<_>>>().unwrap();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0].time, Duration::from_micros(1_608_634_708_840_929));
+    assert_eq!(records[0].src, "192.0.2.1:40000".parse().unwrap());
+    assert_eq!(records[0].dst, "192.0.2.2:123".parse().unwrap());
+    assert_eq!(records[0].payload, request);
+    assert_eq!(records[1].src, "[2001:db8::1]:40000".parse().unwrap());
+    assert_eq!(records[1].payload, b"short");
+    assert_eq!(records[2].src, "[::ffff:192.0.2.1]:40000".parse().unwrap());
+    assert!(records[2].payload.is_empty());
+}
+
+#[test]
+fn corrupt_record_length() {
+    let mut data = capture(&[("192.0.2.1:40000", "192.0.2.2:123", b"data")]);
+    //included length of the first record, right after the file header
+    data[24 + 8..24 + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+
+    let err = PcapReader::new(&data[..]).unwrap().next().unwrap().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn replay_output() {
+    let path = std::env::temp_dir().join(format!("chaos-ntp-replay-{}.pcap", std::process::id()));
+    let request = client_request().to_bytes().unwrap();
+    std::fs::write(&path, capture(&[
+        ("192.0.2.1:40000", "192.0.2.2:123", &request),
+        ("192.0.2.2:123", "192.0.2.1:40000", &request),   //not to the port, skipped
+        ("192.0.2.1:40000", "192.0.2.2:123", b"junk"),
+    ])).unwrap();
+
+    let mut out = Vec::new();
+    let result = replay(&path, 123, find_strategy("current_time").unwrap(), 42, &mut out);
+    std::fs::remove_file(&path).unwrap();
+    result.unwrap();
+
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(out.matches("request from 192.0.2.1:40000 to 192.0.2.2:123").count(), 2);
+    assert_eq!(out.matches("  response: ").count(), 2);
+    assert!(out.contains("  parse anomaly: packet is 4 bytes"));
+}
@@ -0,0 +1,70 @@
+use std::sync::Arc;
+use std::time::{Duration,SystemTime};
+use crate::ntp::types::*;
+use crate::ntp::tests::client_request;
+use crate::ntpd::clock::*;
+use crate::ntpd::response_strategy::*;
+use crate::ntpd::random::Randomness;
+
+fn response(strategy: &mut dyn ResponseStrategy) -> Packet {
+    let mut randomness = Randomness::new(0);
+    let client = "127.0.0.1:123".parse().unwrap();
+    let mut req = RequestContext { client, rng: randomness.for_client(client.ip()), nts: None };
+    let request = client_request().to_bytes().unwrap();
+    match strategy.process_packet(&PacketRef::parse(&request).unwrap(), &mut req).unwrap() {
+        Response::Packet(packet) => packet,
+        other => panic!("unexpected response {:?}", other),
+    }
+}
+
+#[test]
+fn conversions() {
+    assert_eq!(system_time_to_timestamp(SystemTime::UNIX_EPOCH).get_seconds(), 2_208_988_800);
+    assert_eq!(system_time_to_timestamp(SystemTime::UNIX_EPOCH - Duration::from_secs(1)).get_seconds(), 2_208_988_799);
+    assert_eq!(system_time_to_timestamp(SystemTime::UNIX_EPOCH + Duration::from_millis(500)).get_fraction(), 1 << 31);
+
+    let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_608_634_708);
+    assert_eq!(timestamp_to_system_time(system_time_to_timestamp(time)), time);
+    assert_eq!(system_time_to_timestamp(time).into_utc_datetime().to_rfc3339(), "2020-12-22T10:58:28+00:00");
+}
+
+#[test]
+fn virtual_clock() {
+    let clock = VirtualClock::new(Timestamp::from(0).set_seconds(100));
+    assert_eq!(clock.now(), Timestamp::from(0).set_seconds(100));
+
+    clock.advance(Duration::from_millis(1500));
+    assert_eq!(clock.now(), Timestamp::from(0).set_seconds(101).set_fraction(1 << 31));
+
+    clock.set(Timestamp::from(0).set_seconds(u32::MAX));
+    clock.advance(Duration::from_secs(2));
+    assert_eq!(clock.now().get_seconds(), 1);
+
+    //rounded, not truncated, 0.999999999 * 2^32 is 4294967291.7
+    clock.set(Timestamp(0));
+    clock.advance(Duration::from_nanos(999_999_999));
+    assert_eq!(clock.now(), Timestamp(4_294_967_292));
+}
+
+#[test]
+fn accelerated_monotonic_clock() {
+    let start = Timestamp::from(0).set_seconds(1000);
+    let clock = MonotonicClock::new(start, 1000.0);
+    std::thread::sleep(Duration::from_millis(10));
+    assert!(clock.now().get_seconds() >= 1010);
+}
+
+#[test]
+fn strategies_use_the_clock() {
+    let clock = Arc::new(VirtualClock::new(Timestamp::from(0).set_seconds(0xe38c4fd4).set_fraction(0x1234)));
+    let ctx = StrategyContext { clock: clock.clone(), ..Default::default() };
+
+    let mut current_time = find_strategy("current_time").unwrap().new_boxed(&ctx);
+    assert_eq!(response(current_time.as_mut()).transit_timestamp, clock.now());
+    clock.advance(Duration::from_secs(60));
+    assert_eq!(response(current_time.as_mut()).transit_timestamp, clock.now());
+
+    let mut single_offset = find_strategy("single_offset").unwrap().new_boxed(&ctx);
+    assert_eq!(response(single_offset.as_mut()).transit_timestamp.get_seconds(), clock.now().get_seconds()+1);
+    assert_eq!(response(single_offset.as_mut()).transit_timestamp.get_seconds(), clock.now().get_seconds()+2);
+}
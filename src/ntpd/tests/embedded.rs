@@ -0,0 +1,161 @@
+use std::net::UdpSocket;
+use std::time::Duration;
+use crate::ntp::types::*;
+use crate::ntp::tests::client_request;
+use crate::ntp::parser::*;
+use crate::ntpd::ChaosServer;
+use crate::ntpd::server_config::{self,ResponseVersion};
+use crate::ntpd::response_strategy::{ResponseStrategy,RequestContext,Response,StrategyResult};
+
+//sends a client packet to the server, returns None on timeout
+pub fn query(port: u16) -> Option<Packet> {
+    send(port, &client_request())
+}
+
+pub fn send(port: u16, request: &Packet) -> Option<Packet> {
+    exchange(port, &serialize_packet(request).unwrap())
+}
+
+pub fn exchange(port: u16, data: &[u8]) -> Option<Packet> {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    socket.send_to(data, ("127.0.0.1", port)).unwrap();
+
+    let mut buf = [0; Packet::MAX_SIZE];
+    let (size, _) = socket.recv_from(&mut buf).ok()?;
+    Some(parse_packet(&buf[..size]).unwrap().1.unwrap())
+}
+
+#[test]
+fn spawn_and_shutdown() {
+    let server = ChaosServer::builder()
+        .strategy_name("transit_timestamp")
+        .bind("127.0.0.1:0")
+        .spawn()
+        .unwrap();
+
+    assert_ne!(server.port(), 0);
+
+    let response = query(server.port()).unwrap();
+    assert_eq!(response.mode, Mode::Server);
+    assert_eq!(response.origin_timestamp, client_request().transit_timestamp);
+    assert_eq!(response.transit_timestamp.get_seconds(), client_request().transit_timestamp.get_seconds()+1);
+    assert_eq!(response.validate(), Ok(()));
+
+    let stats = server.shutdown().unwrap();
+    assert_eq!(stats.requests, 1);
+    assert_eq!(stats.responses, 1);
+}
+
+#[test]
+fn transit_timestamp_wraps() {
+    let server = ChaosServer::builder().strategy_name("transit_timestamp").spawn().unwrap();
+
+    //what a short packet turns into in lenient mode
+    let response = send(server.port(), &Packet { transit_timestamp: Timestamp(0), ..client_request() }).unwrap();
+    assert_eq!(response.reference_timestamp.get_seconds(), u32::MAX - 4);
+    assert_eq!(response.transit_timestamp.get_seconds(), 1);
+
+    let response = send(server.port(), &Packet { transit_timestamp: Timestamp(u64::MAX), ..client_request() }).unwrap();
+    assert_eq!(response.transit_timestamp.get_seconds(), 0);
+
+    let stats = server.shutdown().unwrap();
+    assert_eq!(stats.responses, 2);
+}
+
+struct Silent;
+impl ResponseStrategy for Silent {
+    fn process_packet(&mut self, _packet: &PacketRef, _req: &mut RequestContext) -> StrategyResult {
+        Ok(Response::Nothing)
+    }
+}
+
+#[test]
+fn custom_strategy() {
+    let server = ChaosServer::builder()
+        .strategy(Silent)
+        .spawn()
+        .unwrap();
+
+    assert!(query(server.port()).is_none());
+
+    let stats = server.shutdown().unwrap();
+    assert_eq!(stats.requests, 1);
+    assert_eq!(stats.dropped, 1);
+    assert_eq!(stats.responses, 0);
+}
+
+#[test]
+fn unknown_strategy() {
+    let err = ChaosServer::builder()
+        .strategy_name("no_such_strategy")
+        .spawn()
+        .err()
+        .unwrap();
+
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+}
+
+//a request cut short after the transit timestamp's seconds
+fn short_query(port: u16) -> Option<Packet> {
+    exchange(port, &serialize_packet(&client_request()).unwrap()[..44])
+}
+
+#[test]
+fn parse_modes() {
+    let server = ChaosServer::builder().strategy_name("transit_timestamp").spawn().unwrap();
+    let response = short_query(server.port()).unwrap();
+    assert_eq!(response.origin_timestamp.get_seconds(), client_request().transit_timestamp.get_seconds());
+    assert_eq!(response.origin_timestamp.get_fraction(), 0);
+    let stats = server.shutdown().unwrap();
+    assert_eq!((stats.parse_anomalies, stats.parse_errors), (1, 0));
+
+    let server = ChaosServer::builder().parse_mode(ParseMode::Strict).spawn().unwrap();
+    assert!(short_query(server.port()).is_none());
+    assert!(query(server.port()).is_some());
+    let stats = server.shutdown().unwrap();
+    assert_eq!((stats.parse_anomalies, stats.parse_errors), (0, 1));
+}
+
+fn versioned_query(port: u16, version: u8, mode: Mode) -> Option<Packet> {
+    send(port, &Packet { version, mode, ..client_request() })
+}
+
+#[test]
+fn response_versions() {
+    let server = ChaosServer::builder().strategy_name("transit_timestamp").spawn().unwrap();
+    for version in 2..=4 {
+        assert_eq!(versioned_query(server.port(), version, Mode::Client).unwrap().version, version);
+    }
+    //ntpv1 clients send mode 0
+    let response = versioned_query(server.port(), 1, Mode::Reserved).unwrap();
+    assert_eq!((response.version, response.mode), (1, Mode::Server));
+
+    let server = ChaosServer::builder().response_version(ResponseVersion::Fixed(3)).spawn().unwrap();
+    assert_eq!(query(server.port()).unwrap().version, 3);
+
+    //anything past 7 would overwrite the leap indicator
+    let err = ChaosServer::builder().response_version(ResponseVersion::Fixed(8)).spawn().err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    let config = |version| toml::from_str::<server_config::Server>(&format!(
+        "address = \"127.0.0.1\"\nport = 123\nresp_strategy = \"current_time\"\nresponse_version = {{ fixed = {} }}", version));
+    assert_eq!(config(7).unwrap().response_version, ResponseVersion::Fixed(7));
+    assert!(config(8).is_err());
+
+    let server = ChaosServer::builder().response_version(ResponseVersion::Mismatch).seed(0).spawn().unwrap();
+    for version in 1..=4 {
+        for _ in 0..10 {
+            let response = versioned_query(server.port(), version, Mode::Client).unwrap().version;
+            assert!(response != version && (1..=4).contains(&response));
+        }
+    }
+    //unknown versions get any of the known ones
+    for version in [0, 5] {
+        let mut seen = [false; 4];
+        for _ in 0..40 {
+            let response = versioned_query(server.port(), version, Mode::Client).unwrap().version;
+            seen[usize::from(response) - 1] = true;
+        }
+        assert_eq!(seen, [true; 4]);
+    }
+}
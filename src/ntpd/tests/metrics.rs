@@ -0,0 +1,25 @@
+use std::net::{IpAddr,Ipv4Addr};
+use crate::ntpd::metrics::Metrics;
+
+#[test]
+fn client_prefixes() {
+    let mut metrics = Metrics::new().unwrap();
+    metrics.count_client("192.0.2.1".parse().unwrap());
+    metrics.count_client("192.0.2.200".parse().unwrap());
+    metrics.count_client("2001:db8::1".parse().unwrap());
+    metrics.count_client("2001:db8::ffff".parse().unwrap());
+
+    assert_eq!(metrics.requests_per_client.with_label_values(&["192.0.2.0/24"]).get(), 2);
+    assert_eq!(metrics.requests_per_client.with_label_values(&["2001:db8::/64"]).get(), 2);
+
+    //a flood of spoofed addresses ends up in a fixed number of series
+    for n in 0..u16::MAX {
+        metrics.count_client(IpAddr::V4(Ipv4Addr::new(10, (n >> 8) as u8, n as u8, 1)));
+    }
+    let series = metrics.registry.gather().iter()
+        .find(|family| family.get_name() == "chaos_ntpd_requests_per_client_total")
+        .map(|family| family.get_metric().len())
+        .unwrap();
+    assert_eq!(series, 1025);
+    assert_eq!(metrics.requests_per_client.with_label_values(&["other"]).get(), u64::from(u16::MAX) + 2 - 1024);
+}
@@ -0,0 +1,26 @@
+#[cfg(test)]
+pub mod embedded;
+
+#[cfg(test)]
+pub mod clock;
+
+#[cfg(test)]
+pub mod random;
+
+#[cfg(test)]
+pub mod rate_limit;
+
+#[cfg(test)]
+pub mod acl;
+
+#[cfg(test)]
+pub mod auth;
+
+#[cfg(test)]
+pub mod nts;
+
+#[cfg(test)]
+pub mod metrics;
+
+#[cfg(test)]
+pub mod capture;
@@ -0,0 +1,185 @@
+use std::io::{Read,Write};
+use std::net::TcpStream;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Duration;
+use rustls::pki_types::{CertificateDer,ServerName};
+use crate::ntp::types::*;
+use crate::ntp::tests::client_request;
+use crate::ntp::constants::{ExtensionFieldType,KoD};
+use crate::ntp::nts::*;
+use crate::ntpd::ChaosServer;
+use crate::ntpd::embedded::ServerHandle;
+use crate::ntpd::nts::NtsKe;
+use super::embedded::send;
+
+//nts-ke as a client does it, returns the session keys and the records the server sent
+fn key_exchange(server: &ServerHandle, request: &[Record]) -> (SessionKeys, Vec<Record>) {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(CertificateDer::from(server.nts_certificate().unwrap().to_vec())).unwrap();
+    let mut config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13]).unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![ALPN.to_vec()];
+
+    let connection = rustls::ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap();
+    let stream = TcpStream::connect(server.nts_ke_addr().unwrap()).unwrap();
+    let mut tls = rustls::StreamOwned::new(connection, stream);
+    tls.write_all(&serialize_message(request).unwrap()).unwrap();
+
+    let mut data = Vec::new();
+    tls.read_to_end(&mut data).unwrap();
+    let export = |s2c| tls.conn.export_keying_material([0; 32], EXPORTER_LABEL,
+        Some(&exporter_context(AEAD_AES_SIV_CMAC_256, s2c))).unwrap();
+    (SessionKeys { c2s: export(false), s2c: export(true) }, parse_message(&data).unwrap())
+}
+
+fn ke_request() -> Vec<Record> {
+    vec![
+        Record::from_u16s(true, RecordType::NEXT_PROTOCOL, &[PROTOCOL_NTPV4]),
+        Record::from_u16s(false, RecordType::AEAD_ALGORITHM, &[AEAD_AES_SIV_CMAC_256]),
+        Record::end_of_message(),
+    ]
+}
+
+fn cookies(records: &[Record]) -> Vec<Vec<u8>> {
+    records.iter().filter(|r| r.record_type == RecordType::NEW_COOKIE).map(|r| r.body.clone()).collect()
+}
+
+fn request(keys: &SessionKeys, cookie_value: Vec<u8>) -> Packet {
+    let mut packet = Packet {
+        extensions: Some(vec![unique_id(vec![7; 32]), cookie(cookie_value)]),
+        ..client_request()
+    };
+    let ad = associated_data(&packet, usize::MAX).unwrap();
+    let authenticator = authenticator(&keys.c2s, &ad, &[9; NONCE_SIZE], &[]).unwrap();
+    packet.extensions.as_mut().unwrap().push(authenticator);
+    packet
+}
+
+#[test]
+fn key_exchange_and_time() {
+    let server = ChaosServer::builder().nts(true).spawn().unwrap();
+    let (keys, records) = key_exchange(&server, &ke_request());
+    assert_eq!(records[0].u16s(), vec![PROTOCOL_NTPV4]);
+    assert_eq!(records[1].u16s(), vec![AEAD_AES_SIV_CMAC_256]);
+    let cookies = cookies(&records);
+    assert_eq!(cookies.len(), COOKIES_PER_KE);
+
+    let response = send(server.port(), &request(&keys, cookies[0].clone())).unwrap();
+    let extensions = response.extensions.clone().unwrap();
+    assert_eq!(extensions[0], unique_id(vec![7; 32]));
+    assert_eq!(extensions[1].field_type, ExtensionFieldType::NTP_AUTHENTICATOR);
+
+    let ad = associated_data(&response, 1).unwrap();
+    let encrypted = open_authenticator(&keys.s2c, &ad, &extensions[1]).unwrap();
+    assert_eq!(encrypted.len(), 1);
+    assert_eq!(encrypted[0].field_type, ExtensionFieldType::NTS_COOKIE);
+    assert!(cookies.iter().all(|c| *c != encrypted[0].value));
+
+    //the fresh cookie works as well
+    assert!(send(server.port(), &request(&keys, encrypted[0].value.clone())).unwrap().extensions.is_some());
+}
+
+#[test]
+fn nts_nak() {
+    let server = ChaosServer::builder().nts(true).spawn().unwrap();
+    let (keys, records) = key_exchange(&server, &ke_request());
+
+    let mut tampered = request(&keys, cookies(&records)[0].clone());
+    tampered.poll += 1;
+    let nak = send(server.port(), &tampered).unwrap();
+    assert_eq!((nak.stratum, nak.reference_id), (Stratum::Unspecified, KoD::NTSN));
+    assert_eq!(nak.extensions, Some(vec![unique_id(vec![7; 32])]));
+
+    let stats = server.shutdown().unwrap();
+    assert_eq!(stats.auth_failures, 1);
+}
+
+#[test]
+fn key_exchange_error() {
+    let server = ChaosServer::builder().nts(true).spawn().unwrap();
+    let request = vec![
+        Record::from_u16s(true, RecordType::NEXT_PROTOCOL, &[PROTOCOL_NTPV4]),
+        Record::new(true, 0x4242, vec![]),
+        Record::end_of_message(),
+    ];
+    let (_, records) = key_exchange(&server, &request);
+    assert_eq!(records[0].record_type, RecordType::ERROR);
+    assert_eq!(records[0].u16s(), vec![KeError::UNRECOGNIZED_CRITICAL_RECORD]);
+}
+
+#[test]
+fn key_exchange_bad_request() {
+    let server = ChaosServer::builder().nts(true).spawn().unwrap();
+    //next protocol and aead algorithm both missing once and repeated once
+    for (index, repeat) in [(0, false), (1, false), (0, true), (1, true)] {
+        let mut request = ke_request();
+        if repeat {
+            request.insert(index, request[index].clone());
+        } else {
+            request.remove(index);
+        }
+        let (_, records) = key_exchange(&server, &request);
+        assert_eq!(records[0].record_type, RecordType::ERROR);
+        assert_eq!(records[0].u16s(), vec![KeError::BAD_REQUEST]);
+    }
+}
+
+#[test]
+fn session_limit() {
+    let server = ChaosServer::builder().nts(true).spawn().unwrap();
+    let idle = (0..NtsKe::MAX_SESSIONS).map(|_| TcpStream::connect(server.nts_ke_addr().unwrap()).unwrap()).collect::<Vec<_>>();
+
+    //one more is closed without a thread to handle it
+    let mut extra = TcpStream::connect(server.nts_ke_addr().unwrap()).unwrap();
+    extra.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    match extra.read(&mut [0; 16]) {
+        Ok(n) => assert_eq!(n, 0),
+        Err(err) => assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset),
+    }
+
+    //and the slots come back once the idle clients are gone
+    drop(idle);
+    std::thread::sleep(Duration::from_millis(500));
+    let (_, records) = key_exchange(&server, &ke_request());
+    assert_eq!(cookies(&records).len(), COOKIES_PER_KE);
+}
+
+//key exchange and one nts request against a server running strategy
+fn strategy_exchange(strategy: &str) -> (SessionKeys, Packet, ServerHandle) {
+    let server = ChaosServer::builder().strategy_name(strategy).nts(true).seed(0).spawn().unwrap();
+    let (keys, records) = key_exchange(&server, &ke_request());
+    let response = send(server.port(), &request(&keys, cookies(&records)[0].clone())).unwrap();
+    (keys, response, server)
+}
+
+fn open_response(keys: &SessionKeys, response: &Packet) -> Option<Vec<ExtensionField>> {
+    let extensions = response.extensions.as_ref()?;
+    let position = extensions.iter().position(|e| e.field_type == ExtensionFieldType::NTP_AUTHENTICATOR)?;
+    open_authenticator(&keys.s2c, &associated_data(response, position).unwrap(), &extensions[position])
+}
+
+#[test]
+fn failure_strategies() {
+    let (keys, response, server) = strategy_exchange("nts_corrupt_cookie");
+    let cookie = open_response(&keys, &response).unwrap().remove(0).value;
+    let nak = send(server.port(), &request(&keys, cookie)).unwrap();
+    assert_eq!(nak.reference_id, KoD::NTSN);
+
+    let (keys, response, _) = strategy_exchange("nts_no_unique_id");
+    assert_eq!(open_response(&keys, &response).unwrap().len(), 1);
+    assert!(response.extensions.unwrap().iter().all(|e| e.field_type != ExtensionFieldType::UNIQUE));
+
+    let (keys, response, _) = strategy_exchange("nts_bad_authenticator");
+    assert_eq!(response.extensions.as_ref().unwrap()[0], unique_id(vec![7; 32]));
+    assert!(open_response(&keys, &response).is_none());
+
+    let (keys, response, _) = strategy_exchange("nts_no_cookies");
+    assert_eq!(open_response(&keys, &response), Some(vec![]));
+
+    let (_, nak, _) = strategy_exchange("nts_nak");
+    assert_eq!((nak.stratum, nak.reference_id), (Stratum::Unspecified, KoD::NTSN));
+    assert_eq!(nak.extensions, Some(vec![unique_id(vec![7; 32])]));
+}
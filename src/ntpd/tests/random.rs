@@ -0,0 +1,67 @@
+use rand::RngCore;
+use crate::ntp::types::*;
+use crate::ntpd::ChaosServer;
+use crate::ntpd::random::Randomness;
+use crate::ntpd::response_strategy::{ResponseStrategy,RequestContext,StrategyResult};
+use super::embedded::query;
+
+#[test]
+fn client_streams() {
+    let a = "192.0.2.1".parse().unwrap();
+    let b = "2001:db8::1".parse().unwrap();
+
+    let mut first = Randomness::new(42);
+    let mut second = Randomness::new(42);
+
+    //interleaving clients does not change what each of them gets
+    let a1 = first.for_client(a).next_u64();
+    let b1 = first.for_client(b).next_u64();
+    let a2 = first.for_client(a).next_u64();
+
+    assert_eq!(second.for_client(a).next_u64(), a1);
+    assert_eq!(second.for_client(a).next_u64(), a2);
+    assert_eq!(second.for_client(b).next_u64(), b1);
+    assert_ne!(a1, b1);
+
+    assert_ne!(Randomness::new(43).for_client(a).next_u64(), a1);
+    assert!(Randomness::from_entropy().seed() < 1 << 63);
+}
+
+#[test]
+fn client_eviction() {
+    let a: std::net::IpAddr = "192.0.2.1".parse().unwrap();
+    let mut randomness = Randomness::new(42);
+    let a1 = randomness.for_client(a).next_u64();
+
+    //a client that is still there keeps its stream, the oldest one starts over
+    for n in 1..Randomness::MAX_CLIENTS as u32 {
+        randomness.for_client(std::net::Ipv4Addr::from(0x0a00_0000 + n).into());
+    }
+    assert_ne!(randomness.for_client(a).next_u64(), a1);
+    randomness.for_client("10.255.255.255".parse().unwrap());
+    assert_eq!(randomness.for_client(a).next_u64(), a1);
+}
+
+//answers with random transmit timestamps
+struct Noise;
+impl ResponseStrategy for Noise {
+    fn process_packet(&mut self, packet: &PacketRef, req: &mut RequestContext) -> StrategyResult {
+        Ok(Packet {
+            mode: Mode::Server,
+            origin_timestamp: packet.transit_timestamp,
+            transit_timestamp: Timestamp(req.rng.next_u64()),
+            ..packet.header()
+        }.into())
+    }
+}
+
+#[test]
+fn same_seed_same_responses() {
+    let run = |seed| {
+        let server = ChaosServer::builder().strategy(Noise).seed(seed).spawn().unwrap();
+        (0..3).map(|_| query(server.port()).unwrap().transit_timestamp).collect::<Vec<_>>()
+    };
+
+    assert_eq!(run(7), run(7));
+    assert_ne!(run(7), run(8));
+}
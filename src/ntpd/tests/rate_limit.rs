@@ -0,0 +1,89 @@
+use std::sync::Arc;
+use std::time::{Duration,Instant};
+use crate::ntp::types::*;
+use crate::ntp::constants::KoD;
+use crate::ntpd::ChaosServer;
+use crate::ntpd::clock::*;
+use crate::ntpd::rate_limit::RateLimiter;
+use crate::ntpd::server_config::RateLimitAction;
+use super::embedded::query;
+
+#[test]
+fn token_bucket() {
+    let mut now = Instant::now();
+    let mut limiter = RateLimiter::new(2.0, 3, RateLimitAction::Drop);
+    let client = "10.0.0.1".parse().unwrap();
+    let other = "10.0.0.2".parse().unwrap();
+
+    assert!((0..3).all(|_| limiter.check(client, now)));
+    assert!(!limiter.check(client, now));
+    assert!(limiter.check(other, now));
+
+    now += Duration::from_secs(1);
+    assert!(!limiter.check(client, now));
+    now += Duration::from_secs(1);
+    assert!(limiter.check(client, now));
+    assert!(!limiter.check(client, now));
+
+    //the bucket never holds more than burst tokens
+    now += Duration::from_secs(3600);
+    assert!((0..3).all(|_| limiter.check(client, now)));
+    assert!(!limiter.check(client, now));
+}
+
+#[test]
+fn max_clients() {
+    let now = Instant::now();
+    let mut limiter = RateLimiter::new(60.0, 1, RateLimitAction::Drop);
+    let client = "10.0.0.1".parse().unwrap();
+
+    assert!(limiter.check(client, now));
+    assert!(!limiter.check(client, now));
+
+    //spoofed addresses push the oldest bucket out instead of growing the map
+    for n in 1..RateLimiter::MAX_CLIENTS as u32 {
+        assert!(limiter.check(std::net::Ipv4Addr::from(0x0b00_0000 + n).into(), now));
+    }
+    assert!(!limiter.check(client, now));
+    assert!(limiter.check("12.0.0.1".parse().unwrap(), now));
+    assert!(limiter.check(client, now));
+}
+
+#[test]
+fn kiss_of_death() {
+    let clock = Arc::new(VirtualClock::new(Timestamp::from(0).set_seconds(0xe38c4fd4)));
+    let server = ChaosServer::builder()
+        .strategy_name("current_time")
+        .clock(clock)
+        .rate_limit(RateLimiter::new(60.0, 1, RateLimitAction::Kod))
+        .spawn()
+        .unwrap();
+
+    let response = query(server.port()).unwrap();
+    assert_eq!(response.stratum, Stratum::SecondaryServer(4));
+
+    let kod = query(server.port()).unwrap();
+    assert_eq!(kod.stratum, Stratum::Unspecified);
+    assert_eq!(kod.reference_id, KoD::RATE);
+    assert_eq!(kod.origin_timestamp, kod.transit_timestamp);
+
+    let stats = server.shutdown().unwrap();
+    assert_eq!(stats.requests, 2);
+    assert_eq!(stats.responses, 2);
+    assert_eq!(stats.rate_limited, 1);
+}
+
+#[test]
+fn drop() {
+    let server = ChaosServer::builder()
+        .rate_limit(RateLimiter::new(60.0, 1, RateLimitAction::Drop))
+        .spawn()
+        .unwrap();
+
+    assert!(query(server.port()).is_some());
+    assert!(query(server.port()).is_none());
+
+    let stats = server.shutdown().unwrap();
+    assert_eq!(stats.rate_limited, 1);
+    assert_eq!(stats.dropped, 1);
+}